@@ -0,0 +1,123 @@
+use crate::timezone::DisplayZone;
+use chrono::{DateTime, NaiveTime, Utc};
+use log::info;
+
+/// Daily window in which URLs may be opened, such as `09:00-18:00`, in the
+/// wall-clock time of ACTIVE_TIMEZONE. A window whose end is before its start
+/// runs past midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    zone: DisplayZone,
+}
+
+impl ActiveHours {
+    pub fn parse(spec: &str, zone: DisplayZone) -> Result<Self, String> {
+        let invalid = || format!("Invalid ACTIVE_HOURS value: {}", spec);
+        let (start, end) = spec.split_once('-').ok_or_else(invalid)?;
+        let parse_time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(invalid());
+        }
+        Ok(ActiveHours { start, end, zone })
+    }
+
+    pub fn contains(&self, at: DateTime<Utc>) -> bool {
+        let time = self.zone.wall_clock(at).time();
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Time from `at` until the window next opens; zero inside the window.
+    pub fn until_open(&self, at: DateTime<Utc>) -> std::time::Duration {
+        if self.contains(at) {
+            return std::time::Duration::ZERO;
+        }
+        let now = self.zone.wall_clock(at);
+        let mut opens = now.date().and_time(self.start);
+        if opens <= now {
+            opens += chrono::Duration::days(1);
+        }
+        // Through UTC, so a daylight saving change before the window opens
+        // is accounted for
+        (self.zone.to_utc(opens) - at).to_std().unwrap_or_default()
+    }
+
+    /// Waits until the window is open.
+    pub async fn wait(&self) {
+        loop {
+            let wait = self.until_open(Utc::now());
+            if wait.is_zero() {
+                return;
+            }
+            info!("Outside ACTIVE_HOURS, holding URLs for {:?}", wait);
+            // Re-checked after waking, in case a daylight saving change moved
+            // the opening time
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl std::fmt::Display for ActiveHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{} {}", self.start.format("%H:%M"), self.end.format("%H:%M"), self.zone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&format!("2024-03-01T{}Z", time))
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn checks_window_in_its_time_zone() {
+        let zone = DisplayZone::parse("ACTIVE_TIMEZONE", "+02:00").unwrap();
+        let hours = ActiveHours::parse("09:00-18:00", zone).unwrap();
+        assert!(!hours.contains(utc("06:59:00")));
+        assert!(hours.contains(utc("07:00:00")));
+        assert!(hours.contains(utc("15:59:00")));
+        assert!(!hours.contains(utc("16:00:00")));
+        assert_eq!(hours.until_open(utc("06:30:00")), std::time::Duration::from_secs(30 * 60));
+        assert_eq!(hours.until_open(utc("16:00:00")), std::time::Duration::from_secs(15 * 3600));
+        assert_eq!(hours.until_open(utc("12:00:00")), std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn windows_may_run_past_midnight() {
+        let hours = ActiveHours::parse("22:00-02:00", DisplayZone::parse("ACTIVE_TIMEZONE", "utc").unwrap()).unwrap();
+        assert!(hours.contains(utc("23:00:00")));
+        assert!(hours.contains(utc("01:00:00")));
+        assert!(!hours.contains(utc("12:00:00")));
+    }
+
+    #[test]
+    fn rejects_malformed_specs() {
+        assert!(ActiveHours::parse("9-18", DisplayZone::Local).is_err());
+        assert!(ActiveHours::parse("09:00", DisplayZone::Local).is_err());
+        assert!(ActiveHours::parse("09:00-09:00", DisplayZone::Local).is_err());
+        assert!(DisplayZone::parse("ACTIVE_TIMEZONE", "Not/A_Zone").is_err());
+    }
+
+    #[test]
+    fn named_zones_follow_daylight_saving() {
+        let hours = ActiveHours::parse("09:00-18:00", crate::timezone::tests::berlin()).unwrap();
+        let at = |time: &str| DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc);
+        assert!(hours.contains(at("2031-01-15T08:00:00Z")));
+        assert!(!hours.contains(at("2031-07-15T16:00:00Z")));
+        assert!(hours.contains(at("2031-07-15T07:00:00Z")));
+        // The clocks go forward overnight, so the window opens 11 hours later
+        // rather than 12
+        assert_eq!(hours.until_open(at("2031-03-29T20:00:00Z")), std::time::Duration::from_secs(11 * 3600));
+        assert_eq!(hours.to_string(), "09:00-18:00 Europe/Berlin");
+    }
+}
@@ -0,0 +1,35 @@
+use chrono::{DateTime, Utc};
+use firestore::FirestoreDb;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+/// Record of one opened URL, written to `AUDIT_COLLECTION`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub doc_id: Option<String>,
+    pub collection: String,
+    pub url_host: String,
+    /// The stored or fetched title, or the host when there is neither.
+    pub title: String,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub opened_at: DateTime<Utc>,
+    pub instance_id: String,
+}
+
+/// Writes `entry` to the audit collection. A failed write is logged and
+/// otherwise ignored, so auditing never holds up opening URLs.
+pub async fn record(db: &FirestoreDb, audit_collection: &str, entry: &AuditEntry) {
+    let doc_id = entry.doc_id.as_deref().unwrap_or_default();
+    let result = db
+        .fluent()
+        .insert()
+        .into(audit_collection)
+        .generate_document_id()
+        .object(entry)
+        .execute::<AuditEntry>()
+        .await;
+    match result {
+        Ok(_) => debug!(doc_id = doc_id; "Recorded open in audit collection {}", audit_collection),
+        Err(e) => warn!(doc_id = doc_id; "Failed to record open in audit collection {}: {}", audit_collection, e),
+    }
+}
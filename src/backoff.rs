@@ -0,0 +1,39 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Capped exponential backoff with jitter. Each delay is picked at random
+/// between half and all of the current step, which doubles up to `max`, so
+/// instances that lost Firestore together don't all reconnect in lockstep.
+#[derive(Debug)]
+pub struct Backoff {
+    step: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff { step: base.min(max), max }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self.step;
+        self.step = (self.step * 2).min(self.max);
+        let jitter: f64 = rand::thread_rng().gen_range(0.0..=0.5);
+        step.mul_f64(1.0 - jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_with_jitter_up_to_the_cap() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(500));
+        for step in [100, 200, 400, 500, 500] {
+            let delay = backoff.next_delay();
+            let step = Duration::from_millis(step);
+            assert!(delay >= step / 2 && delay <= step, "{:?} outside {:?}", delay, step);
+        }
+    }
+}
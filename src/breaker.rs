@@ -0,0 +1,197 @@
+use crate::health::Health;
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Where the breaker is in its cycle, as reported on `/healthz`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    /// Opens go ahead.
+    Closed,
+    /// Too many opens failed in a row; opens wait out the cool-down.
+    Open,
+    /// The cool-down is over and a single probe open decides what's next.
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Stops launching the browser after BREAKER_THRESHOLD opens in a row have
+/// failed, so a broken browser doesn't burn every URL's retries. Opens wait
+/// for BREAKER_COOLDOWN_SECS, then one probe is let through: if it works,
+/// opens resume, otherwise the cool-down starts over.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+    changed: Notify,
+    health: Arc<Health>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    consecutive_failures: u32,
+    state: BreakerState,
+    /// When the cool-down of an open breaker ends.
+    retry_at: Instant,
+    /// Whether a half-open breaker's probe is under way.
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    /// A threshold of zero disables the breaker.
+    pub fn new(threshold: u32, cooldown: Duration, health: Arc<Health>) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            inner: Mutex::new(Inner {
+                consecutive_failures: 0,
+                state: BreakerState::Closed,
+                retry_at: Instant::now(),
+                probing: false,
+            }),
+            changed: Notify::new(),
+            health,
+        }
+    }
+
+    /// Waits until an open may be attempted: right away while closed, after
+    /// the cool-down while open, and one caller at a time while half open.
+    pub async fn ready(&self) {
+        loop {
+            let changed = self.changed.notified();
+            let wait = {
+                let mut inner = self.inner.lock().unwrap();
+                match inner.state {
+                    BreakerState::Closed => return,
+                    BreakerState::Open if Instant::now() >= inner.retry_at => {
+                        info!("Circuit breaker cool-down over, probing with a single open");
+                        inner.state = BreakerState::HalfOpen;
+                        inner.probing = true;
+                        self.health.set_breaker_state(BreakerState::HalfOpen);
+                        return;
+                    }
+                    BreakerState::HalfOpen if !inner.probing => {
+                        inner.probing = true;
+                        return;
+                    }
+                    BreakerState::Open => Some(inner.retry_at),
+                    BreakerState::HalfOpen => None,
+                }
+            };
+            match wait {
+                Some(retry_at) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(retry_at) => {}
+                        _ = changed => {}
+                    }
+                }
+                None => changed.await,
+            }
+        }
+    }
+
+    /// Records how an attempt let through by `ready` went.
+    pub fn record(&self, succeeded: bool) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.probing = false;
+        if succeeded {
+            inner.consecutive_failures = 0;
+            if inner.state != BreakerState::Closed {
+                info!("Circuit breaker closed, the probe open worked; resuming opens");
+                self.set_state(&mut inner, BreakerState::Closed);
+            }
+            return;
+        }
+        inner.consecutive_failures += 1;
+        match inner.state {
+            BreakerState::HalfOpen => {
+                warn!("Circuit breaker probe failed, pausing opens for another {:?}", self.cooldown);
+                self.trip(&mut inner);
+            }
+            BreakerState::Closed if inner.consecutive_failures >= self.threshold => {
+                warn!(
+                    "Circuit breaker open after {} failed opens in a row, pausing opens for {:?}",
+                    inner.consecutive_failures, self.cooldown
+                );
+                self.trip(&mut inner);
+            }
+            _ => {}
+        }
+    }
+
+    fn trip(&self, inner: &mut Inner) {
+        inner.retry_at = Instant::now() + self.cooldown;
+        self.set_state(inner, BreakerState::Open);
+    }
+
+    fn set_state(&self, inner: &mut Inner, state: BreakerState) {
+        inner.state = state;
+        self.health.set_breaker_state(state);
+        self.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn trips_after_consecutive_failures_and_probes_after_the_cooldown() {
+        let health = Arc::new(Health::default());
+        let breaker = Arc::new(CircuitBreaker::new(2, Duration::from_millis(50), health.clone()));
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(false);
+        assert_eq!(breaker.inner.lock().unwrap().state, BreakerState::Closed);
+        breaker.record(false);
+        assert_eq!(breaker.inner.lock().unwrap().state, BreakerState::Open);
+        assert_eq!(health.breaker_state(), BreakerState::Open);
+
+        let start = Instant::now();
+        breaker.ready().await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(breaker.inner.lock().unwrap().state, BreakerState::HalfOpen);
+
+        // Only the probe goes ahead while half open
+        let waiting = tokio::spawn({
+            let breaker = breaker.clone();
+            async move { breaker.ready().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        // A failed probe starts the cool-down over
+        breaker.record(false);
+        assert_eq!(breaker.inner.lock().unwrap().state, BreakerState::Open);
+        waiting.await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        breaker.record(true);
+        assert_eq!(breaker.inner.lock().unwrap().state, BreakerState::Closed);
+        assert_eq!(health.breaker_state(), BreakerState::Closed);
+    }
+
+    #[tokio::test]
+    async fn zero_threshold_never_trips() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(30), Arc::new(Health::default()));
+        for _ in 0..10 {
+            breaker.record(false);
+        }
+        assert_eq!(breaker.inner.lock().unwrap().state, BreakerState::Closed);
+        breaker.ready().await;
+    }
+}
@@ -0,0 +1,65 @@
+use chrono::prelude::*;
+use firestore::errors::FirestoreError;
+use firestore::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Claim {
+    opened_by: String,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    claimed_at: DateTime<Utc>,
+    #[serde(default)]
+    open_count: i64,
+}
+
+/// Atomically marks the document as opened by `instance_id`, starting its
+/// `open_count` at 0 if it has none.
+///
+/// The write is conditional on the document's `update_time`, so when several
+/// instances race for the same document only the first write succeeds.
+/// Returns `Ok(false)` when the document was already claimed or another
+/// instance won the race.
+pub async fn claim_document(
+    db: &FirestoreDb,
+    collection_name: &str,
+    doc_id: &str,
+    doc: &FirestoreDocument,
+    instance_id: &str,
+) -> FirestoreResult<bool> {
+    if doc.fields.contains_key("opened_by") {
+        return Ok(false);
+    }
+    let Some(update_time) = doc.update_time else {
+        return Ok(false);
+    };
+
+    let claim = Claim {
+        opened_by: instance_id.to_string(),
+        claimed_at: Utc::now(),
+        open_count: 0,
+    };
+    let mut fields = vec!["opened_by", "claimed_at"];
+    if !doc.fields.contains_key("open_count") {
+        fields.push("open_count");
+    }
+    let result = db
+        .fluent()
+        .update()
+        .fields(fields)
+        .in_col(collection_name)
+        .precondition(FirestoreWritePrecondition::UpdateTime(
+            firestore::timestamp_utils::from_timestamp(update_time)?,
+        ))
+        .document_id(doc_id)
+        .object(&claim)
+        .execute::<Claim>()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(FirestoreError::DatabaseError(ref e)) if e.public.code == "FailedPrecondition" => {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
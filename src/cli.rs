@@ -0,0 +1,212 @@
+use crate::config::{CollectionConfig, Config};
+use chrono::Duration;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: firestore_url_opener [OPTIONS] [COMMAND]
+
+Listens to a Firestore collection and opens shared URLs in the browser.
+
+Commands:
+  share <URL>                   Add a URL to the collection and exit
+  once                          Open the pending URLs, then exit instead of
+                                listening; exits nonzero if any open failed
+  purge                         Delete documents whose expired_at has passed;
+                                with --dry-run, only list them
+  replay <FILE>                 Feed events recorded with RECORD_EVENTS_PATH
+                                through the handlers, printing URLs instead
+                                of opening them
+
+Options:
+      --config <PATH>           JSON config file (env: CONFIG_PATH)
+      --env-file <PATH>         File of environment variables to load instead
+                                of ./.env (env: DOTENV_PATH)
+      --project-id <ID>         Firebase project id (env: PROJECT_ID)
+      --collection <NAME>       Collection to listen on (env: COLLECTION_NAME, COLLECTIONS)
+      --dry-run                 Log URLs instead of opening them (env: DRY_RUN)
+      --expiration-days <DAYS>  Days until opened URLs expire (env: EXPIRATION_HOURS)
+      --log-level <LEVEL>       error, warn, info, debug or trace (env: RUST_LOG, LOG_LEVEL)
+  -h, --help                    Print this help
+
+Command-line options take precedence over environment variables, which take
+precedence over the config file.";
+
+/// What to do instead of listening.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Add a URL to the collection.
+    Share(String),
+    /// Open the backlog and exit.
+    Once,
+    /// Delete expired documents.
+    Purge,
+    /// Replay recorded listener events.
+    Replay(PathBuf),
+}
+
+/// Command-line options. Anything left unset defers to the environment and
+/// config file.
+#[derive(Debug, Default, PartialEq)]
+pub struct Cli {
+    pub command: Option<Command>,
+    pub help: bool,
+    pub config: Option<PathBuf>,
+    pub env_file: Option<PathBuf>,
+    pub project_id: Option<String>,
+    pub collection: Option<String>,
+    pub dry_run: bool,
+    pub expiration_days: Option<i64>,
+    pub log_level: Option<String>,
+}
+
+impl Cli {
+    pub fn parse<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut cli = Cli::default();
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("Missing value for {}", flag))
+            };
+            match flag.as_str() {
+                "-h" | "--help" => cli.help = true,
+                "--config" => cli.config = Some(PathBuf::from(value()?)),
+                "--env-file" => cli.env_file = Some(PathBuf::from(value()?)),
+                "--project-id" => cli.project_id = Some(value()?),
+                "--collection" => cli.collection = Some(value()?),
+                "--dry-run" => cli.dry_run = true,
+                "--expiration-days" => {
+                    let days = value()?;
+                    cli.expiration_days = Some(
+                        days.parse()
+                            .ok()
+                            .filter(|days| *days > 0)
+                            .ok_or_else(|| format!("Invalid --expiration-days value: {}", days))?,
+                    );
+                }
+                "--log-level" => cli.log_level = Some(value()?),
+                _ if !arg.starts_with('-') => positional.push(arg),
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+        cli.command = parse_command(positional)?;
+        Ok(cli)
+    }
+
+    /// Overrides config values with the options given on the command line.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(project_id) = &self.project_id {
+            config.project_id = project_id.clone();
+        }
+        if let Some(collection) = &self.collection {
+            config.collections = vec![CollectionConfig {
+                name: collection.clone(),
+                target_id: config.collections[0].target_id,
+                max_opens_per_minute: config.max_opens_per_minute,
+            }];
+        }
+        if self.dry_run {
+            config.dry_run = true;
+        }
+        if let Some(days) = self.expiration_days {
+            config.expiration = Duration::days(days);
+        }
+    }
+}
+
+fn parse_command(positional: Vec<String>) -> Result<Option<Command>, String> {
+    let mut positional = positional.into_iter();
+    let Some(name) = positional.next() else {
+        return Ok(None);
+    };
+    let command = match name.as_str() {
+        "share" => Command::Share(
+            positional
+                .next()
+                .ok_or_else(|| "Missing URL for share".to_string())?,
+        ),
+        "once" => Command::Once,
+        "purge" => Command::Purge,
+        "replay" => Command::Replay(
+            positional
+                .next()
+                .map(PathBuf::from)
+                .ok_or_else(|| "Missing file for replay".to_string())?,
+        ),
+        _ => return Err(format!("Unknown command: {}", name)),
+    };
+    match positional.next() {
+        Some(extra) => Err(format!("Unexpected argument: {}", extra)),
+        None => Ok(Some(command)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        Cli::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_flags_and_values() {
+        let cli = parse(&[
+            "--project-id",
+            "demo",
+            "--collection=staging_urls",
+            "--env-file",
+            "/etc/opener.env",
+            "--dry-run",
+            "--expiration-days",
+            "2",
+        ])
+        .unwrap();
+        assert_eq!(cli.project_id.as_deref(), Some("demo"));
+        assert_eq!(cli.collection.as_deref(), Some("staging_urls"));
+        assert_eq!(cli.env_file, Some(PathBuf::from("/etc/opener.env")));
+        assert!(cli.dry_run);
+        assert_eq!(cli.expiration_days, Some(2));
+    }
+
+    #[test]
+    fn parses_commands() {
+        let cli = parse(&["--collection", "inbox", "share", "https://example.com"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Share("https://example.com".to_string())));
+        assert_eq!(cli.collection.as_deref(), Some("inbox"));
+        assert_eq!(parse(&["--dry-run"]).unwrap().command, None);
+        assert!(parse(&["share"]).is_err());
+        assert!(parse(&["share", "https://a.com", "https://b.com"]).is_err());
+        assert!(parse(&["publish"]).is_err());
+
+        assert_eq!(parse(&["once"]).unwrap().command, Some(Command::Once));
+        assert!(parse(&["once", "now"]).is_err());
+
+        let cli = parse(&["purge", "--dry-run"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Purge));
+        assert!(cli.dry_run);
+
+        let cli = parse(&["replay", "events.jsonl"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Replay(PathBuf::from("events.jsonl"))));
+        assert!(parse(&["replay"]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_and_incomplete_arguments() {
+        assert!(parse(&["--verbose"]).is_err());
+        assert!(parse(&["--project-id"]).is_err());
+        assert!(parse(&["--expiration-days", "soon"]).is_err());
+    }
+}
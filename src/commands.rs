@@ -0,0 +1,128 @@
+use crate::cli::Command;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::{STATUS_PENDING, document_id, is_allowed_scheme, validate_url};
+use chrono::{DateTime, Utc};
+use firestore::{FirestoreDb, FirestoreTimestamp};
+use serde::{Deserialize, Serialize};
+
+/// Firestore's limit on writes in one batch.
+const PURGE_BATCH_SIZE: usize = 500;
+
+/// Runs a one-off command against the configured collections.
+pub async fn run(command: &Command, config: &Config) -> Result<(), AppError> {
+    let connect = || crate::initialize_firestore(config, &config.project_id);
+    match command {
+        Command::Share(url) => share(&connect().await?, config, url).await,
+        Command::Once => crate::run_once(connect().await?, config.clone()).await,
+        Command::Purge => purge(&connect().await?, config).await,
+        // Replaying recorded events needs no Firestore connection
+        Command::Replay(path) => crate::record::replay(path, config.clone()).await,
+    }
+}
+
+/// Adds `url` to the collection, stamped with the current time, and prints
+/// the new document's id.
+async fn share(db: &FirestoreDb, config: &Config, url: &str) -> Result<(), AppError> {
+    let invalid = |reason: &str| AppError::InvalidUrl {
+        url: url.to_string(),
+        reason: reason.to_string(),
+    };
+    validate_url(url).map_err(|reason| invalid(&reason))?;
+    if !is_allowed_scheme(url) {
+        return Err(invalid("only http and https URLs can be shared"));
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    struct NewSharedUrl {
+        url: String,
+        #[serde(with = "firestore::serialize_as_timestamp")]
+        timestamp: DateTime<Utc>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<String>,
+        open_count: i64,
+    }
+
+    let collection = &config.collections[0].name;
+    let new_url = NewSharedUrl {
+        url: url.to_string(),
+        timestamp: Utc::now(),
+        status: config.use_status_field.then(|| STATUS_PENDING.to_string()),
+        open_count: 0,
+    };
+    let write_error = |source| AppError::Write {
+        collection: collection.clone(),
+        source,
+    };
+    // Written under URL_FIELD, where the daemon reads it
+    let mut doc = FirestoreDb::serialize_to_doc("", &new_url).map_err(write_error)?;
+    if let Some(url) = doc.fields.remove("url") {
+        doc.fields.insert(config.url_field.clone(), url);
+    }
+    let doc = db
+        .fluent()
+        .insert()
+        .into(collection)
+        .generate_document_id()
+        .document(doc)
+        .execute()
+        .await
+        .map_err(write_error)?;
+    println!("{}", document_id(&doc));
+    Ok(())
+}
+
+/// Deletes documents whose `expired_at` has passed, in batches. With
+/// `--dry-run` the candidates are only listed.
+async fn purge(db: &FirestoreDb, config: &Config) -> Result<(), AppError> {
+    let now = Utc::now();
+    let mut total = 0;
+    for collection in &config.collections {
+        let name = collection.name.as_str();
+        let expired = db
+            .fluent()
+            .select()
+            .from(name)
+            .filter(|q| q.field("expired_at").less_than(FirestoreTimestamp(now)))
+            .query()
+            .await
+            .map_err(|source| AppError::Query {
+                collection: name.to_string(),
+                source,
+            })?;
+        let doc_ids: Vec<&str> = expired.iter().map(document_id).collect();
+        total += doc_ids.len();
+
+        if config.dry_run {
+            for doc_id in &doc_ids {
+                println!("{}/{}", name, doc_id);
+            }
+            continue;
+        }
+
+        let write_error = |source| AppError::Write {
+            collection: name.to_string(),
+            source,
+        };
+        let writer = db.create_simple_batch_writer().await.map_err(write_error)?;
+        for chunk in doc_ids.chunks(PURGE_BATCH_SIZE) {
+            let mut batch = writer.new_batch();
+            for doc_id in chunk {
+                db.fluent()
+                    .delete()
+                    .from(name)
+                    .document_id(*doc_id)
+                    .add_to_batch(&mut batch)
+                    .map_err(write_error)?;
+            }
+            batch.write().await.map_err(write_error)?;
+        }
+    }
+
+    if config.dry_run {
+        println!("Would delete {} expired documents", total);
+    } else {
+        println!("Deleted {} expired documents", total);
+    }
+    Ok(())
+}
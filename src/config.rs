@@ -0,0 +1,878 @@
+use chrono::Duration;
+use crate::active_hours::ActiveHours;
+use crate::listen_state::StateStorage;
+use crate::normalize::{DEFAULT_TRACKING_PARAMS, ascii_host};
+use crate::opener::{CommandOpener, LinkKind};
+use crate::timezone::DisplayZone;
+use log::warn;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use webbrowser::Browser;
+
+const DEFAULT_COLLECTION_NAME: &str = "shared_urls";
+const DEFAULT_LISTENER_TARGET_ID: u32 = 42;
+const DEFAULT_EXPIRATION_HOURS: i64 = 3 * 24;
+const DEFAULT_DEDUP_WINDOW_MINUTES: u64 = 60;
+const DEFAULT_DEDUP_CACHE_SIZE: usize = 10_000;
+const DEFAULT_MAX_RECONNECT_ATTEMPTS: u32 = 5;
+const DEFAULT_CONFIRM_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_OPEN_RETRY_DELAY_MS: u64 = 500;
+const DEFAULT_UPDATE_RETRIES: u32 = 3;
+const DEFAULT_UPDATE_RETRY_DELAY_MS: u64 = 200;
+const DEFAULT_QUEUE_CAPACITY: usize = 100;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_RESOLVE_MAX_REDIRECTS: usize = 5;
+const DEFAULT_RESOLVE_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_RECONNECT_BASE_MS: u64 = 1000;
+const DEFAULT_RECONNECT_MAX_MS: u64 = 60_000;
+const DEFAULT_TAB_DELAY_MS: u64 = 200;
+const DEFAULT_VERIFY_OPEN_WAIT_MS: u64 = 2000;
+const DEFAULT_FETCH_TITLE_TIMEOUT_MS: u64 = 3000;
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 60;
+const DEFAULT_USER_AGENT: &str = concat!("firestore_url_opener/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_MAX_URL_LENGTH: usize = 4096;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_RESUME_STATE_PATH: &str = "last_processed.txt";
+
+/// `BROWSER` values understood by `webbrowser`, with the browser each selects.
+pub const BROWSER_NAMES: &[(&str, Browser)] = &[
+    ("default", Browser::Default),
+    ("firefox", Browser::Firefox),
+    ("chrome", Browser::Chrome),
+    ("safari", Browser::Safari),
+    ("opera", Browser::Opera),
+    ("ie", Browser::InternetExplorer),
+    ("webpositive", Browser::WebPositive),
+];
+
+/// A listened collection, its listener target id and its own open rate limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectionConfig {
+    pub name: String,
+    pub target_id: u32,
+    pub max_opens_per_minute: usize,
+}
+
+/// A project listened to alongside PROJECT_ID, from the config file's
+/// `projects` array.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectConfig {
+    pub project_id: String,
+    pub collections: Vec<CollectionConfig>,
+}
+
+/// What the listener does with a changed document that already has an
+/// `expired_at` field, per EXPIRED_DOC_ACTION.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpiredDocAction {
+    /// Leave it alone.
+    Skip,
+    /// Delete it from the collection.
+    Delete,
+    /// Open it again once its `expired_at` has passed, so touching an
+    /// expired document shares it anew.
+    Reopen,
+}
+
+impl ExpiredDocAction {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "skip" => Ok(ExpiredDocAction::Skip),
+            "delete" => Ok(ExpiredDocAction::Delete),
+            "reopen" => Ok(ExpiredDocAction::Reopen),
+            _ => Err(format!("Invalid EXPIRED_DOC_ACTION value: {}", value)),
+        }
+    }
+}
+
+/// Runtime settings resolved once at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub project_id: String,
+    pub collections: Vec<CollectionConfig>,
+    /// Further projects, each with its own connection and listener.
+    pub projects: Vec<ProjectConfig>,
+    pub expiration: Duration,
+    pub allowed_domains: Vec<String>,
+    pub browser: Browser,
+    pub dry_run: bool,
+    pub read_only: bool,
+    pub delete_after_open: bool,
+    pub dedup_window: std::time::Duration,
+    pub max_opens_per_minute: usize,
+    pub max_reconnect_attempts: u32,
+    pub confirm_before_open: bool,
+    pub confirm_timeout: std::time::Duration,
+    pub notify: bool,
+    pub health_port: Option<u16>,
+    pub metrics_port: Option<u16>,
+    pub replay_backlog: bool,
+    pub claim_before_open: bool,
+    pub instance_id: String,
+    pub open_delay: std::time::Duration,
+    pub max_open_retries: u32,
+    pub open_retry_delay: std::time::Duration,
+    pub use_status_field: bool,
+    pub strip_tracking: bool,
+    pub tracking_params: Vec<String>,
+    pub dedup_state_path: Option<PathBuf>,
+    pub queue_capacity: usize,
+    pub shutdown_timeout: std::time::Duration,
+    pub active_hours: Option<ActiveHours>,
+    pub resolve_redirects: bool,
+    pub resolve_max_redirects: usize,
+    pub resolve_timeout: std::time::Duration,
+    pub audit_collection: Option<String>,
+    pub service_account_path: Option<PathBuf>,
+    pub max_concurrent_opens: usize,
+    pub heartbeat_interval: std::time::Duration,
+    pub stale_threshold: Option<std::time::Duration>,
+    pub reconnect_when_stale: bool,
+    pub percent_decode: bool,
+    pub url_filter: Option<Regex>,
+    pub record_events_path: Option<PathBuf>,
+    pub incognito: bool,
+    pub browser_path: Option<PathBuf>,
+    pub domain_browsers: Vec<(String, Browser)>,
+    pub reconnect_base: std::time::Duration,
+    pub reconnect_max: std::time::Duration,
+    pub resume_from_last: bool,
+    pub resume_state_path: PathBuf,
+    pub state_storage: StateStorage,
+    pub connect_timeout: std::time::Duration,
+    pub append_utm: Vec<(String, String)>,
+    pub max_url_length: usize,
+    pub open_with: Vec<(LinkKind, CommandOpener)>,
+    pub slack_webhook_url: Option<String>,
+    pub verify_open: bool,
+    pub verify_open_wait: std::time::Duration,
+    pub tab_delay: std::time::Duration,
+    /// LOG_LEVEL. Logging reads the variable itself before the config loads;
+    /// this applies the file's `log_level` and SIGHUP reloads.
+    pub log_level: Option<String>,
+    pub dedup_cache_size: usize,
+    pub fetch_title: bool,
+    pub fetch_title_timeout: std::time::Duration,
+    /// Sent with outbound HTTP requests, such as FETCH_TITLE's.
+    pub user_agent: String,
+    /// Proxy for all outbound HTTP, instead of the usual proxy variables.
+    pub proxy_url: Option<String>,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: std::time::Duration,
+    pub queue_state_path: Option<PathBuf>,
+    pub expired_doc_action: ExpiredDocAction,
+    pub domain_rate_limits: Vec<(String, usize)>,
+    pub url_field: String,
+    pub browser_profile: Option<String>,
+    pub debounce: std::time::Duration,
+    pub open_command: Option<CommandOpener>,
+    pub safe_mode: bool,
+    pub update_retries: u32,
+    pub update_retry_delay: std::time::Duration,
+    pub display_timezone: DisplayZone,
+    pub pause_file: Option<PathBuf>,
+}
+
+/// Entry of the config file's `projects` array.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileProject {
+    pub project_id: String,
+    pub collections: Vec<String>,
+}
+
+/// Optional settings file. Keys mirror the environment variables in
+/// snake_case; any variable that is set overrides the file value.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub project_id: Option<String>,
+    pub collection_name: Option<String>,
+    pub collections: Option<Vec<String>>,
+    pub listener_target_id: Option<u32>,
+    pub expiration_hours: Option<i64>,
+    pub allowed_domains: Option<Vec<String>>,
+    pub browser: Option<String>,
+    pub dry_run: Option<bool>,
+    pub read_only: Option<bool>,
+    pub delete_after_open: Option<bool>,
+    pub dedup_window_minutes: Option<u64>,
+    pub max_opens_per_minute: Option<usize>,
+    pub max_reconnect_attempts: Option<u32>,
+    pub confirm_before_open: Option<bool>,
+    pub confirm_timeout_secs: Option<u64>,
+    pub notify: Option<bool>,
+    pub health_port: Option<u16>,
+    pub metrics_port: Option<u16>,
+    pub replay_backlog: Option<bool>,
+    pub claim_before_open: Option<bool>,
+    pub instance_id: Option<String>,
+    pub open_delay_ms: Option<u64>,
+    pub max_open_retries: Option<u32>,
+    pub open_retry_delay_ms: Option<u64>,
+    pub use_status_field: Option<bool>,
+    pub strip_tracking: Option<bool>,
+    pub tracking_params: Option<Vec<String>>,
+    pub dedup_state_path: Option<PathBuf>,
+    pub queue_capacity: Option<usize>,
+    pub shutdown_timeout_secs: Option<u64>,
+    pub active_hours: Option<String>,
+    pub active_timezone: Option<String>,
+    pub resolve_redirects: Option<bool>,
+    pub resolve_max_redirects: Option<usize>,
+    pub resolve_timeout_secs: Option<u64>,
+    pub audit_collection: Option<String>,
+    pub service_account_path: Option<PathBuf>,
+    pub max_concurrent_opens: Option<usize>,
+    pub heartbeat_interval_secs: Option<u64>,
+    pub stale_threshold_secs: Option<u64>,
+    pub reconnect_when_stale: Option<bool>,
+    pub percent_decode: Option<bool>,
+    pub url_filter_regex: Option<String>,
+    pub record_events_path: Option<PathBuf>,
+    pub incognito: Option<bool>,
+    pub browser_path: Option<PathBuf>,
+    pub domain_browser_map: Option<BTreeMap<String, String>>,
+    pub reconnect_base_ms: Option<u64>,
+    pub reconnect_max_ms: Option<u64>,
+    pub resume_from_last: Option<bool>,
+    pub resume_state_path: Option<PathBuf>,
+    pub state_storage: Option<String>,
+    pub state_dir: Option<PathBuf>,
+    pub connect_timeout_secs: Option<u64>,
+    pub append_utm: Option<BTreeMap<String, String>>,
+    pub max_url_length: Option<usize>,
+    pub open_with: Option<BTreeMap<String, String>>,
+    pub slack_webhook_url: Option<String>,
+    pub verify_open: Option<bool>,
+    pub verify_open_wait_ms: Option<u64>,
+    pub tab_delay_ms: Option<u64>,
+    pub projects: Option<Vec<FileProject>>,
+    pub log_level: Option<String>,
+    pub dedup_cache_size: Option<usize>,
+    pub fetch_title: Option<bool>,
+    pub fetch_title_timeout_ms: Option<u64>,
+    pub user_agent: Option<String>,
+    pub proxy_url: Option<String>,
+    pub breaker_threshold: Option<u32>,
+    pub breaker_cooldown_secs: Option<u64>,
+    pub queue_state_path: Option<PathBuf>,
+    pub expired_doc_action: Option<String>,
+    pub domain_rate_limits: Option<BTreeMap<String, usize>>,
+    pub url_field: Option<String>,
+    pub browser_profile: Option<String>,
+    pub debounce_ms: Option<u64>,
+    pub open_command: Option<String>,
+    pub safe_mode: Option<bool>,
+    pub update_retries: Option<u32>,
+    pub update_retry_delay_ms: Option<u64>,
+    pub display_timezone: Option<String>,
+    pub pause_file: Option<PathBuf>,
+}
+
+impl FileConfig {
+    /// Reads the config file, which is JSON. There's no TOML parser among our
+    /// dependencies, so a `.toml` file is refused rather than misread.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("toml")) {
+            return Err(format!(
+                "Config file {} looks like TOML, which isn't supported; write it as JSON",
+                path.display()
+            ));
+        }
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            project_id: String::new(),
+            collections: vec![CollectionConfig {
+                name: DEFAULT_COLLECTION_NAME.to_string(),
+                target_id: DEFAULT_LISTENER_TARGET_ID,
+                max_opens_per_minute: 0,
+            }],
+            expiration: Duration::hours(DEFAULT_EXPIRATION_HOURS),
+            allowed_domains: Vec::new(),
+            browser: Browser::Default,
+            dry_run: false,
+            read_only: false,
+            delete_after_open: false,
+            dedup_window: std::time::Duration::from_secs(DEFAULT_DEDUP_WINDOW_MINUTES * 60),
+            max_opens_per_minute: 0,
+            max_reconnect_attempts: DEFAULT_MAX_RECONNECT_ATTEMPTS,
+            confirm_before_open: false,
+            confirm_timeout: std::time::Duration::from_secs(DEFAULT_CONFIRM_TIMEOUT_SECS),
+            notify: false,
+            health_port: None,
+            metrics_port: None,
+            replay_backlog: false,
+            claim_before_open: false,
+            instance_id: hostname(),
+            open_delay: std::time::Duration::ZERO,
+            max_open_retries: 0,
+            open_retry_delay: std::time::Duration::from_millis(DEFAULT_OPEN_RETRY_DELAY_MS),
+            use_status_field: false,
+            strip_tracking: false,
+            tracking_params: default_tracking_params(),
+            dedup_state_path: None,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            shutdown_timeout: std::time::Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            active_hours: None,
+            resolve_redirects: false,
+            resolve_max_redirects: DEFAULT_RESOLVE_MAX_REDIRECTS,
+            resolve_timeout: std::time::Duration::from_secs(DEFAULT_RESOLVE_TIMEOUT_SECS),
+            audit_collection: None,
+            service_account_path: None,
+            max_concurrent_opens: 1,
+            heartbeat_interval: std::time::Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_SECS),
+            stale_threshold: None,
+            reconnect_when_stale: false,
+            percent_decode: true,
+            url_filter: None,
+            record_events_path: None,
+            incognito: false,
+            browser_path: None,
+            domain_browsers: Vec::new(),
+            reconnect_base: std::time::Duration::from_millis(DEFAULT_RECONNECT_BASE_MS),
+            reconnect_max: std::time::Duration::from_millis(DEFAULT_RECONNECT_MAX_MS),
+            resume_from_last: false,
+            resume_state_path: PathBuf::from(DEFAULT_RESUME_STATE_PATH),
+            state_storage: StateStorage::Files { dir: None },
+            connect_timeout: std::time::Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            append_utm: Vec::new(),
+            max_url_length: DEFAULT_MAX_URL_LENGTH,
+            open_with: Vec::new(),
+            slack_webhook_url: None,
+            verify_open: false,
+            verify_open_wait: std::time::Duration::from_millis(DEFAULT_VERIFY_OPEN_WAIT_MS),
+            tab_delay: std::time::Duration::from_millis(DEFAULT_TAB_DELAY_MS),
+            projects: Vec::new(),
+            log_level: None,
+            dedup_cache_size: DEFAULT_DEDUP_CACHE_SIZE,
+            fetch_title: false,
+            fetch_title_timeout: std::time::Duration::from_millis(DEFAULT_FETCH_TITLE_TIMEOUT_MS),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            proxy_url: None,
+            breaker_threshold: DEFAULT_BREAKER_THRESHOLD,
+            breaker_cooldown: std::time::Duration::from_secs(DEFAULT_BREAKER_COOLDOWN_SECS),
+            queue_state_path: None,
+            expired_doc_action: ExpiredDocAction::Skip,
+            domain_rate_limits: Vec::new(),
+            url_field: "url".to_string(),
+            browser_profile: None,
+            debounce: std::time::Duration::ZERO,
+            open_command: None,
+            safe_mode: false,
+            update_retries: DEFAULT_UPDATE_RETRIES,
+            update_retry_delay: std::time::Duration::from_millis(DEFAULT_UPDATE_RETRY_DELAY_MS),
+            display_timezone: DisplayZone::Utc,
+            pause_file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Collections of every project, each with its project id, `None`
+    /// standing for PROJECT_ID.
+    pub fn all_collections(&self) -> impl Iterator<Item = (Option<&str>, &CollectionConfig)> {
+        let projects = self.projects.iter().flat_map(|project| {
+            project
+                .collections
+                .iter()
+                .map(|collection| (Some(project.project_id.as_str()), collection))
+        });
+        self.collections.iter().map(|collection| (None, collection)).chain(projects)
+    }
+
+    /// Resolves settings from the environment, falling back to the config
+    /// file at `path` (if any) and then to the built-in defaults.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let file = match path {
+            Some(path) => FileConfig::read(path)?,
+            None => FileConfig::default(),
+        };
+
+        let expiration_hours = read_parsed(
+            "EXPIRATION_HOURS",
+            file.expiration_hours.unwrap_or(DEFAULT_EXPIRATION_HOURS),
+        )?;
+        if expiration_hours <= 0 {
+            return Err(format!("Invalid EXPIRATION_HOURS value: {}", expiration_hours));
+        }
+
+        let max_opens_per_minute = read_parsed(
+            "MAX_OPENS_PER_MINUTE",
+            file.max_opens_per_minute.unwrap_or(0),
+        )?;
+        let collection_specs = match read_list("COLLECTIONS", file.collections.unwrap_or_default()) {
+            specs if specs.is_empty() => vec![
+                read_string("COLLECTION_NAME", file.collection_name)
+                    .unwrap_or_else(|| DEFAULT_COLLECTION_NAME.to_string()),
+            ],
+            specs => specs,
+        };
+        // Collections get sequential target ids starting at LISTENER_TARGET_ID
+        let first_target_id = read_parsed(
+            "LISTENER_TARGET_ID",
+            file.listener_target_id.unwrap_or(DEFAULT_LISTENER_TARGET_ID),
+        )?;
+        if first_target_id == 0
+            || first_target_id as u64 + collection_specs.len() as u64 > i32::MAX as u64 + 1
+        {
+            return Err(format!("Invalid LISTENER_TARGET_ID value: {}", first_target_id));
+        }
+        let collections: Vec<CollectionConfig> = collection_specs
+            .iter()
+            .zip(first_target_id..)
+            .map(|(spec, target_id)| parse_collection(spec, target_id, max_opens_per_minute))
+            .collect::<Result<_, _>>()?;
+        for (i, collection) in collections.iter().enumerate() {
+            if collections[..i].iter().any(|other| other.name == collection.name) {
+                return Err(format!("Collection {} is listed more than once", collection.name));
+            }
+        }
+        // Events of every listener are routed by target id, so further
+        // projects continue the numbering after the main collections
+        let mut next_target_id = first_target_id + collections.len() as u32;
+        let mut projects = Vec::new();
+        for project in file.projects.unwrap_or_default() {
+            if project.collections.is_empty() {
+                return Err(format!("Project {} in projects lists no collections", project.project_id));
+            }
+            let mut project_collections = Vec::new();
+            for spec in &project.collections {
+                if next_target_id as u64 > i32::MAX as u64 {
+                    return Err(format!("Too many collections for LISTENER_TARGET_ID {}", first_target_id));
+                }
+                project_collections.push(parse_collection(spec, next_target_id, max_opens_per_minute)?);
+                next_target_id += 1;
+            }
+            projects.push(ProjectConfig {
+                project_id: project.project_id,
+                collections: project_collections,
+            });
+        }
+
+        let max_concurrent_opens = read_parsed("MAX_CONCURRENT_OPENS", file.max_concurrent_opens.unwrap_or(1))?;
+        if max_concurrent_opens == 0 {
+            return Err("Invalid MAX_CONCURRENT_OPENS value: 0".to_string());
+        }
+
+        let queue_capacity = read_parsed(
+            "QUEUE_CAPACITY",
+            file.queue_capacity.unwrap_or(DEFAULT_QUEUE_CAPACITY),
+        )?;
+        if queue_capacity == 0 {
+            return Err("Invalid QUEUE_CAPACITY value: 0".to_string());
+        }
+
+        let file_domain_browsers = file
+            .domain_browser_map
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(host, browser)| format!("{}={}", host, browser))
+            .collect();
+        let domain_browsers = read_list("DOMAIN_BROWSER_MAP", file_domain_browsers)
+            .iter()
+            .map(|rule| parse_domain_browser(rule))
+            .collect::<Result<_, _>>()?;
+
+        let file_domain_rate_limits = file
+            .domain_rate_limits
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(host, limit)| format!("{}={}", host, limit))
+            .collect();
+        let domain_rate_limits = read_list("DOMAIN_RATE_LIMITS", file_domain_rate_limits)
+            .iter()
+            .map(|rule| parse_domain_rate_limit(rule))
+            .collect::<Result<_, _>>()?;
+
+        let file_append_utm = file
+            .append_utm
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        let append_utm: Vec<(String, String)> = read_list("APPEND_UTM", file_append_utm)
+            .iter()
+            .map(|param| parse_query_param(param))
+            .collect::<Result<_, _>>()?;
+        let strip_tracking = read_flag("STRIP_TRACKING", file.strip_tracking.unwrap_or(false))?;
+        // Stripping would remove the very tags being appended
+        if strip_tracking && !append_utm.is_empty() {
+            return Err("APPEND_UTM and STRIP_TRACKING can't be used together".to_string());
+        }
+
+        let file_open_with = file
+            .open_with
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, command)| format!("{}={}", key, command))
+            .collect();
+        let open_with = read_list("OPEN_WITH", file_open_with)
+            .iter()
+            .map(|rule| parse_open_rule(rule))
+            .collect::<Result<_, _>>()?;
+
+        let reconnect_base = read_parsed(
+            "RECONNECT_BASE_MS",
+            file.reconnect_base_ms.unwrap_or(DEFAULT_RECONNECT_BASE_MS),
+        )?;
+        let reconnect_max = read_parsed("RECONNECT_MAX_MS", file.reconnect_max_ms.unwrap_or(DEFAULT_RECONNECT_MAX_MS))?;
+        if reconnect_base == 0 || reconnect_max < reconnect_base {
+            return Err(format!(
+                "Invalid reconnect delays: RECONNECT_BASE_MS {} must be positive and at most RECONNECT_MAX_MS {}",
+                reconnect_base, reconnect_max
+            ));
+        }
+        let reconnect_base = std::time::Duration::from_millis(reconnect_base);
+        let reconnect_max = std::time::Duration::from_millis(reconnect_max);
+
+        let state_storage = StateStorage::parse(
+            &read_string("STATE_STORAGE", file.state_storage).unwrap_or_default(),
+            read_optional("STATE_DIR", file.state_dir)?,
+        )?;
+
+        let url_filter = read_string("URL_FILTER_REGEX", file.url_filter_regex)
+            .map(|pattern| Regex::new(&pattern).map_err(|e| format!("Invalid URL_FILTER_REGEX value: {}", e)))
+            .transpose()?;
+
+        let active_zone = match read_zone("ACTIVE_TIMEZONE", file.active_timezone) {
+            Some(value) => DisplayZone::parse("ACTIVE_TIMEZONE", &value)?,
+            None => DisplayZone::Local,
+        };
+        let active_hours = read_string("ACTIVE_HOURS", file.active_hours)
+            .map(|spec| ActiveHours::parse(&spec, active_zone))
+            .transpose()?;
+
+        Ok(Config {
+            project_id: read_string("PROJECT_ID", file.project_id).unwrap_or_default(),
+            collections,
+            projects,
+            expiration: Duration::hours(expiration_hours),
+            allowed_domains: read_list("ALLOWED_DOMAINS", file.allowed_domains.unwrap_or_default())
+                .iter()
+                .map(|domain| ascii_host(domain))
+                .collect(),
+            browser: read_browser(read_string("BROWSER", file.browser)),
+            dry_run: read_flag("DRY_RUN", file.dry_run.unwrap_or(false))?,
+            read_only: read_flag("READ_ONLY", file.read_only.unwrap_or(false))?,
+            delete_after_open: read_flag(
+                "DELETE_AFTER_OPEN",
+                file.delete_after_open.unwrap_or(false),
+            )?,
+            dedup_window: std::time::Duration::from_secs(
+                read_parsed(
+                    "DEDUP_WINDOW_MINUTES",
+                    file.dedup_window_minutes.unwrap_or(DEFAULT_DEDUP_WINDOW_MINUTES),
+                )? * 60,
+            ),
+            max_opens_per_minute,
+            max_reconnect_attempts: read_parsed(
+                "MAX_RECONNECT_ATTEMPTS",
+                file.max_reconnect_attempts.unwrap_or(DEFAULT_MAX_RECONNECT_ATTEMPTS),
+            )?,
+            confirm_before_open: read_flag(
+                "CONFIRM_BEFORE_OPEN",
+                file.confirm_before_open.unwrap_or(false),
+            )?,
+            confirm_timeout: std::time::Duration::from_secs(read_parsed(
+                "CONFIRM_TIMEOUT_SECS",
+                file.confirm_timeout_secs.unwrap_or(DEFAULT_CONFIRM_TIMEOUT_SECS),
+            )?),
+            notify: read_flag("NOTIFY", file.notify.unwrap_or(false))?,
+            health_port: read_optional("HEALTH_PORT", file.health_port)?,
+            metrics_port: read_optional("METRICS_PORT", file.metrics_port)?,
+            replay_backlog: read_flag("REPLAY_BACKLOG", file.replay_backlog.unwrap_or(false))?,
+            claim_before_open: read_flag("CLAIM_BEFORE_OPEN", file.claim_before_open.unwrap_or(false))?,
+            instance_id: read_string("INSTANCE_ID", file.instance_id).unwrap_or_else(hostname),
+            open_delay: std::time::Duration::from_millis(read_parsed("OPEN_DELAY_MS", file.open_delay_ms.unwrap_or(0))?),
+            max_open_retries: read_parsed("MAX_OPEN_RETRIES", file.max_open_retries.unwrap_or(0))?,
+            open_retry_delay: std::time::Duration::from_millis(read_parsed(
+                "OPEN_RETRY_DELAY_MS",
+                file.open_retry_delay_ms.unwrap_or(DEFAULT_OPEN_RETRY_DELAY_MS),
+            )?),
+            use_status_field: read_flag("USE_STATUS_FIELD", file.use_status_field.unwrap_or(false))?,
+            strip_tracking,
+            tracking_params: read_list("TRACKING_PARAMS", file.tracking_params.unwrap_or_else(default_tracking_params)),
+            dedup_state_path: read_optional("DEDUP_STATE_PATH", file.dedup_state_path)?,
+            queue_capacity,
+            shutdown_timeout: std::time::Duration::from_secs(read_parsed(
+                "SHUTDOWN_TIMEOUT_SECS",
+                file.shutdown_timeout_secs.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            )?),
+            active_hours,
+            resolve_redirects: read_flag("RESOLVE_REDIRECTS", file.resolve_redirects.unwrap_or(false))?,
+            resolve_max_redirects: read_parsed(
+                "RESOLVE_MAX_REDIRECTS",
+                file.resolve_max_redirects.unwrap_or(DEFAULT_RESOLVE_MAX_REDIRECTS),
+            )?,
+            resolve_timeout: std::time::Duration::from_secs(read_parsed(
+                "RESOLVE_TIMEOUT_SECS",
+                file.resolve_timeout_secs.unwrap_or(DEFAULT_RESOLVE_TIMEOUT_SECS),
+            )?),
+            audit_collection: read_string("AUDIT_COLLECTION", file.audit_collection),
+            service_account_path: read_optional("SERVICE_ACCOUNT_PATH", file.service_account_path)?,
+            max_concurrent_opens,
+            heartbeat_interval: std::time::Duration::from_secs(read_parsed(
+                "HEARTBEAT_INTERVAL_SECS",
+                file.heartbeat_interval_secs.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS),
+            )?),
+            stale_threshold: read_optional("STALE_THRESHOLD_SECS", file.stale_threshold_secs)?
+                .filter(|&secs| secs > 0)
+                .map(std::time::Duration::from_secs),
+            reconnect_when_stale: read_flag("RECONNECT_WHEN_STALE", file.reconnect_when_stale.unwrap_or(false))?,
+            percent_decode: read_flag("PERCENT_DECODE", file.percent_decode.unwrap_or(true))?,
+            url_filter,
+            record_events_path: read_optional("RECORD_EVENTS_PATH", file.record_events_path)?,
+            incognito: read_flag("INCOGNITO", file.incognito.unwrap_or(false))?,
+            browser_path: read_optional("BROWSER_PATH", file.browser_path)?,
+            domain_browsers,
+            reconnect_base,
+            reconnect_max,
+            resume_from_last: read_flag("RESUME_FROM_LAST", file.resume_from_last.unwrap_or(false))?,
+            resume_state_path: read_optional("RESUME_STATE_PATH", file.resume_state_path)?.unwrap_or_else(|| PathBuf::from(DEFAULT_RESUME_STATE_PATH)),
+            state_storage,
+            connect_timeout: std::time::Duration::from_secs(read_parsed(
+                "CONNECT_TIMEOUT_SECS",
+                file.connect_timeout_secs.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+            )?),
+            append_utm,
+            max_url_length: read_parsed("MAX_URL_LENGTH", file.max_url_length.unwrap_or(DEFAULT_MAX_URL_LENGTH))?,
+            open_with,
+            slack_webhook_url: read_string("SLACK_WEBHOOK_URL", file.slack_webhook_url),
+            verify_open: read_flag("VERIFY_OPEN", file.verify_open.unwrap_or(false))?,
+            verify_open_wait: std::time::Duration::from_millis(read_parsed(
+                "VERIFY_OPEN_WAIT_MS",
+                file.verify_open_wait_ms.unwrap_or(DEFAULT_VERIFY_OPEN_WAIT_MS),
+            )?),
+            tab_delay: std::time::Duration::from_millis(read_parsed(
+                "TAB_DELAY_MS",
+                file.tab_delay_ms.unwrap_or(DEFAULT_TAB_DELAY_MS),
+            )?),
+            log_level: read_string("LOG_LEVEL", file.log_level),
+            dedup_cache_size: read_parsed("DEDUP_CACHE_SIZE", file.dedup_cache_size.unwrap_or(DEFAULT_DEDUP_CACHE_SIZE))?,
+            fetch_title: read_flag("FETCH_TITLE", file.fetch_title.unwrap_or(false))?,
+            fetch_title_timeout: std::time::Duration::from_millis(read_parsed(
+                "FETCH_TITLE_TIMEOUT_MS",
+                file.fetch_title_timeout_ms.unwrap_or(DEFAULT_FETCH_TITLE_TIMEOUT_MS),
+            )?),
+            user_agent: read_string("USER_AGENT", file.user_agent).unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()),
+            proxy_url: read_string("PROXY_URL", file.proxy_url).map(|proxy_url| parse_proxy_url(&proxy_url)).transpose()?,
+            breaker_threshold: read_parsed("BREAKER_THRESHOLD", file.breaker_threshold.unwrap_or(DEFAULT_BREAKER_THRESHOLD))?,
+            breaker_cooldown: std::time::Duration::from_secs(read_parsed(
+                "BREAKER_COOLDOWN_SECS",
+                file.breaker_cooldown_secs.unwrap_or(DEFAULT_BREAKER_COOLDOWN_SECS),
+            )?),
+            queue_state_path: read_optional("QUEUE_STATE_PATH", file.queue_state_path)?,
+            expired_doc_action: ExpiredDocAction::parse(
+                &read_string("EXPIRED_DOC_ACTION", file.expired_doc_action).unwrap_or_default(),
+            )?,
+            domain_rate_limits,
+            url_field: read_string("URL_FIELD", file.url_field).map_or_else(|| "url".to_string(), |field| field.trim().to_string()),
+            browser_profile: read_string("BROWSER_PROFILE", file.browser_profile),
+            debounce: std::time::Duration::from_millis(read_parsed("DEBOUNCE_MS", file.debounce_ms.unwrap_or(0))?),
+            open_command: read_string("OPEN_COMMAND", file.open_command)
+                .map(|template| CommandOpener::parse("OPEN_COMMAND", &template))
+                .transpose()?,
+            safe_mode: read_flag("SAFE_MODE", file.safe_mode.unwrap_or(false))?,
+            update_retries: read_parsed("UPDATE_RETRIES", file.update_retries.unwrap_or(DEFAULT_UPDATE_RETRIES))?,
+            update_retry_delay: std::time::Duration::from_millis(read_parsed(
+                "UPDATE_RETRY_DELAY_MS",
+                file.update_retry_delay_ms.unwrap_or(DEFAULT_UPDATE_RETRY_DELAY_MS),
+            )?),
+            display_timezone: read_display_zone(read_zone("DISPLAY_TIMEZONE", file.display_timezone)),
+            pause_file: read_optional("PAUSE_FILE", file.pause_file)?,
+        })
+    }
+}
+
+/// Hostname, used as the instance id when `INSTANCE_ID` is unset.
+pub fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn default_tracking_params() -> Vec<String> {
+    DEFAULT_TRACKING_PARAMS.iter().map(|param| param.to_string()).collect()
+}
+
+/// Parses a `name` or `name:max_opens_per_minute` collection entry.
+fn parse_collection(spec: &str, target_id: u32, default_limit: usize) -> Result<CollectionConfig, String> {
+    let (name, limit) = match spec.split_once(':') {
+        Some((name, limit)) => (
+            name.trim(),
+            limit
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid rate limit in COLLECTIONS entry: {}", spec))?,
+        ),
+        None => (spec.trim(), default_limit),
+    };
+    if name.is_empty() {
+        return Err(format!("Invalid COLLECTIONS entry: {}", spec));
+    }
+    Ok(CollectionConfig {
+        name: name.to_string(),
+        target_id,
+        max_opens_per_minute: limit,
+    })
+}
+
+/// Checks a PROXY_URL value is an `http` or `https` URL with a host.
+fn parse_proxy_url(value: &str) -> Result<String, String> {
+    let invalid = || format!("Invalid PROXY_URL value: {}", value);
+    let url = url::Url::parse(value.trim()).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return Err(invalid());
+    }
+    Ok(value.trim().to_string())
+}
+
+/// Parses a `name=value` APPEND_UTM entry.
+fn parse_query_param(param: &str) -> Result<(String, String), String> {
+    match param.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.trim().to_string())),
+        _ => Err(format!("Invalid APPEND_UTM entry: {}", param)),
+    }
+}
+
+/// Parses a `key=command` OPEN_WITH entry.
+fn parse_open_rule(rule: &str) -> Result<(LinkKind, CommandOpener), String> {
+    let (key, command) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid OPEN_WITH entry: {}", rule))?;
+    Ok((LinkKind::parse(key)?, CommandOpener::parse("OPEN_WITH", command)?))
+}
+
+/// Parses a `host=browser` DOMAIN_BROWSER_MAP entry.
+fn parse_domain_browser(rule: &str) -> Result<(String, Browser), String> {
+    let invalid = || format!("Invalid DOMAIN_BROWSER_MAP entry: {}", rule);
+    let (host, name) = rule.split_once('=').ok_or_else(invalid)?;
+    let (host, name) = (ascii_host(host), name.trim().to_lowercase());
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    match BROWSER_NAMES.iter().find(|(known, _)| *known == name) {
+        Some((_, browser)) => Ok((host, *browser)),
+        None => Err(invalid()),
+    }
+}
+
+/// Parses a `host=max_opens_per_minute` DOMAIN_RATE_LIMITS entry.
+fn parse_domain_rate_limit(rule: &str) -> Result<(String, usize), String> {
+    let invalid = || format!("Invalid DOMAIN_RATE_LIMITS entry: {}", rule);
+    let (host, limit) = rule.split_once('=').ok_or_else(invalid)?;
+    let host = ascii_host(host);
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, limit.trim().parse().map_err(|_| invalid())?))
+}
+
+/// Maps a browser name to `webbrowser::Browser`, falling back to the system
+/// default for unknown names.
+fn read_browser(value: Option<String>) -> Browser {
+    let Some(value) = value else {
+        return Browser::Default;
+    };
+    let name = value.trim().to_lowercase();
+    match BROWSER_NAMES.iter().find(|(known, _)| *known == name) {
+        Some((_, browser)) => *browser,
+        None => {
+            warn!("Unknown BROWSER value {:?}, using the default browser", value);
+            Browser::Default
+        }
+    }
+}
+
+/// Reads a time zone setting, treating a blank value, from the config file
+/// too, as unset, so it means the setting's default.
+fn read_zone(name: &str, fallback: Option<String>) -> Option<String> {
+    read_string(name, fallback).filter(|value| !value.trim().is_empty())
+}
+
+/// Reads DISPLAY_TIMEZONE, falling back to UTC for names that can't be
+/// found.
+fn read_display_zone(value: Option<String>) -> DisplayZone {
+    let Some(value) = value else {
+        return DisplayZone::Utc;
+    };
+    DisplayZone::parse("DISPLAY_TIMEZONE", &value).unwrap_or_else(|e| {
+        warn!("{}, showing times in UTC", e);
+        DisplayZone::Utc
+    })
+}
+
+/// Reads a string, treating an empty variable as unset.
+fn read_string(name: &str, fallback: Option<String>) -> Option<String> {
+    env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .or(fallback)
+}
+
+/// Parses a variable, using `fallback` when unset.
+fn read_parsed<T: FromStr>(name: &str, fallback: T) -> Result<T, String> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid {} value: {}", name, value)),
+        Err(_) => Ok(fallback),
+    }
+}
+
+/// Parses an optional variable, using `fallback` when unset or empty.
+fn read_optional<T: FromStr>(name: &str, fallback: Option<T>) -> Result<Option<T>, String> {
+    match env::var(name) {
+        Ok(value) if value.trim().is_empty() => Ok(fallback),
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid {} value: {}", name, value)),
+        Err(_) => Ok(fallback),
+    }
+}
+
+/// Reads a boolean flag, using `fallback` when unset.
+fn read_flag(name: &str, fallback: bool) -> Result<bool, String> {
+    match env::var(name) {
+        Ok(value) => match value.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "" | "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(format!("Invalid {} value: {}", name, value)),
+        },
+        Err(_) => Ok(fallback),
+    }
+}
+
+/// Reads a comma-separated list, ignoring blank entries.
+fn read_list(name: &str, fallback: Vec<String>) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(_) => fallback,
+    }
+}
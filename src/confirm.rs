@@ -0,0 +1,49 @@
+use log::info;
+use std::io::Write;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, Lines};
+use tokio::sync::Mutex;
+
+/// Where the answers are read from; stdin outside tests.
+type Input = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Asks on stdin before each open. Reads are async so the listener keeps
+/// running while a prompt is pending.
+pub struct Confirmer {
+    lines: Mutex<Lines<BufReader<Input>>>,
+    timeout: Duration,
+}
+
+impl Confirmer {
+    pub fn new(timeout: Duration) -> Self {
+        Self::with_input(Box::new(tokio::io::stdin()), timeout)
+    }
+
+    /// Reads the answers from `input` instead of stdin.
+    pub fn with_input(input: Input, timeout: Duration) -> Self {
+        Confirmer {
+            lines: Mutex::new(BufReader::new(input).lines()),
+            timeout,
+        }
+    }
+
+    /// Returns `true` only for an explicit yes; no answer before the timeout,
+    /// end of input, or anything else counts as no.
+    pub async fn confirm(&self, url: &str) -> bool {
+        let mut lines = self.lines.lock().await;
+        print!("Open {}? [y/N] ", url);
+        std::io::stdout().flush().ok();
+
+        match tokio::time::timeout(self.timeout, lines.next_line()).await {
+            Ok(Ok(Some(answer))) => {
+                matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+            }
+            Ok(_) => false,
+            Err(_) => {
+                println!();
+                info!("No confirmation within {:?}", self.timeout);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use fnv::FnvHasher;
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Remembers recently opened URLs so the same link isn't opened twice within
+/// the dedup window. At most `capacity` URLs are kept; past that the least
+/// recently seen one is forgotten, so it may open again.
+#[derive(Debug)]
+pub struct RecentUrls {
+    window: Duration,
+    capacity: usize,
+    opened: HashMap<String, Entry>,
+    /// Dedup keys by when they were last seen, least recent first.
+    recency: BTreeMap<u64, String>,
+    next_use: u64,
+    /// Dedup keys recorded for each document, so deleting the document can
+    /// forget its URLs.
+    doc_keys: HashMap<String, Vec<String>>,
+    /// File the opened URLs are saved to, so a restart doesn't reopen them.
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    opened_at: Instant,
+    /// Key of the entry in `recency`.
+    last_use: u64,
+    /// Whether the open went through; only these are saved.
+    confirmed: bool,
+    /// Document the URL was recorded for, whose `doc_keys` list holds it.
+    doc_id: Option<String>,
+}
+
+impl RecentUrls {
+    /// A capacity of zero leaves the number of URLs unbounded.
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        RecentUrls {
+            window,
+            capacity,
+            opened: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_use: 0,
+            doc_keys: HashMap::new(),
+            path: None,
+        }
+    }
+
+    /// Like `new`, but loads the URLs opened within the window from `path`
+    /// and saves every change back to it. A missing file starts empty.
+    pub fn persisted(window: Duration, capacity: usize, path: PathBuf) -> Self {
+        let mut recent = Self::new(window, capacity);
+        match read_state(&path) {
+            Ok(saved) => {
+                let now = Instant::now();
+                let system_now = SystemTime::now();
+                let mut saved: Vec<_> = saved.into_iter().collect();
+                saved.sort_by_key(|(_, opened_at_ms)| *opened_at_ms);
+                for (key, opened_at_ms) in saved {
+                    let opened_at = UNIX_EPOCH + Duration::from_millis(opened_at_ms);
+                    let age = system_now.duration_since(opened_at).unwrap_or_default();
+                    if age < window
+                        && let Some(opened_at) = now.checked_sub(age)
+                    {
+                        recent.remember(key, opened_at, true, None);
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to load dedup state from {}: {}", path.display(), e),
+        }
+        recent.path = Some(path);
+        recent
+    }
+
+    /// Number of URLs currently remembered.
+    pub fn len(&self) -> usize {
+        self.opened.len()
+    }
+
+    /// Whether the URL was opened, or is being opened, within the window.
+    /// A hit counts as a use, keeping a link that keeps being shared from
+    /// being evicted.
+    pub fn contains(&mut self, url: &str) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+        let now = Instant::now();
+        self.expire(now);
+
+        let key = dedup_key(url);
+        match self.opened.get_mut(&key) {
+            Some(entry) if now.duration_since(entry.opened_at) < self.window => {
+                self.recency.remove(&entry.last_use);
+                entry.last_use = self.next_use;
+                self.recency.insert(self.next_use, key);
+                self.next_use += 1;
+                true
+            }
+            // Used again after it was opened, so it wasn't at the front yet
+            Some(_) => {
+                self.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Records the URL as being opened now, returning `false` if it was
+    /// already opened, or is being opened, within the window. It's only saved
+    /// once `confirm`ed, so a failed launch isn't remembered across a
+    /// restart.
+    pub fn insert(&mut self, url: &str, doc_id: Option<&str>) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+        if self.contains(url) {
+            return false;
+        }
+        let key = dedup_key(url);
+        if let Some(doc_id) = doc_id {
+            self.doc_keys.entry(doc_id.to_string()).or_default().push(key.clone());
+        }
+        self.remember(key, Instant::now(), false, doc_id.map(str::to_string));
+        true
+    }
+
+    /// Forgets the least recently seen URLs while they were opened before the
+    /// window. Stops at the first one still inside it, so an insert doesn't
+    /// look at every entry; one used again since it was opened is caught when
+    /// it's looked up, or once it reaches the front.
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, key)) = self.recency.first_key_value() {
+            if self
+                .opened
+                .get(key)
+                .is_some_and(|entry| now.duration_since(entry.opened_at) < self.window)
+            {
+                break;
+            }
+            let key = key.clone();
+            self.remove(&key);
+        }
+    }
+
+    /// Drops `key` along with its place in `recency` and `doc_keys`.
+    fn remove(&mut self, key: &str) {
+        let Some(entry) = self.opened.remove(key) else {
+            return;
+        };
+        self.recency.remove(&entry.last_use);
+        if let Some(doc_id) = entry.doc_id
+            && let Some(keys) = self.doc_keys.get_mut(&doc_id)
+        {
+            keys.retain(|doc_key| doc_key != key);
+            if keys.is_empty() {
+                self.doc_keys.remove(&doc_id);
+            }
+        }
+    }
+
+    /// Marks an inserted URL as opened and saves it.
+    pub fn confirm(&mut self, url: &str) {
+        if let Some(entry) = self.opened.get_mut(&dedup_key(url))
+            && !entry.confirmed
+        {
+            entry.confirmed = true;
+            self.save();
+        }
+    }
+
+    /// Forgets an inserted URL whose open failed, so sharing it again opens it.
+    pub fn forget(&mut self, url: &str) {
+        self.remove(&dedup_key(url));
+    }
+
+    /// Adds `key` as the most recently seen URL, evicting the least recently
+    /// seen ones beyond the capacity.
+    fn remember(&mut self, key: String, opened_at: Instant, confirmed: bool, doc_id: Option<String>) {
+        let last_use = self.next_use;
+        self.next_use += 1;
+        self.recency.insert(last_use, key.clone());
+        self.opened.insert(
+            key,
+            Entry {
+                opened_at,
+                last_use,
+                confirmed,
+                doc_id,
+            },
+        );
+        while self.capacity > 0 && self.opened.len() > self.capacity {
+            let Some(evicted) = self.recency.first_key_value().map(|(_, key)| key.clone()) else {
+                break;
+            };
+            self.remove(&evicted);
+        }
+    }
+
+    /// Forgets the URLs recorded for a deleted document, returning whether
+    /// there were any.
+    pub fn forget_doc(&mut self, doc_id: &str) -> bool {
+        let keys = self.doc_keys.remove(doc_id).unwrap_or_default();
+        let forgotten = !keys.is_empty();
+        for key in keys {
+            self.remove(&key);
+        }
+        if forgotten {
+            self.save();
+        }
+        forgotten
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let system_now = SystemTime::now();
+        let saved: HashMap<&str, u64> = self
+            .opened
+            .iter()
+            .filter(|(_, entry)| entry.confirmed)
+            .map(|(key, entry)| {
+                let opened_at = system_now - entry.opened_at.elapsed();
+                let opened_at_ms = opened_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                (key.as_str(), opened_at_ms as u64)
+            })
+            .collect();
+        if let Err(e) = write_state(path, &saved) {
+            warn!("Failed to save dedup state to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Keys on a hash of the parsed form, so trivially different spellings of a
+/// URL match and the state file doesn't hold the URLs themselves.
+fn dedup_key(url: &str) -> String {
+    let canonical = Url::parse(url)
+        .map(|parsed| parsed.to_string())
+        .unwrap_or_else(|_| url.to_string());
+    let mut hasher = FnvHasher::default();
+    hasher.write(canonical.as_bytes());
+    format!("{:016x}", hasher.finish())
+}
+
+/// Reads the saved state: dedup keys mapped to when they were opened, in
+/// milliseconds since the Unix epoch.
+fn read_state(path: &Path) -> Result<HashMap<String, u64>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Writes the state next to `path` and renames it over, so a crash mid-write
+/// never leaves a truncated file.
+fn write_state(path: &Path, saved: &HashMap<&str, u64>) -> Result<(), String> {
+    let contents = serde_json::to_string(saved).map_err(|e| e.to_string())?;
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_duplicates_within_window() {
+        let mut recent = RecentUrls::new(Duration::from_secs(60), 0);
+        assert!(recent.insert("https://example.com/page", None));
+        assert!(!recent.insert("https://EXAMPLE.com/page", None));
+        assert!(recent.insert("https://example.com/other", None));
+    }
+
+    #[test]
+    fn forgetting_a_document_allows_its_url_again() {
+        let mut recent = RecentUrls::new(Duration::from_secs(60), 0);
+        assert!(recent.insert("https://example.com/page", Some("abc")));
+        assert!(!recent.forget_doc("other"));
+        assert!(recent.forget_doc("abc"));
+        assert!(recent.insert("https://example.com/page", None));
+        recent.forget("https://example.com/page");
+        assert!(recent.insert("https://example.com/page", None));
+    }
+
+    #[test]
+    fn least_recently_seen_urls_are_evicted_past_the_capacity() {
+        let mut recent = RecentUrls::new(Duration::from_secs(60), 2);
+        assert!(recent.insert("https://example.com/a", Some("doc-a")));
+        assert!(recent.insert("https://example.com/b", None));
+        assert!(!recent.insert("https://example.com/a", None));
+        assert!(recent.insert("https://example.com/c", None));
+        assert_eq!(recent.len(), 2);
+        assert!(!recent.insert("https://example.com/a", None));
+        assert!(!recent.insert("https://example.com/c", None));
+        assert!(recent.insert("https://example.com/b", None));
+        assert!(recent.insert("https://example.com/a", None));
+        assert!(!recent.forget_doc("doc-a"));
+    }
+
+    #[test]
+    fn urls_are_forgotten_once_the_window_passes() {
+        let mut recent = RecentUrls::new(Duration::from_millis(100), 0);
+        assert!(recent.insert("https://example.com/a", Some("doc-a")));
+        assert!(recent.insert("https://example.com/b", None));
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(recent.insert("https://example.com/c", None));
+        assert_eq!(recent.len(), 1);
+        assert!(!recent.forget_doc("doc-a"));
+
+        // Seen again behind a newer URL, but still opened back then
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(recent.insert("https://example.com/d", None));
+        assert!(!recent.insert("https://example.com/c", None));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(recent.insert("https://example.com/c", None));
+        assert!(!recent.insert("https://example.com/d", None));
+    }
+
+    #[test]
+    fn zero_window_disables_dedup() {
+        let mut recent = RecentUrls::new(Duration::ZERO, 0);
+        assert!(recent.insert("https://example.com/page", None));
+        assert!(recent.insert("https://example.com/page", None));
+    }
+
+    #[test]
+    fn persisted_urls_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("dedup_state_{}.json", std::process::id()));
+        let window = Duration::from_secs(60);
+
+        let mut recent = RecentUrls::persisted(window, 0, path.clone());
+        assert!(recent.insert("https://example.com/page", None));
+        recent.confirm("https://example.com/page");
+        // Not opened yet, so not saved
+        assert!(recent.insert("https://example.com/pending", None));
+
+        let mut restarted = RecentUrls::persisted(window, 0, path.clone());
+        assert!(!restarted.insert("https://example.com/page", None));
+        assert!(restarted.insert("https://example.com/other", None));
+        assert!(restarted.insert("https://example.com/pending", None));
+
+        // Entries older than the window are pruned on load
+        let stale_ms = (SystemTime::now() - Duration::from_secs(120))
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        write_state(&path, &HashMap::from([(dedup_key("https://example.com/page").as_str(), stale_ms)])).unwrap();
+        assert_eq!(RecentUrls::persisted(window, 0, path.clone()).len(), 0);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
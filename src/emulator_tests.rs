@@ -0,0 +1,54 @@
+//! End-to-end tests against the Firestore emulator. They pass without doing
+//! anything unless `FIRESTORE_EMULATOR_HOST` is set.
+
+use super::tests::{recording_state, wait_for_opens};
+use super::*;
+use crate::cli::Command;
+use crate::config::CollectionConfig;
+
+/// Config for a collection of its own, so runs don't see each other's
+/// documents, or `None` without an emulator.
+fn emulator_config() -> Option<Config> {
+    env::var("FIRESTORE_EMULATOR_HOST").ok()?;
+    Some(Config {
+        project_id: "demo-url-opener".to_string(),
+        collections: vec![CollectionConfig {
+            name: format!("shared_urls_{}", Utc::now().timestamp_micros()),
+            target_id: 42,
+            max_opens_per_minute: 0,
+        }],
+        ..Config::default()
+    })
+}
+
+#[tokio::test]
+async fn shared_urls_are_opened_and_expired() {
+    let Some(config) = emulator_config() else {
+        eprintln!("FIRESTORE_EMULATOR_HOST is not set, skipping");
+        return;
+    };
+    let url = "https://example.com/emulator";
+    let db = Arc::new(initialize_firestore(&config, &config.project_id).await.unwrap());
+    commands::run(&Command::Share(url.to_string()), &config).await.unwrap();
+
+    let (state, opener) = recording_state(config);
+    let collection = &state.collections[0];
+    let docs = db.fluent().select().from(collection.name.as_str()).query().await.unwrap();
+    assert_eq!(docs.len(), 1);
+    let doc_id = document_id(&docs[0]).to_string();
+
+    // With draining already cancelled, the worker stops once the queue is empty
+    state.db.set(db.clone()).ok();
+    handle_document_change(&state, collection, &docs[0]).await;
+    let receiver = state.queue.take_receiver().unwrap();
+    let draining = CancellationToken::new();
+    draining.cancel();
+    run_worker(state.clone(), receiver, draining).await;
+    wait_for_opens(&state).await;
+    assert_eq!(opener.opened(), [url]);
+
+    let doc = db.get_doc(&collection.name, &doc_id, None).await.unwrap();
+    let stored = FirestoreDb::deserialize_doc_to::<SharedUrl>(&doc).unwrap();
+    assert!(stored.expired_at.is_some_and(|expired_at| expired_at > Utc::now()));
+    assert_eq!(doc.fields["open_count"].value_type, Some(gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(1)));
+}
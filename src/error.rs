@@ -0,0 +1,58 @@
+use firestore::errors::FirestoreError;
+use std::io;
+use std::path::PathBuf;
+
+/// Failures that stop the opener, or a single open, split by where they happen.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("PROJECT_ID must be set with --project-id, the environment or the config file")]
+    MissingProjectId,
+    #[error("Invalid PROJECT_ID {id:?}: {reason}")]
+    InvalidProjectId { id: String, reason: &'static str },
+    #[error("Listener target id {target_id} is used by both {first} and {second}")]
+    DuplicateTargetId {
+        target_id: u32,
+        first: String,
+        second: String,
+    },
+    #[error("Failed to read SERVICE_ACCOUNT_PATH {}: {source}", path.display())]
+    ServiceAccountKey {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to access event log {}: {source}", path.display())]
+    EventLog {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Timed out connecting to Firestore after {0:?}")]
+    ConnectTimeout(std::time::Duration),
+    #[error("Failed to connect to Firestore: {0}")]
+    FirestoreConnect(#[source] FirestoreError),
+    #[error("Failed to start listener: {0}")]
+    ListenerInit(#[source] FirestoreError),
+    #[error("Failed to stop listener: {0}")]
+    ListenerShutdown(#[source] FirestoreError),
+    #[error("Failed to query collection {collection}: {source}")]
+    Query {
+        collection: String,
+        #[source]
+        source: FirestoreError,
+    },
+    #[error("Failed to write to collection {collection}: {source}")]
+    Write {
+        collection: String,
+        #[source]
+        source: FirestoreError,
+    },
+    #[error("Invalid URL {url:?}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+    #[error("{0} URLs failed to open")]
+    OpensFailed(u64),
+    #[error("Failed to open URL in browser: {0}")]
+    BrowserOpen(#[source] io::Error),
+    #[error("Failed to wait for shutdown signal: {0}")]
+    Signal(#[source] io::Error),
+}
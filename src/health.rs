@@ -0,0 +1,166 @@
+use crate::breaker::BreakerState;
+use crate::metrics::Metrics;
+use axum::Router;
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Instant;
+
+/// Liveness reported by `/healthz`.
+#[derive(Debug)]
+pub struct Health {
+    started_at: Instant,
+    listener_running: AtomicBool,
+    /// Unix milliseconds of the last listener event, or 0 before the first.
+    last_event_ms: AtomicI64,
+    /// Unix milliseconds at which the current listener was started.
+    listening_since_ms: AtomicI64,
+    breaker_state: Mutex<BreakerState>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            started_at: Instant::now(),
+            listener_running: AtomicBool::default(),
+            last_event_ms: AtomicI64::default(),
+            listening_since_ms: AtomicI64::default(),
+            breaker_state: Mutex::new(BreakerState::Closed),
+        }
+    }
+}
+
+impl Health {
+    pub fn set_listener_running(&self, running: bool) {
+        if running {
+            self.listening_since_ms
+                .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+        }
+        self.listener_running.store(running, Ordering::Relaxed);
+    }
+
+    pub fn is_listener_running(&self) -> bool {
+        self.listener_running.load(Ordering::Relaxed)
+    }
+
+    pub fn set_breaker_state(&self, state: BreakerState) {
+        *self.breaker_state.lock().unwrap() = state;
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        *self.breaker_state.lock().unwrap()
+    }
+
+    pub fn record_event(&self) {
+        self.last_event_ms
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn last_event(&self) -> Option<DateTime<Utc>> {
+        match self.last_event_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => DateTime::from_timestamp_millis(ms),
+        }
+    }
+
+    /// Time since the last event, or since the listener started if it hasn't
+    /// had one yet.
+    pub fn idle_for(&self, now: DateTime<Utc>) -> std::time::Duration {
+        let since = self
+            .last_event_ms
+            .load(Ordering::Relaxed)
+            .max(self.listening_since_ms.load(Ordering::Relaxed));
+        let idle = now.timestamp_millis() - since;
+        std::time::Duration::from_millis(idle.max(0) as u64)
+    }
+}
+
+/// Body of `/healthz`.
+#[derive(Debug, PartialEq, Serialize)]
+struct HealthReport {
+    uptime_seconds: u64,
+    last_event_at: Option<String>,
+    urls_opened: u64,
+    listener_connected: bool,
+    /// `closed`, `open` or `half_open`; an open breaker leaves the status
+    /// at 200, as the listener still takes URLs.
+    circuit_breaker: &'static str,
+}
+
+impl HealthReport {
+    fn new(health: &Health, metrics: &Metrics) -> Self {
+        HealthReport {
+            uptime_seconds: health.started_at.elapsed().as_secs(),
+            last_event_at: health.last_event().map(|at| at.to_rfc3339()),
+            urls_opened: metrics.urls_opened(),
+            listener_connected: health.is_listener_running(),
+            circuit_breaker: health.breaker_state().as_str(),
+        }
+    }
+}
+
+pub fn routes(health: Arc<Health>, metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .with_state((health, metrics))
+}
+
+/// 200 while the listener runs and 503 otherwise, so probes need only look at
+/// the status; the JSON body is for people and dashboards.
+async fn healthz(State((health, metrics)): State<(Arc<Health>, Arc<Metrics>)>) -> impl IntoResponse {
+    let report = HealthReport::new(&health, &metrics);
+    let status = if report.listener_connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = serde_json::to_string(&report).unwrap_or_default();
+    (status, [(header::CONTENT_TYPE, "application/json")], body + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_time_counts_from_the_last_event_or_listener_start() {
+        let health = Health::default();
+        health.set_listener_running(true);
+        assert_eq!(health.last_event(), None);
+        let later = Utc::now() + chrono::Duration::seconds(30);
+        assert!(health.idle_for(later) >= std::time::Duration::from_secs(29));
+
+        health.record_event();
+        let event = health.last_event().unwrap();
+        assert_eq!(
+            health.idle_for(event + chrono::Duration::seconds(5)),
+            std::time::Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn reports_listener_and_open_counts() {
+        let (health, metrics) = (Health::default(), Metrics::default());
+        let report = HealthReport::new(&health, &metrics);
+        assert_eq!(report.last_event_at, None);
+        assert!(!report.listener_connected);
+
+        health.set_listener_running(true);
+        health.record_event();
+        metrics.record_open();
+        let report = HealthReport::new(&health, &metrics);
+        assert!(report.listener_connected);
+        assert_eq!(report.urls_opened, 1);
+        assert!(report.last_event_at.is_some());
+        let body = serde_json::to_value(&report).unwrap();
+        assert_eq!(body["listener_connected"], true);
+        assert!(body["uptime_seconds"].is_u64());
+        assert_eq!(body["circuit_breaker"], "closed");
+    }
+}
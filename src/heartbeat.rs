@@ -0,0 +1,53 @@
+use crate::health::Health;
+use crate::logging::display_time;
+use crate::metrics::Metrics;
+use chrono::Utc;
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Logs a heartbeat every `interval`, with the running count of documents
+/// that failed to deserialize, and warns once the listener has gone
+/// `stale_threshold` without an event, notifying `stale` so it can be
+/// restarted. A zero `interval` only checks for staleness.
+pub async fn run(
+    health: Arc<Health>,
+    metrics: Arc<Metrics>,
+    interval: Duration,
+    stale_threshold: Option<Duration>,
+    stale: Arc<Notify>,
+) {
+    let period = match stale_threshold {
+        Some(threshold) if interval.is_zero() || threshold < interval => threshold,
+        _ => interval,
+    };
+    let mut ticker = tokio::time::interval(period);
+    // The first tick completes immediately
+    ticker.tick().await;
+    let mut warned = false;
+    loop {
+        ticker.tick().await;
+        let idle = health.idle_for(Utc::now());
+        if !interval.is_zero() {
+            match health.last_event() {
+                Some(at) => info!("Heartbeat: last event at {}, {:?} ago", display_time(at), idle),
+                None => info!("Heartbeat: no events yet"),
+            }
+            match metrics.deserialization_failures() {
+                0 => {}
+                failures => warn!("{} documents have failed to deserialize since startup", failures),
+            }
+        }
+        match stale_threshold {
+            Some(threshold) if idle >= threshold => {
+                if !warned {
+                    warn!("No listener events for {:?}, the listener may be stale", idle);
+                    warned = true;
+                }
+                stale.notify_one();
+            }
+            _ => warned = false,
+        }
+    }
+}
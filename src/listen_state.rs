@@ -0,0 +1,101 @@
+use async_trait::async_trait;
+use firestore::{
+    FirestoreListenerTarget, FirestoreListenerTargetResumeType, FirestoreListenerToken,
+    FirestoreMemListenStateStorage, FirestoreResumeStateStorage, FirestoreTempFilesListenStateStorage,
+};
+use std::path::PathBuf;
+
+type StorageResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Where the listener keeps its resume tokens, picked with `STATE_STORAGE`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateStorage {
+    /// One file per listener target, in `dir` or the working directory.
+    Files { dir: Option<PathBuf> },
+    Memory,
+}
+
+impl StateStorage {
+    pub fn parse(value: &str, dir: Option<PathBuf>) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "file" | "files" | "tempfile" => Ok(StateStorage::Files { dir }),
+            "memory" | "mem" => Ok(StateStorage::Memory),
+            _ => Err(format!("Invalid STATE_STORAGE value: {} (expected file or memory)", value)),
+        }
+    }
+
+    pub fn build(&self) -> ListenStateStorage {
+        match self {
+            StateStorage::Files { dir: Some(dir) } => {
+                ListenStateStorage::Files(FirestoreTempFilesListenStateStorage::with_temp_dir(dir))
+            }
+            StateStorage::Files { dir: None } => ListenStateStorage::Files(FirestoreTempFilesListenStateStorage::new()),
+            StateStorage::Memory => ListenStateStorage::Memory(FirestoreMemListenStateStorage::new()),
+        }
+    }
+}
+
+impl std::fmt::Display for StateStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateStorage::Files { dir } => write!(
+                f,
+                "files in {}; the listener resumes where it stopped after a restart, as long as the directory is kept",
+                dir.as_ref().map_or(".".into(), |dir| dir.display().to_string())
+            ),
+            StateStorage::Memory => write!(
+                f,
+                "memory; nothing is written to disk, but a restart sees every matching document again"
+            ),
+        }
+    }
+}
+
+/// Resume token storage chosen at runtime; the listener needs one concrete
+/// type.
+#[derive(Debug, Clone)]
+pub enum ListenStateStorage {
+    Files(FirestoreTempFilesListenStateStorage),
+    Memory(FirestoreMemListenStateStorage),
+}
+
+#[async_trait]
+impl FirestoreResumeStateStorage for ListenStateStorage {
+    async fn read_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> StorageResult<Option<FirestoreListenerTargetResumeType>> {
+        match self {
+            ListenStateStorage::Files(storage) => storage.read_resume_state(target).await,
+            ListenStateStorage::Memory(storage) => storage.read_resume_state(target).await,
+        }
+    }
+
+    async fn update_resume_token(
+        &self,
+        target: &FirestoreListenerTarget,
+        token: FirestoreListenerToken,
+    ) -> StorageResult<()> {
+        match self {
+            ListenStateStorage::Files(storage) => storage.update_resume_token(target, token).await,
+            ListenStateStorage::Memory(storage) => storage.update_resume_token(target, token).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_storage_kinds() {
+        let dir = Some(PathBuf::from("/var/lib/opener"));
+        assert_eq!(
+            StateStorage::parse("file", dir.clone()),
+            Ok(StateStorage::Files { dir: dir.clone() })
+        );
+        assert_eq!(StateStorage::parse("", None), Ok(StateStorage::Files { dir: None }));
+        assert_eq!(StateStorage::parse("Memory", dir), Ok(StateStorage::Memory));
+        assert!(StateStorage::parse("redis", None).is_err());
+    }
+}
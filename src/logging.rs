@@ -0,0 +1,238 @@
+use crate::timezone::DisplayZone;
+use chrono::{DateTime, Utc};
+use log::LevelFilter;
+use log::kv::{Error, Key, Value, VisitSource};
+use serde_json::{Map, Value as JsonValue};
+use std::fmt;
+use std::io::Write;
+use std::sync::{OnceLock, RwLock};
+use url::Url;
+
+static URL_LOG_MODE: OnceLock<UrlLogMode> = OnceLock::new();
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+static DISPLAY_ZONE: OnceLock<DisplayZone> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "text" | "plain" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// How much of a URL appears in log output. The browser always gets the full
+/// URL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UrlLogMode {
+    Full,
+    /// Only the host, such as `example.com`.
+    HostOnly,
+    /// Everything after `?` replaced with `<redacted>`.
+    RedactQuery,
+}
+
+impl UrlLogMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "full" => Some(UrlLogMode::Full),
+            "host_only" | "host" => Some(UrlLogMode::HostOnly),
+            "redact_query" | "redact" => Some(UrlLogMode::RedactQuery),
+            _ => None,
+        }
+    }
+}
+
+/// Installs the global logger. `RUST_LOG` sets the filter unless `level`
+/// overrides it; with neither, `LOG_LEVEL` sets a plain level, defaulting to
+/// info. JSON output puts structured fields (`url_host`, `doc_id`,
+/// `event_type`, ...) in their own keys.
+pub fn init(format: LogFormat, level: Option<&str>, url_mode: UrlLogMode) {
+    URL_LOG_MODE.set(url_mode).ok();
+
+    let log_level = std::env::var("LOG_LEVEL").ok();
+    let default_level = parse_level(log_level.as_deref());
+    let env = env_logger::Env::default().default_filter_or(default_level.unwrap_or(LevelFilter::Info).as_str());
+    let mut builder = env_logger::Builder::from_env(env);
+    if let Some(level) = level {
+        builder.parse_filters(level);
+    }
+    install(format, builder);
+
+    if let (Some(value), None) = (log_level, default_level) {
+        log::warn!("Invalid LOG_LEVEL value {:?}, logging at info", value);
+    }
+}
+
+/// Replaces the log filter with the plain level `value`, as a config reload
+/// does for `LOG_LEVEL`. Returns the new level, or `None` if `value` isn't one
+/// and the filter was left alone.
+pub fn set_level(value: &str) -> Option<LevelFilter> {
+    let level = parse_level(Some(value))?;
+    if let Some(logger) = LOGGER.get() {
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(level);
+        let inner = with_format(builder, logger.format).build();
+        log::set_max_level(inner.filter());
+        *logger.inner.write().unwrap() = inner;
+    }
+    Some(level)
+}
+
+/// The global logger, wrapping an `env_logger` one that `set_level` can swap.
+struct ReloadableLogger {
+    format: LogFormat,
+    inner: RwLock<env_logger::Logger>,
+}
+
+impl log::Log for ReloadableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.inner.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+
+fn install(format: LogFormat, builder: env_logger::Builder) {
+    let inner = with_format(builder, format).build();
+    let max_level = inner.filter();
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        format,
+        inner: RwLock::new(inner),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+fn with_format(mut builder: env_logger::Builder, format: LogFormat) -> env_logger::Builder {
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut fields = Map::new();
+            fields.insert("timestamp".into(), Utc::now().to_rfc3339().into());
+            fields.insert("level".into(), record.level().as_str().into());
+            fields.insert("target".into(), record.target().into());
+            fields.insert("message".into(), record.args().to_string().into());
+            record
+                .key_values()
+                .visit(&mut JsonFields(&mut fields))
+                .ok();
+            writeln!(buf, "{}", JsonValue::Object(fields))
+        });
+    }
+    builder
+}
+
+/// Parses a `LOG_LEVEL` value such as `debug` or `WARN`. Unset and blank
+/// values give info; anything unrecognised gives `None`.
+fn parse_level(value: Option<&str>) -> Option<LevelFilter> {
+    match value.map(str::trim) {
+        None | Some("") => Some(LevelFilter::Info),
+        Some(value) if value.eq_ignore_ascii_case("warning") => Some(LevelFilter::Warn),
+        Some(value) => value.parse().ok(),
+    }
+}
+
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = if let Some(number) = value.to_i64() {
+            number.into()
+        } else if let Some(flag) = value.to_bool() {
+            flag.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Formats a URL for log output as `LOG_URL_MODE` asks.
+pub fn display_url(url: &str) -> LoggedUrl<'_> {
+    LoggedUrl {
+        url,
+        mode: URL_LOG_MODE.get().copied().unwrap_or(UrlLogMode::Full),
+    }
+}
+
+pub struct LoggedUrl<'a> {
+    url: &'a str,
+    mode: UrlLogMode,
+}
+
+impl fmt::Display for LoggedUrl<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mode {
+            UrlLogMode::Full => f.write_str(self.url),
+            UrlLogMode::HostOnly => match url_host(self.url) {
+                host if host.is_empty() => f.write_str("<redacted>"),
+                host => f.write_str(&host),
+            },
+            UrlLogMode::RedactQuery => match self.url.split_once('?') {
+                Some((base, _)) => write!(f, "{}?<redacted>", base),
+                None => f.write_str(self.url),
+            },
+        }
+    }
+}
+
+/// Sets the zone `display_time` shows times in, once DISPLAY_TIMEZONE has
+/// been read.
+pub fn set_display_zone(zone: DisplayZone) {
+    DISPLAY_ZONE.set(zone).ok();
+}
+
+/// Formats a time for log output in DISPLAY_TIMEZONE, UTC until it's set.
+pub fn display_time(at: DateTime<Utc>) -> String {
+    DISPLAY_ZONE.get().unwrap_or(&DisplayZone::Utc).format(at)
+}
+
+/// Host of `url` as a structured log field, or an empty string if it has none.
+pub fn url_host(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|parsed| parsed.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_logged_as_the_mode_asks() {
+        let logged = |url, mode| LoggedUrl { url, mode }.to_string();
+        let url = "https://example.com/reset?token=secret";
+        assert_eq!(logged(url, UrlLogMode::Full), url);
+        assert_eq!(logged(url, UrlLogMode::HostOnly), "example.com");
+        assert_eq!(logged(url, UrlLogMode::RedactQuery), "https://example.com/reset?<redacted>");
+        assert_eq!(logged("https://example.com/page", UrlLogMode::RedactQuery), "https://example.com/page");
+        assert_eq!(logged("not a url?x=1", UrlLogMode::HostOnly), "<redacted>");
+        assert_eq!(UrlLogMode::parse("HOST_ONLY"), Some(UrlLogMode::HostOnly));
+        assert_eq!(UrlLogMode::parse("some"), None);
+    }
+
+    #[test]
+    fn parses_log_levels() {
+        assert_eq!(parse_level(None), Some(LevelFilter::Info));
+        assert_eq!(parse_level(Some("DEBUG")), Some(LevelFilter::Debug));
+        assert_eq!(parse_level(Some(" error ")), Some(LevelFilter::Error));
+        assert_eq!(parse_level(Some("warning")), Some(LevelFilter::Warn));
+        assert_eq!(parse_level(Some("loud")), None);
+    }
+}
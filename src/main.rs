@@ -1,145 +1,3280 @@
+mod active_hours;
+mod audit;
+mod backoff;
+mod breaker;
+mod claim;
+mod cli;
+mod commands;
+mod config;
+mod confirm;
+mod dedup;
+#[cfg(test)]
+mod emulator_tests;
+mod error;
+mod health;
+mod http;
+mod listen_state;
+mod heartbeat;
+mod logging;
+mod metrics;
+mod normalize;
+mod notify;
+mod open_count;
+mod opener;
+mod pause;
+mod queue;
+mod queue_state;
+mod rate_limit;
+mod record;
+mod reload;
+mod resolve;
+mod resume;
+mod server;
+mod slack;
+mod timezone;
+mod title;
+mod updater;
+
+use audit::AuditEntry;
+use backoff::Backoff;
+use breaker::CircuitBreaker;
+use cli::Cli;
+use config::{BROWSER_NAMES, CollectionConfig, Config, ExpiredDocAction};
+use confirm::Confirmer;
+use dedup::RecentUrls;
+use error::AppError;
+use health::Health;
+use listen_state::ListenStateStorage;
+use logging::{LogFormat, UrlLogMode, display_time, display_url, url_host};
+use metrics::Metrics;
+use normalize::{append_params, normalize_url};
+use opener::{LinkKind, UrlOpener, WebBrowserOpener};
+use pause::PauseFile;
+use queue::{QueueReceiver, UrlQueue};
+use queue_state::{QueueState, SavedUrl};
+use rate_limit::{OpenPacer, RateLimiter};
+use record::EventRecorder;
+use reload::LiveSettings;
+use resolve::RedirectResolver;
+use resume::LastProcessed;
+use slack::{OpenedMessage, SlackWebhook};
+use title::TitleFetcher;
+use updater::{DocUpdater, ExpiryUpdate, RetryingUpdater, StatusUpdate};
 use firestore::*;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::path::{Path, PathBuf};
 use tokio::signal;
-use webbrowser;
-use log::{info, error};
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinHandle;
+use log::{debug, info, warn, error};
 use chrono::prelude::*;
 use percent_encoding::percent_decode_str;
-use chrono::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use url::Url;
+use webbrowser::Browser;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct SharedUrl {
     #[serde(alias = "_firestore_id")]
     doc_id: Option<String>,
+    #[serde(default)]
     url: String,
-    #[serde(with = "firestore::serialize_as_timestamp")]
+    /// Further links of a document that batches several, opened after `url`.
+    #[serde(default)]
+    urls: Vec<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(
+        serialize_with = "firestore::serialize_as_timestamp::serialize",
+        deserialize_with = "tolerant_timestamp"
+    )]
     timestamp: DateTime<Utc>,
     #[serde(with = "firestore::serialize_as_optional_timestamp", default)]
     expired_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    status: Option<String>,
+    /// Not opened before this time, if set.
+    #[serde(with = "firestore::serialize_as_optional_timestamp", default)]
+    open_at: Option<DateTime<Utc>>,
+    /// Higher priorities are opened first, ahead of older documents.
+    #[serde(default)]
+    priority: i32,
+    /// Only opened by the instance with this INSTANCE_ID or hostname, if set.
+    #[serde(default)]
+    target_device: Option<String>,
+}
+
+/// Reads a timestamp, falling back to the current time with a warning when
+/// the stored value isn't one, so a bad `timestamp` doesn't drop the URL.
+fn tolerant_timestamp<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredTimestamp {
+        Valid(DateTime<Utc>),
+        Malformed(serde_json::Value),
+    }
+
+    Ok(match StoredTimestamp::deserialize(deserializer)? {
+        StoredTimestamp::Valid(timestamp) => timestamp,
+        StoredTimestamp::Malformed(value) => {
+            warn!("Malformed timestamp {}, using the current time instead", value);
+            Utc::now()
+        }
+    })
+}
+
+impl SharedUrl {
+    /// Orders documents by when they should be opened: by priority, highest
+    /// first, then oldest first.
+    fn open_order(&self, other: &SharedUrl) -> std::cmp::Ordering {
+        other.priority.cmp(&self.priority).then(self.timestamp.cmp(&other.timestamp))
+    }
+
+    /// The document's links in the order they are opened: `url`, then the
+    /// entries of `urls`, leaving out blank ones.
+    fn links(&self) -> Vec<&str> {
+        std::iter::once(&self.url)
+            .chain(&self.urls)
+            .map(String::as_str)
+            .filter(|url| !url.trim().is_empty())
+            .collect()
+    }
+
+    /// Whether this instance opens the document: one without a
+    /// `target_device` opens everywhere, one with it only where it names
+    /// INSTANCE_ID or the hostname, ignoring case.
+    fn is_for_this_device(&self, config: &Config) -> bool {
+        match self.target_device.as_deref().map(str::trim) {
+            None | Some("") => true,
+            Some(device) => {
+                device.eq_ignore_ascii_case(&config.instance_id) || device.eq_ignore_ascii_case(&config::hostname())
+            }
+        }
+    }
+}
+
+const STATUS_PENDING: &str = "pending";
+const STATUS_OPENED: &str = "opened";
+const STATUS_FAILED: &str = "failed";
+
+/// A URL waiting to be opened, with the target of the collection it came from.
+struct QueuedUrl {
+    target_id: u32,
+    shared_url: SharedUrl,
+}
+
+/// The queue hands out its greatest item first, so the URL to open next
+/// compares greatest.
+impl Ord for QueuedUrl {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.shared_url.open_order(&other.shared_url).reverse()
+    }
+}
+
+impl PartialOrd for QueuedUrl {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedUrl {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for QueuedUrl {}
+
+/// A listened collection, registered under its own listener target.
+struct CollectionHandler {
+    target: FirestoreListenerTarget,
+    name: String,
+    /// Project of the collection when it isn't PROJECT_ID.
+    project_id: Option<String>,
+    rate_limiter: RateLimiter,
+}
+
+/// State shared by every listener callback invocation.
+struct AppState {
+    config: Config,
+    /// The settings a SIGHUP reload may have changed since startup; read
+    /// these instead of their `config` fields.
+    live: RwLock<LiveSettings>,
+    collections: Vec<CollectionHandler>,
+    recent_urls: Mutex<RecentUrls>,
+    confirmer: Option<Confirmer>,
+    resolver: Option<RedirectResolver>,
+    title_fetcher: Option<TitleFetcher>,
+    /// Newest processed document timestamp, with RESUME_FROM_LAST.
+    last_processed: Option<LastProcessed>,
+    pacer: OpenPacer,
+    /// Caps browser launches running at the same time at MAX_CONCURRENT_OPENS.
+    open_permits: Semaphore,
+    breaker: CircuitBreaker,
+    opener: Arc<dyn UrlOpener>,
+    /// Writes back to documents in place of their project's connection;
+    /// only tests set it.
+    updater: Option<Arc<dyn DocUpdater>>,
+    /// Limiters for the DOMAIN_RATE_LIMITS hosts, used instead of their
+    /// collection's.
+    domain_rate_limiters: Vec<(String, RateLimiter)>,
+    /// Openers for the DOMAIN_BROWSER_MAP hosts and the browser each launches.
+    domain_openers: Vec<(String, Browser, Arc<dyn UrlOpener>)>,
+    slack: Option<SlackWebhook>,
+    /// Commands from OPEN_WITH, checked before the browser openers.
+    link_openers: Vec<(LinkKind, Arc<dyn UrlOpener>)>,
+    /// Resume tokens, kept across listener restarts.
+    listen_state: ListenStateStorage,
+    /// Browser opens still in flight.
+    open_tasks: TaskTracker,
+    /// Set once connected, for writes made after opening such as auditing.
+    db: OnceLock<Arc<FirestoreDb>>,
+    /// Connections to the further projects, by project id.
+    project_dbs: OnceLock<HashMap<String, Arc<FirestoreDb>>>,
+    queue: UrlQueue<QueuedUrl>,
+    /// Copy of the queue on disk, with QUEUE_STATE_PATH.
+    queue_state: Option<QueueState>,
+    /// Holds opens while PAUSE_FILE exists.
+    pause: Option<Arc<PauseFile>>,
+    /// Timers of documents waiting for their `open_at`, by document id.
+    snoozed: Mutex<HashMap<String, tokio::task::AbortHandle>>,
+    /// Timers of documents changed within DEBOUNCE_MS, by document id.
+    debounced: Mutex<HashMap<String, tokio::task::AbortHandle>>,
+    /// Documents opened but not marked as handled, even after UPDATE_RETRIES;
+    /// their further changes are ignored so they aren't opened again.
+    unmarked: Mutex<HashSet<String>>,
+    /// Set once shutdown starts; new listen events are ignored from then on.
+    shutting_down: AtomicBool,
+    health: Arc<Health>,
+    /// Writes every listener event to RECORD_EVENTS_PATH, if set.
+    recorder: Option<EventRecorder>,
+    metrics: Arc<Metrics>,
+}
+
+/// Opener launching `browser`, in a private window with INCOGNITO. An
+/// OPEN_COMMAND replaces the browser altogether.
+fn browser_opener(config: &Config, browser: Browser) -> Arc<dyn UrlOpener> {
+    let verify = config.verify_open.then_some(config.verify_open_wait);
+    if let Some(command) = &config.open_command {
+        Arc::new(command.clone().verified(verify))
+    } else if config.incognito || config.browser_profile.is_some() {
+        opener::direct_opener(
+            browser,
+            config.browser_path.as_deref(),
+            config.incognito,
+            config.browser_profile.as_deref(),
+            verify,
+        )
+    } else if let Some(wait) = verify {
+        Arc::new(opener::VerifiedOpener::new(browser, config.browser_path.as_deref(), wait))
+    } else {
+        Arc::new(WebBrowserOpener::new(browser))
+    }
+}
+
+impl AppState {
+    fn new(config: Config) -> Self {
+        let opener = browser_opener(&config, config.browser);
+        Self::with_opener(config, opener)
+    }
+
+    fn with_opener(config: Config, opener: Arc<dyn UrlOpener>) -> Self {
+        let metrics = Arc::new(Metrics::default());
+        let http_client = http::client(&config);
+        let health = Arc::new(Health::default());
+        AppState {
+            collections: config
+                .all_collections()
+                .map(|(project_id, collection)| CollectionHandler {
+                    target: FirestoreListenerTarget::new(collection.target_id),
+                    name: collection.name.clone(),
+                    project_id: project_id.map(str::to_string),
+                    rate_limiter: RateLimiter::per_minute(collection.max_opens_per_minute),
+                })
+                .collect(),
+            recent_urls: Mutex::new(match &config.dedup_state_path {
+                Some(path) => RecentUrls::persisted(config.dedup_window, config.dedup_cache_size, path.clone()),
+                None => RecentUrls::new(config.dedup_window, config.dedup_cache_size),
+            }),
+            confirmer: config
+                .confirm_before_open
+                .then(|| Confirmer::new(config.confirm_timeout)),
+            resolver: config
+                .resolve_redirects
+                .then(|| RedirectResolver::new(http_client.clone(), config.resolve_timeout)),
+            title_fetcher: config
+                .fetch_title
+                .then(|| TitleFetcher::new(http_client.clone(), config.fetch_title_timeout)),
+            last_processed: config
+                .resume_from_last
+                .then(|| LastProcessed::load(config.resume_state_path.clone())),
+            pacer: OpenPacer::new(config.open_delay),
+            open_permits: Semaphore::new(config.max_concurrent_opens),
+            breaker: CircuitBreaker::new(config.breaker_threshold, config.breaker_cooldown, health.clone()),
+            domain_rate_limiters: config
+                .domain_rate_limits
+                .iter()
+                .map(|(host, max_opens)| (host.clone(), RateLimiter::per_minute(*max_opens)))
+                .collect(),
+            // OPEN_COMMAND replaces every browser, the mapped ones included
+            domain_openers: config
+                .domain_browsers
+                .iter()
+                .filter(|_| config.open_command.is_none())
+                .map(|(host, browser)| (host.clone(), *browser, browser_opener(&config, *browser)))
+                .collect(),
+            slack: config
+                .slack_webhook_url
+                .clone()
+                .map(|url| SlackWebhook::new(http_client.clone(), url)),
+            link_openers: config
+                .open_with
+                .iter()
+                .map(|(kind, command)| (kind.clone(), Arc::new(command.clone()) as Arc<dyn UrlOpener>))
+                .collect(),
+            opener,
+            updater: None,
+            listen_state: config.state_storage.build(),
+            open_tasks: TaskTracker::new(),
+            db: OnceLock::new(),
+            project_dbs: OnceLock::new(),
+            health,
+            recorder: None,
+            queue: UrlQueue::new(
+                config
+                    .all_collections()
+                    .map(|(_, collection)| (collection.target_id, collection.name.clone()))
+                    .collect(),
+                config.queue_capacity,
+                metrics.clone(),
+            ),
+            queue_state: config.queue_state_path.clone().map(QueueState::load),
+            pause: config.pause_file.clone().map(|path| Arc::new(PauseFile::new(path))),
+            snoozed: Mutex::new(HashMap::new()),
+            debounced: Mutex::new(HashMap::new()),
+            unmarked: Mutex::new(HashSet::new()),
+            shutting_down: AtomicBool::new(false),
+            metrics,
+            live: RwLock::new(LiveSettings::from_config(&config)),
+            config,
+        }
+    }
+
+    /// Opener for URLs on `host`, with the browser of the DOMAIN_BROWSER_MAP
+    /// rule that picked it, if any.
+    fn opener_for(&self, host: &str) -> (Arc<dyn UrlOpener>, Option<Browser>) {
+        match self.domain_openers.iter().find(|(domain, _, _)| domain == &host.to_lowercase()) {
+            Some((_, browser, opener)) => (opener.clone(), Some(*browser)),
+            None => (self.opener.clone(), None),
+        }
+    }
+
+    /// The rate limit opens on `host` wait for: its DOMAIN_RATE_LIMITS one, or
+    /// else that of the collection.
+    fn rate_limiter_for<'a>(&'a self, host: &str, collection: &'a CollectionHandler) -> &'a RateLimiter {
+        match self.domain_rate_limiters.iter().find(|(domain, _)| domain == &host.to_lowercase()) {
+            Some((_, limiter)) => limiter,
+            None => &collection.rate_limiter,
+        }
+    }
+
+    /// The OPEN_WITH command for `url`, if a rule matches it.
+    fn link_opener(&self, url: &str) -> Option<(&LinkKind, Arc<dyn UrlOpener>)> {
+        let parsed = Url::parse(url).ok()?;
+        self.link_openers
+            .iter()
+            .find(|(kind, _)| kind.matches(&parsed))
+            .map(|(kind, opener)| (kind, opener.clone()))
+    }
+
+    /// Connection to the project `collection` belongs to, once connected.
+    fn db_for(&self, collection: &CollectionHandler) -> Option<&Arc<FirestoreDb>> {
+        match &collection.project_id {
+            None => self.db.get(),
+            Some(project_id) => self.project_dbs.get()?.get(project_id),
+        }
+    }
+
+    /// What writes back to the documents of `collection`: its project's
+    /// connection, once connected, retrying failed writes.
+    fn updater_for(&self, collection: &CollectionHandler) -> Option<Arc<dyn DocUpdater>> {
+        let updater = match &self.updater {
+            Some(updater) => updater.clone(),
+            None => self.db_for(collection)?.clone(),
+        };
+        let (retries, delay) = (self.config.update_retries, self.config.update_retry_delay);
+        Some(Arc::new(RetryingUpdater::new(updater, retries, delay)))
+    }
+
+    /// Every connected project with its connection, `None` standing for
+    /// PROJECT_ID.
+    fn connections(&self) -> Vec<(Option<String>, Arc<FirestoreDb>)> {
+        let projects = self
+            .project_dbs
+            .get()
+            .into_iter()
+            .flatten()
+            .map(|(project_id, db)| (Some(project_id.clone()), db.clone()));
+        self.db.get().map(|db| (None, db.clone())).into_iter().chain(projects).collect()
+    }
+
+    /// Finds the collection a listen event was delivered for.
+    fn collection_for(&self, target_ids: &[i32]) -> Option<&CollectionHandler> {
+        self.collections
+            .iter()
+            .find(|collection| target_ids.contains(&(*collection.target.value() as i32)))
+    }
 }
 
-const TARGET_ID: FirestoreListenerTarget = FirestoreListenerTarget::new(42u32);
+type UrlListener = FirestoreListener<FirestoreDb, ListenStateStorage>;
 
-async fn initialize_firestore(project_id: &str) -> Result<FirestoreDb, Box<dyn std::error::Error>> {
-    let db = FirestoreDb::new(project_id).await?;
-    info!("Connected to Firestore");
+async fn initialize_firestore(config: &Config, project_id: &str) -> Result<FirestoreDb, AppError> {
+    // FirestoreDb talks to FIRESTORE_EMULATOR_HOST instead of production when it is set
+    let emulator_host = env::var("FIRESTORE_EMULATOR_HOST")
+        .ok()
+        .filter(|host| !host.trim().is_empty());
+    // Check the key up front, the client's own error doesn't name the file
+    if let Some(path) = &config.service_account_path
+        && let Err(source) = std::fs::File::open(path)
+    {
+        return Err(AppError::ServiceAccountKey {
+            path: path.clone(),
+            source,
+        });
+    }
+    let connect = async {
+        match &config.service_account_path {
+            Some(path) => {
+                info!("Using service account key file: {}", path.display());
+                FirestoreDb::with_options_service_account_key_file(
+                    FirestoreDbOptions::new(project_id.to_string()),
+                    path.clone(),
+                )
+                .await
+            }
+            // The emulator accepts any bearer token, so no credentials are needed
+            None if emulator_host.is_some() => {
+                let token_source = gcloud_sdk::ExternalJwtFunctionSource::new(|| async {
+                    Ok(gcloud_sdk::Token::new(
+                        "Bearer".to_string(),
+                        "owner".into(),
+                        DateTime::<Utc>::MAX_UTC,
+                    ))
+                });
+                FirestoreDb::with_options_token_source(
+                    FirestoreDbOptions::new(project_id.to_string()),
+                    gcloud_sdk::GCP_DEFAULT_SCOPES.clone(),
+                    gcloud_sdk::TokenSourceType::ExternalSource(Box::new(token_source)),
+                )
+                .await
+            }
+            None => {
+                match env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+                    Some(path) => info!(
+                        "Using application default credentials from GOOGLE_APPLICATION_CREDENTIALS: {}",
+                        PathBuf::from(path).display()
+                    ),
+                    None => info!("Using application default credentials"),
+                }
+                FirestoreDb::new(project_id).await
+            }
+        }
+    };
+    // An unreachable network or auth endpoint would otherwise hang startup
+    info!("Connecting to Firestore, giving up after {:?}", config.connect_timeout);
+    let started = std::time::Instant::now();
+    let db = tokio::time::timeout(config.connect_timeout, connect)
+        .await
+        .map_err(|_| AppError::ConnectTimeout(config.connect_timeout))?
+        .map_err(AppError::FirestoreConnect)?;
+    debug!("Connected to Firestore in {:?}", started.elapsed());
+    match emulator_host {
+        Some(host) => warn!(
+            "Connected to the Firestore EMULATOR at {} (project {}), not production",
+            host, project_id
+        ),
+        None => info!("Connected to Firestore project {} (production)", project_id),
+    }
     Ok(db)
 }
 
-async fn initialize_listener(db: &FirestoreDb) -> Result<FirestoreListener<FirestoreDb, FirestoreTempFilesListenStateStorage>, Box<dyn std::error::Error>> {
+async fn initialize_listener(db: &FirestoreDb, storage: &ListenStateStorage) -> Result<UrlListener, AppError> {
     let listener = db
-        .create_listener(FirestoreTempFilesListenStateStorage::new())
-        .await?;
+        .create_listener(storage.clone())
+        .await
+        .map_err(AppError::ListenerInit)?;
     Ok(listener)
 }
 
-fn handle_url(url: &str) {
-    if let Ok(decoded_url) = percent_decode_str(url).decode_utf8() {
-        info!("Opening decoded URL: {}", decoded_url);
-        if let Err(e) = webbrowser::open(decoded_url.as_ref()) {
-            error!("Failed to open URL in browser: {}", e);
+/// Connects to the further projects from the config file's `projects`.
+async fn connect_projects(state: &AppState) -> Result<(), AppError> {
+    let mut dbs = HashMap::new();
+    for project in &state.config.projects {
+        let db = initialize_firestore(&state.config, &project.project_id).await?;
+        dbs.insert(project.project_id.clone(), Arc::new(db));
+    }
+    state.project_dbs.set(dbs).ok();
+    Ok(())
+}
+
+/// Reads one document of each collection, so a misspelt collection name or
+/// missing permission shows up at startup rather than as a listener that
+/// never sees anything. An empty collection is only warned about, as one
+/// that hasn't been shared to yet is fine.
+async fn probe_collections(state: &AppState) {
+    for collection in &state.collections {
+        let Some(db) = state.db_for(collection) else {
+            continue;
+        };
+        let probe = db.fluent().select().from(collection.name.as_str()).limit(1).query();
+        match tokio::time::timeout(state.config.connect_timeout, probe).await {
+            Ok(Ok(docs)) if !docs.is_empty() => info!("Collection {} has documents", collection.name),
+            Ok(Ok(_)) => warn!(
+                "Collection {} is empty or inaccessible; check the name if URLs shared to it never open",
+                collection.name
+            ),
+            Ok(Err(e)) => warn!("Failed to query collection {}: {}", collection.name, e),
+            Err(_) => warn!(
+                "Querying collection {} timed out after {:?}",
+                collection.name, state.config.connect_timeout
+            ),
         }
-    } else {
-        error!("Failed to decode URL: {}", url);
     }
 }
 
-async fn handle_document_change(db: &FirestoreDb, doc: &FirestoreDocument) {
-    if let Ok(shared_url) = FirestoreDb::deserialize_doc_to::<SharedUrl>(doc) {
-        info!("Received new URL: {}", shared_url.url);
-        handle_url(&shared_url.url);
+/// Rejects settings the opener can't start with.
+fn check_config(config: &Config) -> Result<(), AppError> {
+    validate_project_id(&config.project_id)?;
+    for (i, project) in config.projects.iter().enumerate() {
+        validate_project_id(&project.project_id)?;
+        if project.project_id == config.project_id
+            || config.projects[..i].iter().any(|other| other.project_id == project.project_id)
+        {
+            return Err(AppError::InvalidProjectId {
+                id: project.project_id.clone(),
+                reason: "is listed more than once",
+            });
+        }
+    }
+    let collections: Vec<&CollectionConfig> = config.all_collections().map(|(_, collection)| collection).collect();
+    for (i, collection) in collections.iter().enumerate() {
+        if let Some(other) = collections[..i]
+            .iter()
+            .find(|other| other.target_id == collection.target_id)
+        {
+            return Err(AppError::DuplicateTargetId {
+                target_id: collection.target_id,
+                first: other.name.clone(),
+                second: collection.name.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks a project id against GCP's rules: 6 to 30 lowercase letters, digits
+/// or hyphens, starting with a letter and not ending with a hyphen.
+fn validate_project_id(project_id: &str) -> Result<(), AppError> {
+    let invalid = |reason| AppError::InvalidProjectId {
+        id: project_id.to_string(),
+        reason,
+    };
+    if project_id.is_empty() {
+        return Err(AppError::MissingProjectId);
+    }
+    if !(6..=30).contains(&project_id.len()) {
+        return Err(invalid("must be 6 to 30 characters long"));
+    }
+    if !project_id
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(invalid("may only contain lowercase letters, digits and hyphens"));
+    }
+    if !project_id.starts_with(|c: char| c.is_ascii_lowercase()) {
+        return Err(invalid("must start with a letter"));
+    }
+    if project_id.ends_with('-') {
+        return Err(invalid("must not end with a hyphen"));
+    }
+    Ok(())
+}
+
+/// Checks the URL host against the allowlist; an empty allowlist allows
+/// everything. Parsing puts the host in its punycode form, which is how the
+/// allowlist entries are stored.
+fn is_allowed_domain(url: &str, allowed_domains: &[String]) -> bool {
+    if allowed_domains.is_empty() {
+        return true;
+    }
+    match Url::parse(url).ok().as_ref().and_then(Url::host_str) {
+        Some(host) => allowed_domains.iter().any(|domain| domain == host),
+        None => false,
+    }
+}
+
+/// With SAFE_MODE, only https URLs on a host in the allowlist are opened, and
+/// an empty allowlist opens nothing. Returns why `url` is refused.
+fn safe_mode_refusal(url: &str, allowed_domains: &[String]) -> Option<&'static str> {
+    let Ok(parsed) = Url::parse(url) else {
+        return Some("not a valid URL");
+    };
+    if parsed.scheme() != "https" {
+        return Some("not https");
+    }
+    match parsed.host_str() {
+        Some(host) if allowed_domains.iter().any(|domain| domain == host) => None,
+        _ => Some("host not in ALLOWED_DOMAINS"),
+    }
+}
+
+/// Only web URLs are handed to the browser; `file:`, `mailto:` and custom
+/// protocol handlers are never launched.
+fn is_allowed_scheme(url: &str) -> bool {
+    matches!(Url::parse(url), Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https")
+}
+
+/// Checks that a stored URL decodes to an absolute URL with a host, returning
+/// why it doesn't otherwise.
+fn validate_url(url: &str) -> Result<(), String> {
+    let decoded_url = percent_decode_str(url)
+        .decode_utf8()
+        .map_err(|_| "not valid UTF-8 once decoded".to_string())?;
+    if decoded_url.trim().is_empty() {
+        return Err("empty URL".to_string());
+    }
+    let parsed = Url::parse(&decoded_url).map_err(|e| e.to_string())?;
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err("missing host".to_string());
+    }
+    Ok(())
+}
+
+/// Whether an OPEN_WITH rule names the scheme of `url`, which lets URLs other
+/// than http and https through to that command.
+fn has_scheme_rule(url: &str, config: &Config) -> bool {
+    let Ok(parsed) = Url::parse(url) else {
+        return false;
+    };
+    config
+        .open_with
+        .iter()
+        .any(|(kind, _)| matches!(kind, LinkKind::Scheme(_)) && kind.matches(&parsed))
+}
+
+/// Whether `url` contains a `%XX` escape.
+fn has_percent_escapes(url: &str) -> bool {
+    url.as_bytes()
+        .windows(3)
+        .any(|w| w[0] == b'%' && w[1].is_ascii_hexdigit() && w[2].is_ascii_hexdigit())
+}
+
+/// Decodes the stored URL, unless PERCENT_DECODE is off, and applies the open
+/// filters, returning the URL to open.
+fn prepare_url(url: &str, config: &Config, allowed_domains: &[String]) -> Option<String> {
+    let decoded_url = if config.percent_decode {
+        let decoded_url = match percent_decode_str(url).decode_utf8() {
+            Ok(decoded_url) => decoded_url,
+            Err(_) => {
+                error!(url_host = url_host(url).as_str(); "Failed to decode URL: {}", display_url(url));
+                return None;
+            }
+        };
+        if decoded_url != url {
+            info!(url_host = url_host(&decoded_url).as_str(); "Percent-decoded URL to {}", display_url(&decoded_url));
+        } else {
+            debug!(url_host = url_host(url).as_str(); "URL has no percent-escapes to decode");
+        }
+        if has_percent_escapes(&decoded_url) {
+            warn!(
+                url_host = url_host(&decoded_url).as_str();
+                "URL still has percent-escapes after decoding and may be double-encoded: {}",
+                display_url(&decoded_url)
+            );
+        }
+        decoded_url
+    } else {
+        debug!(url_host = url_host(url).as_str(); "PERCENT_DECODE is off, using the URL as stored");
+        std::borrow::Cow::Borrowed(url)
+    };
+    let host = url_host(&decoded_url);
+    if config.safe_mode
+        && let Some(reason) = safe_mode_refusal(&decoded_url, allowed_domains)
+    {
+        warn!(url_host = host.as_str(); "SAFE_MODE refused URL, {}: {}", reason, display_url(&decoded_url));
+        return None;
+    }
+    if !is_allowed_scheme(&decoded_url) && !has_scheme_rule(&decoded_url, config) {
+        warn!(url_host = host.as_str(); "Skipping URL with unsupported scheme: {}", display_url(&decoded_url));
+        return None;
+    }
+    if !is_allowed_domain(&decoded_url, allowed_domains) {
+        warn!(url_host = host.as_str(); "Skipping URL outside ALLOWED_DOMAINS: {}", display_url(&decoded_url));
+        return None;
+    }
+    Some(decoded_url.into_owned())
+}
 
-        // Calculate expired_at timestamp
-        let expired_at = Utc::now() + Duration::days(3);
+/// With RESOLVE_REDIRECTS, replaces a short link with where it redirects to.
+/// The destination must pass the same filters as the link itself; if it
+/// can't be resolved, the original link is kept.
+async fn resolve_url(url: String, state: &AppState) -> Option<String> {
+    let Some(resolver) = &state.resolver else {
+        return Some(url);
+    };
+    // Only web links redirect
+    if !is_allowed_scheme(&url) {
+        return Some(url);
+    }
+    let host = url_host(&url);
+    match resolver.resolve(&url).await {
+        Ok(resolved) if resolved == url => Some(url),
+        Ok(resolved) => {
+            info!(
+                url_host = host.as_str();
+                "Resolved {} to {}", display_url(&url), display_url(&resolved)
+            );
+            let resolved_host = url_host(&resolved);
+            let allowed_domains = state.live.read().unwrap().allowed_domains.clone();
+            if state.config.safe_mode
+                && let Some(reason) = safe_mode_refusal(&resolved, &allowed_domains)
+            {
+                warn!(url_host = resolved_host.as_str(); "SAFE_MODE refused URL that redirects to one {}: {}", reason, display_url(&resolved));
+                return None;
+            }
+            if !is_allowed_scheme(&resolved) || !is_allowed_domain(&resolved, &allowed_domains) {
+                warn!(url_host = resolved_host.as_str(); "Skipping URL that redirects to a filtered URL: {}", display_url(&resolved));
+                return None;
+            }
+            Some(resolved)
+        }
+        Err(e) => {
+            warn!(url_host = host.as_str(); "Failed to resolve redirects, opening the original URL: {}", http::describe_error(e));
+            Some(url)
+        }
+    }
+}
 
-        // Create a struct for the update operation to properly handle timestamps
-        #[derive(Debug, Clone, Deserialize, Serialize)]
-        struct SharedUrlUpdate {
-            url: String,
-            #[serde(with = "firestore::serialize_as_timestamp")]
-            timestamp: DateTime<Utc>,
-            #[serde(with = "firestore::serialize_as_timestamp")]
-            expired_at: DateTime<Utc>,
+/// Opens a shared URL, labelled with its title or, when it has none, its host.
+/// Opens the document's links one after another, each waiting its turn under
+/// the rate limit and open delay, with TAB_DELAY_MS between the tabs of one
+/// document. Links longer than MAX_URL_LENGTH are skipped without logging
+/// them in full. Returns the opens started, still running in the background,
+/// each resolving to whether it succeeded.
+async fn handle_url(shared_url: &SharedUrl, state: &Arc<AppState>, collection: &CollectionHandler) -> Vec<JoinHandle<bool>> {
+    let max_length = state.config.max_url_length;
+    let links = shared_url.links();
+    let mut opens = Vec::new();
+    for url in &links {
+        if max_length > 0 && url.len() > max_length {
+            warn!(
+                doc_id = shared_url.doc_id.as_deref().unwrap_or_default(), url_host = url_host(url).as_str();
+                "Skipping URL of {} bytes, longer than MAX_URL_LENGTH {}",
+                url.len(),
+                max_length
+            );
+            continue;
         }
+        if !opens.is_empty() {
+            tokio::time::sleep(state.config.tab_delay).await;
+        }
+        opens.extend(handle_link(shared_url, url, state, collection).await);
+    }
+    opens
+}
 
-        let update_data = SharedUrlUpdate {
-            url: shared_url.url.clone(),
-            timestamp: shared_url.timestamp,
-            expired_at: expired_at,
+/// Opens one link of a document, returning the background open, which
+/// resolves to whether it succeeded, unless the link was skipped.
+async fn handle_link(
+    shared_url: &SharedUrl,
+    url: &str,
+    state: &Arc<AppState>,
+    collection: &CollectionHandler,
+) -> Option<JoinHandle<bool>> {
+    let config = &state.config;
+    let allowed_domains = state.live.read().unwrap().allowed_domains.clone();
+    if let Some(decoded_url) = prepare_url(url, config, &allowed_domains) {
+        let decoded_url = resolve_url(decoded_url, state).await?;
+        let normalized_url = normalize_url(&decoded_url, &config.tracking_params);
+        let decoded_url = if config.strip_tracking {
+            normalized_url.clone()
+        } else if !config.append_utm.is_empty() {
+            append_params(&decoded_url, &config.append_utm)
+        } else {
+            decoded_url
+        };
+        let host = url_host(&decoded_url);
+        let stored_title = shared_url
+            .title
+            .as_deref()
+            .map(str::trim)
+            .filter(|title| !title.is_empty());
+        if state.recent_urls.lock().unwrap().contains(&normalized_url) {
+            info!(url_host = host.as_str(); "Skipping recently opened URL: {}", display_url(&normalized_url));
+            return None;
+        }
+        if config.dry_run {
+            info!(url_host = host.as_str(); "Dry run, not opening decoded URL: {}", display_url(&decoded_url));
+            return None;
+        }
+        // While PAUSE_FILE exists the worker holds this and later URLs, which
+        // stay queued, before even asking to confirm them
+        if let Some(pause) = &state.pause {
+            pause.wait().await;
+        }
+        if let Some(confirmer) = &state.confirmer
+            && !confirmer.confirm(&decoded_url).await
+        {
+            info!(url_host = host.as_str(); "Opening not confirmed, skipping URL: {}", display_url(&decoded_url));
+            return None;
+        }
+        // Outside ACTIVE_HOURS the worker holds this and later URLs until the
+        // window opens again
+        if let Some(active_hours) = &config.active_hours {
+            active_hours.wait().await;
+        }
+        state.rate_limiter_for(&host, collection).acquire().await;
+        let title = match stored_title {
+            Some(title) => title.to_string(),
+            None => fetch_title(state, &decoded_url).await.unwrap_or_else(|| host.clone()),
         };
+        let (opener, browser) = state.opener_for(&host);
+        let link_opener = state.link_opener(&decoded_url);
+        match (link_opener.as_ref(), browser) {
+            (Some((kind, _)), _) => info!(
+                url_host = host.as_str();
+                "Opening '{}' with the OPEN_WITH command for {}: {}", title, kind, display_url(&decoded_url)
+            ),
+            (None, Some(browser)) => info!(
+                url_host = host.as_str();
+                "Opening '{}' with {:?} (DOMAIN_BROWSER_MAP rule for {}): {}", title, browser, host, display_url(&decoded_url)
+            ),
+            (None, None) => info!(url_host = host.as_str(); "Opening '{}': {}", title, display_url(&decoded_url)),
+        }
+        let opener = link_opener.map_or(opener, |(_, opener)| opener);
+        // Only recorded now, so a dry run, a declined prompt or a URL held by
+        // PAUSE_FILE doesn't count as opened for the dedup window
+        if !state.recent_urls.lock().unwrap().insert(&normalized_url, shared_url.doc_id.as_deref()) {
+            info!(url_host = host.as_str(); "Skipping recently opened URL: {}", display_url(&normalized_url));
+            return None;
+        }
+        // Launching the browser can take seconds, so it happens in the
+        // background while the listener moves on to the next event
+        let open = open_url(
+            state.clone(),
+            opener,
+            decoded_url,
+            title,
+            shared_url.doc_id.clone(),
+            collection.name.clone(),
+            state.db_for(collection).cloned(),
+        );
+        Some(state.open_tasks.spawn({
+            let state = state.clone();
+            async move {
+                let opened = open.await;
+                // Only a URL that actually opened counts for DEDUP_STATE_PATH,
+                // and one that failed for good can be shared again
+                let mut recent_urls = state.recent_urls.lock().unwrap();
+                if opened {
+                    recent_urls.confirm(&normalized_url);
+                } else {
+                    recent_urls.forget(&normalized_url);
+                }
+                opened
+            }
+        }))
+    } else {
+        None
+    }
+}
 
-        // Update the document with all necessary fields
-        if let Some(doc_id) = &shared_url.doc_id {
-            let update_result = db
-                .fluent()
-                .update()
-                .in_col("shared_urls")
-                .document_id(doc_id)
-                .object(&update_data)
-                .execute::<SharedUrl>()
-                .await;
+/// With FETCH_TITLE, the `<title>` of the page at `url`. Failures are logged
+/// and give `None`, so the URL opens labelled with its host instead.
+async fn fetch_title(state: &AppState, url: &str) -> Option<String> {
+    let fetcher = state.title_fetcher.as_ref()?;
+    if !is_allowed_scheme(url) {
+        return None;
+    }
+    let host = url_host(url);
+    match fetcher.fetch(url).await {
+        Ok(Some(title)) => {
+            debug!(url_host = host.as_str(); "Fetched title '{}' for {}", title, display_url(url));
+            Some(title)
+        }
+        Ok(None) => {
+            debug!(url_host = host.as_str(); "Page has no title: {}", display_url(url));
+            None
+        }
+        Err(e) => {
+            warn!(url_host = host.as_str(); "Failed to fetch title, labelling the URL with its host: {}", http::describe_error(e));
+            None
+        }
+    }
+}
 
-            match update_result {
-                Ok(_) => info!("Document updated with expired_at"),
-                Err(e) => error!("Failed to update document with expired_at: {}", e),
+/// Opens a URL that passed the filters off the async runtime, retrying
+/// failures up to `MAX_OPEN_RETRIES` times.
+async fn open_url(
+    state: Arc<AppState>,
+    opener: Arc<dyn UrlOpener>,
+    url: String,
+    title: String,
+    doc_id: Option<String>,
+    collection_name: String,
+    db: Option<Arc<FirestoreDb>>,
+) -> bool {
+    let config = &state.config;
+    let host = url_host(&url);
+    let mut attempt = 0;
+    let open_result = loop {
+        // While the circuit breaker is open the URL waits here, retries and
+        // all, instead of failing
+        state.breaker.ready().await;
+        // The semaphore is never closed, so acquiring only ever waits
+        let permit = state.open_permits.acquire().await;
+        let result = state
+            .pacer
+            .run(|| {
+                let opener = opener.clone();
+                let url = url.clone();
+                tokio::task::spawn_blocking(move || opener.open(&url))
+            })
+            .await
+            .unwrap_or_else(|e| Err(AppError::BrowserOpen(std::io::Error::other(e))));
+        drop(permit);
+        state.breaker.record(result.is_ok());
+        match result {
+            Err(e) if attempt < config.max_open_retries => {
+                attempt += 1;
+                warn!(
+                    url_host = host.as_str();
+                    "{}; retry {}/{} in {:?}",
+                    e, attempt, config.max_open_retries, config.open_retry_delay
+                );
+                tokio::time::sleep(config.open_retry_delay).await;
+            }
+            result => break result,
+        }
+    };
+    match open_result {
+        Ok(()) => {
+            state.metrics.record_open();
+            // Posted in the background, so a slow webhook or notification
+            // backend doesn't hold up this open's audit record
+            if let Some(slack) = state.slack.clone() {
+                let (url, title, host) = (url.clone(), title.clone(), host.clone());
+                let instance_id = config.instance_id.clone();
+                state.open_tasks.spawn(async move {
+                    slack.post(&OpenedMessage::new(&url, &title, &host, &instance_id)).await;
+                });
+            }
+            if config.notify {
+                let (url, host) = (url.clone(), host.clone());
+                state.open_tasks.spawn(async move {
+                    notify::notify_opened(&host, &url).await;
+                });
             }
+            // Every instance counts its own opens, so the tally adds up across
+            // machines; a deleted document has nothing left to count on
+            if let (Some(doc_id), Some(db)) = (&doc_id, &db)
+                && !config.read_only
+                && !config.delete_after_open
+            {
+                match open_count::increment(db, &collection_name, doc_id).await {
+                    Ok(Some(count)) => debug!(doc_id = doc_id.as_str(); "Document opened {} times", count),
+                    Ok(None) => debug!(doc_id = doc_id.as_str(); "Document deleted before its open was counted"),
+                    Err(e) => warn!(doc_id = doc_id.as_str(); "Failed to count the open in open_count: {}", e),
+                }
+            }
+            // The audit collection lives in PROJECT_ID, whichever project
+            // the URL came from
+            if let (Some(audit_collection), Some(db)) = (&config.audit_collection, state.db.get()) {
+                let entry = AuditEntry {
+                    doc_id,
+                    collection: collection_name,
+                    url_host: host,
+                    title,
+                    opened_at: Utc::now(),
+                    instance_id: config.instance_id.clone(),
+                };
+                audit::record(db, audit_collection, &entry).await;
+            }
+            true
+        }
+        Err(e) => {
+            state.metrics.record_open_failure();
+            error!(url_host = host.as_str(); "{}", e);
+            false
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    env_logger::init();
-    dotenv::dotenv().ok();
+async fn handle_document_change(state: &Arc<AppState>, collection: &CollectionHandler, doc: &FirestoreDocument) {
+    let config = &state.config;
+    let shared_url = match read_shared_url(doc, &config.url_field) {
+        Ok(shared_url) => shared_url,
+        Err(e) => {
+            let doc_id = document_id(doc);
+            let failures = state.metrics.record_deserialization_failure();
+            let reason = invalid_document_reason(&with_url_field(doc, &config.url_field));
+            error!(
+                doc_id = doc_id, deserialization_failures = failures;
+                "Skipping invalid document {}: {} ({})", doc_id, reason, e
+            );
+            return;
+        }
+    };
+    let doc_id = shared_url.doc_id.as_deref().unwrap_or_default();
+    let mut links = shared_url.links();
+    if links.is_empty() {
+        error!(doc_id = doc_id; "Skipping invalid document {}: missing url field", doc_id);
+        return;
+    }
+    // Left untouched, for the instance it's meant for
+    if !shared_url.is_for_this_device(config) {
+        debug!(
+            doc_id = doc_id;
+            "Skipping document for target_device {:?}", shared_url.target_device.as_deref().unwrap_or_default()
+        );
+        return;
+    }
+    for url in &links {
+        info!(
+            doc_id = doc_id, url_host = url_host(url).as_str();
+            "Received new URL: {} (shared at {})", display_url(url), display_time(shared_url.timestamp)
+        );
+    }
 
-    // Get project ID from environment
-    let project_id = env::var("PROJECT_ID")
-        .expect("PROJECT_ID environment variable must be set");
+    // Documents with a malformed URL are neither opened nor marked as handled
+    for url in &links {
+        if let Err(reason) = validate_url(url) {
+            warn!(doc_id = doc_id; "Rejecting malformed URL ({}): {}", reason, display_url(url));
+            return;
+        }
+    }
 
-    // Initialize Firestore and listener
-    let db = initialize_firestore(&project_id).await?;
-    let mut listener = initialize_listener(&db).await?;
+    // Only URLs matching URL_FILTER_REGEX, as stored, are opened
+    if let Some(url_filter) = &config.url_filter {
+        let (matching, skipped): (Vec<&str>, Vec<&str>) = links.iter().partition(|url| url_filter.is_match(url));
+        for url in skipped {
+            debug!(doc_id = doc_id; "Skipping URL not matching URL_FILTER_REGEX: {}", display_url(url));
+        }
+        if matching.is_empty() {
+            return;
+        }
+        links = matching;
+    }
 
-    let collection_name = "shared_urls";
+    // Skip links that already expired, e.g. when replaying old documents on startup
+    if let Some(expired_at) = shared_url.expired_at
+        && expired_at <= Utc::now()
+    {
+        info!(doc_id = doc_id; "Skipping document that expired at {}", display_time(expired_at));
+        return;
+    }
+    if state.unmarked.lock().unwrap().contains(doc_id) {
+        info!(doc_id = doc_id; "Skipping document opened before but never marked as handled");
+        return;
+    }
 
-    // Start listening for changes using fluent API
-    db.fluent()
-        .select()
-        .from(collection_name)
-        .listen()
-        .add_target(TARGET_ID, &mut listener)?;
+    // With USE_STATUS_FIELD, only pending documents are opened; re-emitted
+    // events for opened ones are ignored
+    if config.use_status_field && shared_url.status.as_deref() != Some(STATUS_PENDING) {
+        debug!(
+            doc_id = doc_id;
+            "Skipping document with status {:?}", shared_url.status.as_deref().unwrap_or_default()
+        );
+        return;
+    }
 
-    info!("Starting to listen for changes in collection: {}", collection_name);
+    // A document snoozed until a future open_at is queued when that comes
+    if let Some(open_at) = shared_url.open_at
+        && open_at > Utc::now()
+    {
+        let links = links.iter().map(|url| url.to_string()).collect();
+        snooze(state, collection, doc.clone(), shared_url.clone(), links, open_at);
+        return;
+    }
+    claim_and_queue(state, collection, doc, &shared_url, &links).await;
+}
 
-    // Start the listener with a callback
-    listener
-        .start(move |event| {
-            let db = db.clone();  // Clone db to move it into the closure
-            async move {
-                match event {
-                    FirestoreListenEvent::DocumentChange(doc_change) => {
-                        if let Some(doc) = &doc_change.document {
-                            // Check if 'expired_at' field is already present
-                            if !doc.fields.contains_key("expired_at") {
-                                handle_document_change(&db, doc).await;
-                            }
-                        }
-                    }
-                    _ => {
-                        info!("Received other event: {:?}", event);
-                    }
-                }
-                Ok(())
+/// Queues `links` of the document once its `open_at` is reached. A newer
+/// version of the document replaces the timer, and deleting it cancels it.
+/// Timers live only in memory; on restart the stored `open_at` reschedules
+/// them, see `reschedule_snoozed`.
+fn snooze(
+    state: &Arc<AppState>,
+    collection: &CollectionHandler,
+    doc: FirestoreDocument,
+    shared_url: SharedUrl,
+    links: Vec<String>,
+    open_at: DateTime<Utc>,
+) {
+    let doc_id = shared_url.doc_id.clone().unwrap_or_default();
+    let wait = (open_at - Utc::now()).to_std().unwrap_or_default();
+    info!(doc_id = doc_id.as_str(); "Snoozing document until {}", display_time(open_at));
+    let target_id = *collection.target.value();
+    // Held until the timer is registered, so a timer that fires right away
+    // can't remove itself first
+    let mut snoozed = state.snoozed.lock().unwrap();
+    let timer = tokio::spawn({
+        let state = state.clone();
+        let doc_id = doc_id.clone();
+        async move {
+            tokio::time::sleep_until(tokio::time::Instant::now() + wait).await;
+            state.snoozed.lock().unwrap().remove(&doc_id);
+            let Some(collection) = state.collection_for(&[target_id as i32]) else {
+                return;
+            };
+            info!(doc_id = doc_id.as_str(); "Snooze over, queueing document");
+            let links: Vec<&str> = links.iter().map(String::as_str).collect();
+            claim_and_queue(&state, collection, &doc, &shared_url, &links).await;
+        }
+    });
+    if let Some(previous) = snoozed.insert(doc_id, timer.abort_handle()) {
+        previous.abort();
+    }
+}
+
+/// Claims the document if CLAIM_BEFORE_OPEN asks for it, then queues its links.
+async fn claim_and_queue(
+    state: &Arc<AppState>,
+    collection: &CollectionHandler,
+    doc: &FirestoreDocument,
+    shared_url: &SharedUrl,
+    links: &[&str],
+) {
+    let config = &state.config;
+    let doc_id = shared_url.doc_id.as_deref().unwrap_or_default();
+    // Make sure only one instance opens the URL when several share the collection
+    if config.claim_before_open
+        && !config.read_only
+        && let Some(db) = state.db_for(collection)
+    {
+        match claim::claim_document(db, &collection.name, doc_id, doc, &config.instance_id).await {
+            Ok(true) => info!(doc_id = doc_id; "Claimed document as {}", config.instance_id),
+            Ok(false) => {
+                debug!(doc_id = doc_id; "Document already claimed by another instance, skipping");
+                return;
             }
-        })
-        .await?;
+            Err(e) => {
+                error!(doc_id = doc_id; "Failed to claim document: {}", e);
+                return;
+            }
+        }
+    }
 
-    // Wait for Ctrl+C
-    signal::ctrl_c().await?;
-    info!("Received interrupt signal, shutting down...");
-    listener.shutdown().await?;
+    let queued = QueuedUrl {
+        target_id: *collection.target.value(),
+        shared_url: SharedUrl {
+            url: String::new(),
+            urls: links.iter().map(|url| url.to_string()).collect(),
+            ..shared_url.clone()
+        },
+    };
+    let saved = SavedUrl {
+        target_id: queued.target_id,
+        doc_id: doc_id.to_string(),
+        links: queued.shared_url.urls.clone(),
+    };
+    if !state.queue.push(saved.target_id, shared_url.doc_id.as_deref(), queued).await {
+        debug!(doc_id = doc_id; "Document is already queued, skipping");
+    } else if let Some(queue_state) = &state.queue_state
+        && shared_url.doc_id.is_some()
+    {
+        queue_state.add(saved);
+    }
+}
 
-    Ok(())
-} 
+/// Queues again what QUEUE_STATE_PATH says was waiting when the last run
+/// stopped. Each document is read afresh, and ones deleted, opened or
+/// expired since are dropped; the dedup window still applies when opening.
+async fn restore_queue(state: &Arc<AppState>) {
+    let Some(queue_state) = &state.queue_state else {
+        return;
+    };
+    let saved = queue_state.saved();
+    if !saved.is_empty() {
+        info!("Restoring {} documents queued before the last shutdown", saved.len());
+    }
+    for saved_url in saved {
+        let doc_id = saved_url.doc_id.as_str();
+        let Some(collection) = state.collection_for(&[saved_url.target_id as i32]) else {
+            warn!(doc_id = doc_id; "Dropping queued document of target {}, which is no longer listened to", saved_url.target_id);
+            queue_state.remove(saved_url.target_id, doc_id);
+            continue;
+        };
+        let Some(db) = state.db_for(collection) else {
+            continue;
+        };
+        let doc = match db.get_doc(&collection.name, doc_id, None).await {
+            Ok(doc) => doc,
+            Err(firestore::errors::FirestoreError::DataNotFoundError(_)) => {
+                info!(doc_id = doc_id; "Queued document was deleted, dropping it");
+                queue_state.remove(saved_url.target_id, doc_id);
+                continue;
+            }
+            Err(e) => {
+                warn!(doc_id = doc_id; "Failed to read queued document, keeping it for the next start: {}", e);
+                continue;
+            }
+        };
+        let shared_url = match read_shared_url(&doc, &state.config.url_field) {
+            Ok(shared_url) => shared_url,
+            Err(e) => {
+                warn!(doc_id = doc_id; "Dropping queued document that no longer deserializes: {}", e);
+                queue_state.remove(saved_url.target_id, doc_id);
+                continue;
+            }
+        };
+        let handled = doc.fields.contains_key("expired_at")
+            || (state.config.use_status_field && shared_url.status.as_deref() != Some(STATUS_PENDING));
+        if handled {
+            info!(doc_id = doc_id; "Queued document was handled or expired meanwhile, dropping it");
+            queue_state.remove(saved_url.target_id, doc_id);
+            continue;
+        }
+        // Claimed, if at all, before the restart
+        let queued = QueuedUrl {
+            target_id: saved_url.target_id,
+            shared_url: SharedUrl {
+                url: String::new(),
+                urls: saved_url.links,
+                ..shared_url
+            },
+        };
+        state.queue.push(saved_url.target_id, Some(doc_id), queued).await;
+    }
+}
+
+/// Opens queued URLs, taking from each collection in turn and from within one
+/// by priority and arrival order, then updates their documents. Collections
+/// at their rate limit are passed over until it frees up, so they don't hold
+/// up the others. Once
+/// `draining` is cancelled, returns as soon as the queue is empty.
+async fn run_worker(
+    state: Arc<AppState>,
+    mut receiver: QueueReceiver<QueuedUrl>,
+    draining: CancellationToken,
+) {
+    // A URL at its DOMAIN_RATE_LIMITS or collection limit is passed over, so
+    // it doesn't hold up URLs on other hosts. Hosts are those of the links as
+    // shared, before any unwrapping or redirects.
+    let url_wait = |target_id: u32, queued: &QueuedUrl| {
+        let Some(collection) = state.collection_for(&[target_id as i32]) else {
+            return std::time::Duration::ZERO;
+        };
+        let now = std::time::Instant::now();
+        queued
+            .shared_url
+            .links()
+            .into_iter()
+            .map(|link| state.rate_limiter_for(&url_host(link), collection).wait_at(now))
+            .max()
+            .unwrap_or_default()
+    };
+    loop {
+        let queued = tokio::select! {
+            biased;
+            queued = receiver.recv(url_wait) => queued,
+            _ = draining.cancelled() => receiver.try_recv(),
+        };
+        let Some(queued) = queued else {
+            break;
+        };
+        let shared_url = &queued.shared_url;
+        let doc_id = shared_url.doc_id.as_deref().unwrap_or_default();
+        if !state.queue.finish(shared_url.doc_id.as_deref()) {
+            info!(doc_id = doc_id; "Document was deleted while queued, skipping its URLs");
+            if let Some(queue_state) = &state.queue_state {
+                queue_state.remove(queued.target_id, doc_id);
+            }
+            continue;
+        }
+        let Some(collection) = state.collection_for(&[queued.target_id as i32]) else {
+            continue;
+        };
+        process_url(&state, collection, shared_url).await;
+        // Only forgotten once handled, so a URL waiting on the rate limit or
+        // ACTIVE_HOURS is still saved
+        if let Some(queue_state) = &state.queue_state {
+            queue_state.remove(queued.target_id, doc_id);
+        }
+    }
+}
+
+/// Opens a dequeued URL and, once its opens have finished, marks its document
+/// as handled. A document with a link that failed to open is left as it is,
+/// unless USE_STATUS_FIELD can mark it as failed.
+async fn process_url(state: &Arc<AppState>, collection: &CollectionHandler, shared_url: &SharedUrl) {
+    let config = &state.config;
+    let doc_id = shared_url.doc_id.as_deref().unwrap_or_default();
+    let opens = handle_url(shared_url, state, collection).await;
+    let started = opens.len();
+    let mut opened = 0;
+    for open in opens {
+        if open.await.unwrap_or(false) {
+            opened += 1;
+        }
+    }
+    if shared_url.links().len() > 1 && started > 0 {
+        info!(doc_id = doc_id; "Opened {} tabs for doc {}", opened, doc_id);
+    }
+    let failed = opened < started;
+    if failed && !config.use_status_field {
+        warn!(doc_id = doc_id; "Failed to open {} of {} URLs, leaving document {} unmarked", started - opened, started, doc_id);
+        return;
+    }
+    if failed {
+        warn!(doc_id = doc_id; "Failed to open {} of {} URLs of document {}", started - opened, started, doc_id);
+    } else if let Some(last_processed) = &state.last_processed {
+        last_processed.record(shared_url.timestamp);
+    }
+
+    if config.read_only {
+        info!("Read-only mode, not updating document");
+        return;
+    }
+    let Some(updater) = state.updater_for(collection) else {
+        return;
+    };
+
+    if let Some(doc_id) = &shared_url.doc_id {
+        let marked = if failed {
+            set_status(updater.as_ref(), &collection.name, doc_id, STATUS_FAILED).await
+        } else if config.delete_after_open {
+            delete_document(updater.as_ref(), &collection.name, doc_id).await
+        } else if config.use_status_field {
+            set_status(updater.as_ref(), &collection.name, doc_id, STATUS_OPENED).await
+        } else {
+            let expiration = state.live.read().unwrap().expiration;
+            expire_document(updater.as_ref(), expiration, &collection.name, doc_id, shared_url).await
+        };
+        // Left as it was, the document would be opened again on its next
+        // change, until a restart forgets it
+        if !marked {
+            error!(
+                doc_id = doc_id.as_str();
+                "Gave up marking document {} as handled; ignoring its changes from now on so it isn't opened again", doc_id
+            );
+            state.unmarked.lock().unwrap().insert(doc_id.clone());
+        }
+    }
+}
+
+/// Id of a document, the last segment of its resource name.
+fn document_id(doc: &FirestoreDocument) -> &str {
+    doc.name.rsplit('/').next().unwrap_or_default()
+}
+
+/// Reads a document as a `SharedUrl`, taking its URL from URL_FIELD.
+fn read_shared_url(doc: &FirestoreDocument, url_field: &str) -> FirestoreResult<SharedUrl> {
+    FirestoreDb::deserialize_doc_to(&with_url_field(doc, url_field))
+}
+
+/// The document with the URL_FIELD field, if it isn't `url`, moved to `url`,
+/// where `SharedUrl` looks for it.
+fn with_url_field<'a>(doc: &'a FirestoreDocument, url_field: &str) -> Cow<'a, FirestoreDocument> {
+    if url_field == "url" {
+        return Cow::Borrowed(doc);
+    }
+    let mut doc = doc.clone();
+    doc.fields.remove("url");
+    if let Some(url) = doc.fields.remove(url_field) {
+        doc.fields.insert("url".to_string(), url);
+    }
+    Cow::Owned(doc)
+}
+
+/// Explains which field kept a document from deserializing as a `SharedUrl`.
+fn invalid_document_reason(doc: &FirestoreDocument) -> &'static str {
+    #[derive(Deserialize)]
+    struct UrlField {
+        #[serde(rename = "url", default)]
+        _url: String,
+    }
+
+    #[derive(Deserialize)]
+    struct UrlsField {
+        #[serde(rename = "urls", default)]
+        _urls: Vec<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct TimestampField {
+        #[serde(rename = "timestamp", with = "firestore::serialize_as_timestamp")]
+        _timestamp: DateTime<Utc>,
+    }
+
+    if !doc.fields.contains_key("url") && !doc.fields.contains_key("urls") {
+        "missing url field"
+    } else if FirestoreDb::deserialize_doc_to::<UrlField>(doc).is_err() {
+        "url is not a string"
+    } else if FirestoreDb::deserialize_doc_to::<UrlsField>(doc).is_err() {
+        "urls is not a list of strings"
+    } else if !doc.fields.contains_key("timestamp") {
+        "missing timestamp field"
+    } else if FirestoreDb::deserialize_doc_to::<TimestampField>(doc).is_err() {
+        "malformed timestamp"
+    } else {
+        "malformed field"
+    }
+}
+
+async fn expire_document(
+    updater: &dyn DocUpdater,
+    expiration: chrono::Duration,
+    collection_name: &str,
+    doc_id: &str,
+    shared_url: &SharedUrl,
+) -> bool {
+    let update = ExpiryUpdate {
+        timestamp: shared_url.timestamp,
+        expired_at: Utc::now() + expiration,
+    };
+    match updater.expire(collection_name, doc_id, &update).await {
+        Ok(()) => info!(
+            doc_id = doc_id;
+            "Document updated with expired_at {}", display_time(update.expired_at)
+        ),
+        Err(e) => {
+            error!(doc_id = doc_id; "Failed to update document with expired_at: {}", e);
+            return false;
+        }
+    }
+    true
+}
+
+/// Moves a document from `pending` to `status`, `opened` recording when it
+/// was opened and `failed` clearing that.
+async fn set_status(updater: &dyn DocUpdater, collection_name: &str, doc_id: &str, status: &str) -> bool {
+    let update = StatusUpdate {
+        status: status.to_string(),
+        opened_at: (status == STATUS_OPENED).then(Utc::now),
+    };
+    match updater.set_status(collection_name, doc_id, &update).await {
+        Ok(()) => match update.opened_at {
+            Some(opened_at) => info!(doc_id = doc_id; "Document marked as {} at {}", status, display_time(opened_at)),
+            None => info!(doc_id = doc_id; "Document marked as {}", status),
+        },
+        Err(e) => {
+            error!(doc_id = doc_id; "Failed to mark document as {}: {}", status, e);
+            return false;
+        }
+    }
+    true
+}
+
+/// Restricts queries to pending documents when USE_STATUS_FIELD is on.
+fn pending_filter(config: &Config, q: select_filter_builder::FirestoreQueryFilterBuilder) -> Option<FirestoreQueryFilter> {
+    if config.use_status_field {
+        q.field("status").eq(STATUS_PENDING)
+    } else {
+        None
+    }
+}
+
+async fn delete_document(updater: &dyn DocUpdater, collection_name: &str, doc_id: &str) -> bool {
+    match updater.delete(collection_name, doc_id).await {
+        Ok(()) => info!(doc_id = doc_id; "Document {} deleted after opening", doc_id),
+        Err(e) => {
+            error!(doc_id = doc_id; "Failed to delete document {}: {}", doc_id, e);
+            return false;
+        }
+    }
+    true
+}
+
+/// Opens the documents already in each collection, oldest first, before the
+/// listener takes over. Documents stamped with `expired_at` are skipped.
+async fn replay_backlog(state: &Arc<AppState>) -> Result<(), AppError> {
+    // With RESUME_FROM_LAST, documents no newer than the last processed one
+    // were handled before the restart
+    let since = state.last_processed.as_ref().and_then(LastProcessed::get);
+    if let Some(since) = since {
+        info!("Replaying only documents newer than {}", display_time(since));
+    }
+    for collection in &state.collections {
+        let Some(db) = state.db_for(collection) else {
+            continue;
+        };
+        let docs = db
+            .fluent()
+            .select()
+            .from(collection.name.as_str())
+            .filter(|q| {
+                q.for_all([
+                    pending_filter(&state.config, q.clone()),
+                    since.and_then(|since| q.field("timestamp").greater_than(FirestoreTimestamp(since))),
+                ])
+            })
+            .order_by([("timestamp", FirestoreQueryDirection::Ascending)])
+            .query()
+            .await
+            .map_err(|source| AppError::Query {
+                collection: collection.name.clone(),
+                source,
+            })?;
+
+        let mut pending: Vec<_> = docs
+            .iter()
+            .filter(|doc| !doc.fields.contains_key("expired_at"))
+            .collect();
+        // Sorted here rather than in the query, which would leave out
+        // documents without a priority field
+        pending.sort_by_cached_key(|doc| {
+            let priority = read_shared_url(doc, &state.config.url_field)
+                .map_or(0, |shared_url| shared_url.priority);
+            std::cmp::Reverse(priority)
+        });
+        info!(
+            "Replaying {} of {} documents in collection: {}",
+            pending.len(),
+            docs.len(),
+            collection.name
+        );
+        for doc in pending {
+            handle_document_change(state, collection, doc).await;
+        }
+    }
+    Ok(())
+}
+
+/// Opens the pending documents, updating them as the listener would, and
+/// returns once every open has finished, for runs from a scheduler.
+async fn run_once(db: FirestoreDb, config: Config) -> Result<(), AppError> {
+    let state = Arc::new(AppState::new(config));
+    state.db.set(Arc::new(db)).ok();
+    connect_projects(&state).await?;
+    // Cancelled once the backlog is queued, so the worker exits when it is empty
+    let draining = CancellationToken::new();
+    let worker = state
+        .queue
+        .take_receiver()
+        .map(|receiver| tokio::spawn(run_worker(state.clone(), receiver, draining.clone())));
+    restore_queue(&state).await;
+    replay_backlog(&state).await?;
+    draining.cancel();
+    if let Some(worker) = worker {
+        worker.await.ok();
+    }
+    state.open_tasks.close();
+    state.open_tasks.wait().await;
+
+    match state.metrics.open_failures() {
+        0 => Ok(()),
+        failed => Err(AppError::OpensFailed(failed)),
+    }
+}
+
+/// Snoozes the pending documents whose `open_at` is still ahead, which the
+/// listener won't deliver again when it resumes after a restart.
+async fn reschedule_snoozed(state: &Arc<AppState>) -> Result<(), AppError> {
+    let now = Utc::now();
+    for collection in &state.collections {
+        let Some(db) = state.db_for(collection) else {
+            continue;
+        };
+        let docs = db
+            .fluent()
+            .select()
+            .from(collection.name.as_str())
+            .filter(|q| {
+                q.for_all([
+                    pending_filter(&state.config, q.clone()),
+                    q.field("open_at").greater_than(FirestoreTimestamp(now)),
+                ])
+            })
+            .query()
+            .await
+            .map_err(|source| AppError::Query {
+                collection: collection.name.clone(),
+                source,
+            })?;
+        for doc in docs.iter().filter(|doc| !doc.fields.contains_key("expired_at")) {
+            handle_document_change(state, collection, doc).await;
+        }
+    }
+    Ok(())
+}
+
+/// Drops the in-memory state kept for a document deleted upstream, so its URL
+/// can be shared again right away.
+fn handle_document_delete(state: &AppState, document_name: &str) {
+    let doc_id = document_name.rsplit('/').next().unwrap_or_default();
+    if let Some(timer) = state.debounced.lock().unwrap().remove(doc_id) {
+        timer.abort();
+        debug!(doc_id = doc_id, event_type = "document_delete"; "Document {} deleted before its changes settled", doc_id);
+    }
+    if let Some(timer) = state.snoozed.lock().unwrap().remove(doc_id) {
+        timer.abort();
+        info!(doc_id = doc_id, event_type = "document_delete"; "Document {} deleted upstream, cancelled its snooze", doc_id);
+        return;
+    }
+    if state.queue.cancel(doc_id) {
+        info!(doc_id = doc_id, event_type = "document_delete"; "Document {} deleted upstream, dropped its queued URL", doc_id);
+        return;
+    }
+    // With DELETE_AFTER_OPEN the delete is usually our own, and forgetting the
+    // URL would defeat the dedup window
+    if state.config.delete_after_open {
+        debug!(doc_id = doc_id, event_type = "document_delete"; "Document {} deleted", doc_id);
+        return;
+    }
+    let forgotten = state.recent_urls.lock().unwrap().forget_doc(doc_id);
+    info!(
+        doc_id = doc_id, event_type = "document_delete";
+        "Document {} deleted upstream{}", doc_id, if forgotten { ", forgot its recently opened URL" } else { "" }
+    );
+}
+
+/// Short name of a listen event's kind, used as a structured log field.
+fn event_type(event: &FirestoreListenEvent) -> &'static str {
+    match event {
+        FirestoreListenEvent::TargetChange(_) => "target_change",
+        FirestoreListenEvent::DocumentChange(_) => "document_change",
+        FirestoreListenEvent::DocumentDelete(_) => "document_delete",
+        FirestoreListenEvent::DocumentRemove(_) => "document_remove",
+        FirestoreListenEvent::Filter(_) => "filter",
+    }
+}
+
+/// Handles one listener event, live or replayed from RECORD_EVENTS_PATH.
+async fn handle_event(state: &Arc<AppState>, event: FirestoreListenEvent) {
+    state.metrics.record_event();
+    state.health.record_event();
+    match event {
+        FirestoreListenEvent::DocumentChange(_) if state.shutting_down.load(Ordering::Relaxed) => {
+            debug!("Shutting down, ignoring document change");
+        }
+        FirestoreListenEvent::DocumentChange(doc_change) => {
+            let Some(collection) = state.collection_for(&doc_change.target_ids) else {
+                warn!("Received change for unknown targets: {:?}", doc_change.target_ids);
+                return;
+            };
+            if let Some(doc) = doc_change.document {
+                if state.config.debounce.is_zero() {
+                    handle_changed_document(state, collection, &doc).await;
+                } else {
+                    debounce(state, collection, doc);
+                }
+            }
+        }
+        FirestoreListenEvent::DocumentDelete(doc_delete) => {
+            handle_document_delete(state, &doc_delete.document);
+        }
+        _ => {
+            info!(event_type = event_type(&event); "Received other event: {:?}", event);
+        }
+    }
+}
+
+async fn handle_changed_document(state: &Arc<AppState>, collection: &CollectionHandler, doc: &FirestoreDocument) {
+    if doc.fields.contains_key("expired_at") {
+        handle_expired_document(state, collection, doc).await;
+    } else {
+        handle_document_change(state, collection, doc).await;
+    }
+}
+
+/// Handles a changed document once DEBOUNCE_MS passes without another change
+/// to it, so a burst of edits is handled once, in its final version.
+fn debounce(state: &Arc<AppState>, collection: &CollectionHandler, doc: FirestoreDocument) {
+    let doc_id = document_id(&doc).to_string();
+    let target_id = *collection.target.value();
+    // Held until the timer is registered, as for snoozes
+    let mut debounced = state.debounced.lock().unwrap();
+    let timer = tokio::spawn({
+        let state = state.clone();
+        let doc_id = doc_id.clone();
+        async move {
+            tokio::time::sleep(state.config.debounce).await;
+            state.debounced.lock().unwrap().remove(&doc_id);
+            if state.shutting_down.load(Ordering::Relaxed) {
+                return;
+            }
+            if let Some(collection) = state.collection_for(&[target_id as i32]) {
+                handle_changed_document(&state, collection, &doc).await;
+            }
+        }
+    });
+    if let Some(previous) = debounced.insert(doc_id.clone(), timer.abort_handle()) {
+        previous.abort();
+        debug!(doc_id = doc_id.as_str(); "Document changed again within DEBOUNCE_MS, restarting its timer");
+    }
+}
+
+/// Applies EXPIRED_DOC_ACTION to a changed document that already has an
+/// `expired_at` field. Marking an opened document expired changes it too, so
+/// `reopen` waits for the field to pass rather than opening it in a loop.
+async fn handle_expired_document(state: &Arc<AppState>, collection: &CollectionHandler, doc: &FirestoreDocument) {
+    let doc_id = document_id(doc);
+    match state.config.expired_doc_action {
+        ExpiredDocAction::Skip => info!(doc_id = doc_id; "Skipping document with expired_at (EXPIRED_DOC_ACTION skip)"),
+        ExpiredDocAction::Delete if state.config.read_only => {
+            info!(doc_id = doc_id; "Read-only mode, not deleting document with expired_at (EXPIRED_DOC_ACTION delete)");
+        }
+        ExpiredDocAction::Delete => {
+            if let Some(updater) = state.updater_for(collection) {
+                info!(doc_id = doc_id; "Deleting document with expired_at (EXPIRED_DOC_ACTION delete)");
+                delete_document(updater.as_ref(), &collection.name, doc_id).await;
+            }
+        }
+        ExpiredDocAction::Reopen => {
+            let expired_at = FirestoreDb::deserialize_doc_to::<SharedUrl>(doc)
+                .ok()
+                .and_then(|shared_url| shared_url.expired_at);
+            match expired_at {
+                Some(expired_at) if expired_at > Utc::now() => info!(
+                    doc_id = doc_id;
+                    "Not reopening document before it expires at {} (EXPIRED_DOC_ACTION reopen)",
+                    display_time(expired_at)
+                ),
+                _ => {
+                    info!(doc_id = doc_id; "Reopening document with expired_at (EXPIRED_DOC_ACTION reopen)");
+                    let mut doc = doc.clone();
+                    doc.fields.remove("expired_at");
+                    handle_document_change(state, collection, &doc).await;
+                }
+            }
+        }
+    }
+}
+
+/// Starts a listener for the collections of `project`, `None` standing for
+/// PROJECT_ID.
+async fn start_listener(
+    db: &Arc<FirestoreDb>,
+    state: &Arc<AppState>,
+    project: Option<&str>,
+) -> Result<UrlListener, AppError> {
+    let mut listener = initialize_listener(db, &state.listen_state).await?;
+
+    // Start listening for changes using fluent API, one target per collection
+    let collections = state
+        .collections
+        .iter()
+        .filter(|collection| collection.project_id.as_deref() == project);
+    for collection in collections {
+        db.fluent()
+            .select()
+            .from(collection.name.as_str())
+            .filter(|q| pending_filter(&state.config, q))
+            .listen()
+            .add_target(collection.target.clone(), &mut listener)
+            .map_err(AppError::ListenerInit)?;
+
+        match project {
+            Some(project) => info!(
+                "Starting to listen for changes in collection: {} (project {})",
+                collection.name, project
+            ),
+            None => info!("Starting to listen for changes in collection: {}", collection.name),
+        }
+    }
+
+    // Start the listener with a callback; each event only clones the Arc
+    let state = state.clone();
+    listener
+        .start(move |event| {
+            let state = state.clone();
+            async move {
+                if let Some(recorder) = &state.recorder {
+                    recorder.record(&event);
+                }
+                handle_event(&state, event).await;
+                Ok(())
+            }
+        })
+        .await
+        .map_err(AppError::ListenerInit)?;
+
+    Ok(listener)
+}
+
+/// Re-creates the listener with capped exponential backoff until it starts or
+/// `MAX_RECONNECT_ATTEMPTS` is exhausted.
+async fn start_listener_with_retry(
+    db: &Arc<FirestoreDb>,
+    state: &Arc<AppState>,
+    project: Option<&str>,
+) -> Result<UrlListener, AppError> {
+    let max_attempts = state.config.max_reconnect_attempts;
+    let mut backoff = Backoff::new(state.config.reconnect_base, state.config.reconnect_max);
+    let mut attempt = 0;
+    loop {
+        match start_listener(db, state, project).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if attempt < max_attempts => {
+                attempt += 1;
+                let delay = backoff.next_delay();
+                warn!(
+                    "Failed to start listener: {}; reconnect attempt {}/{} in {:?}",
+                    e, attempt, max_attempts, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                error!("Giving up on listener after {} reconnect attempts", attempt);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Starts one listener per connected project.
+async fn start_listeners(state: &Arc<AppState>) -> Result<Vec<UrlListener>, AppError> {
+    let mut listeners = Vec::new();
+    for (project, db) in state.connections() {
+        listeners.push(start_listener_with_retry(&db, state, project.as_deref()).await?);
+    }
+    Ok(listeners)
+}
+
+/// Waits for the first shutdown signal and returns its name.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = signal::ctrl_c() => result.map(|()| "interrupt signal"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+/// Waits for Ctrl+C, the only shutdown signal outside Unix.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    signal::ctrl_c().await.map(|()| "interrupt signal")
+}
+
+/// SIGHUPs, which ask for a config reload.
+#[cfg(unix)]
+type Hangups = signal::unix::Signal;
+
+#[cfg(unix)]
+fn hangups() -> std::io::Result<Hangups> {
+    signal::unix::signal(signal::unix::SignalKind::hangup())
+}
+
+#[cfg(unix)]
+async fn hangup(hangups: &mut Hangups) {
+    hangups.recv().await;
+}
+
+/// Outside Unix there is no SIGHUP, so the config is never reloaded.
+#[cfg(not(unix))]
+type Hangups = ();
+
+#[cfg(not(unix))]
+fn hangups() -> std::io::Result<Hangups> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn hangup(_: &mut Hangups) {
+    std::future::pending().await
+}
+
+/// Re-reads the config file and applies the settings that can change while
+/// running, keeping the current ones if the new config doesn't load.
+fn reload_config(state: &AppState, config_path: Option<&Path>, cli: &Cli, log_level_overridden: bool) {
+    match config_path {
+        Some(path) => info!("Reloading config file: {}", path.display()),
+        None => info!("Reloading config from the environment"),
+    }
+    let mut config = match Config::load(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to reload config, keeping the current settings: {}", e);
+            return;
+        }
+    };
+    cli.apply(&mut config);
+    if let Err(e) = check_config(&config) {
+        error!("Failed to reload config, keeping the current settings: {}", e);
+        return;
+    }
+    apply_reload(state, &config, log_level_overridden);
+}
+
+/// Switches to the runtime settings of `config`, logging each change. Changed
+/// projects or collections are only reported, as they need a restart.
+fn apply_reload(state: &AppState, config: &Config, log_level_overridden: bool) {
+    let restart_needed = reload::restart_needed(&state.config, config);
+    if !restart_needed.is_empty() {
+        warn!("{} changed, restart to apply", restart_needed.join(" and "));
+    }
+
+    let new = LiveSettings::from_config(config);
+    let mut live = state.live.write().unwrap();
+    let changes = live.changes(&new);
+    if changes.is_empty() {
+        info!("Reloaded config, no runtime settings changed");
+        return;
+    }
+    for change in &changes {
+        info!("Reloaded config: changed {}", change);
+    }
+    for (collection, (name, max_opens)) in state.collections.iter().zip(&new.max_opens_per_minute) {
+        if &collection.name == name {
+            collection.rate_limiter.set_max_opens(*max_opens);
+        }
+    }
+    if live.log_level != new.log_level {
+        if log_level_overridden {
+            warn!("Ignoring the new LOG_LEVEL, --log-level or RUST_LOG sets the filter");
+        } else if logging::set_level(new.log_level.as_deref().unwrap_or_default()).is_none() {
+            warn!("Invalid LOG_LEVEL value {:?}, keeping the current level", new.log_level.as_deref().unwrap_or_default());
+        }
+    }
+    *live = new;
+}
+
+#[tokio::main]
+async fn main() -> Result<(), AppError> {
+    // Parse command-line options
+    let cli = match Cli::parse(env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if cli.help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+
+    // Initialize logging, after loading the env file so it can set the log
+    // options; DOTENV_PATH or --env-file name one for services whose working
+    // directory isn't where .env lives
+    let env_file = cli.env_file.clone().or_else(|| env::var_os("DOTENV_PATH").map(PathBuf::from));
+    let loaded_env_file = match &env_file {
+        Some(path) => dotenv::from_path(path).map(|_| path.clone()),
+        None => dotenv::dotenv(),
+    };
+    let log_format = match env::var("LOG_FORMAT") {
+        Ok(value) => LogFormat::parse(&value).unwrap_or_else(|| {
+            eprintln!("Invalid LOG_FORMAT value: {}", value);
+            std::process::exit(2);
+        }),
+        Err(_) => LogFormat::Text,
+    };
+    // LOG_URL_MODE supersedes the older LOG_REDACT_QUERY flag
+    let url_mode = match env::var("LOG_URL_MODE") {
+        Ok(value) => UrlLogMode::parse(&value).unwrap_or_else(|| {
+            eprintln!("Invalid LOG_URL_MODE value: {}", value);
+            std::process::exit(2);
+        }),
+        Err(_) => {
+            let redact_query = match env::var("LOG_REDACT_QUERY") {
+                Ok(value) => matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on"),
+                Err(_) => log_format == LogFormat::Json,
+            };
+            if redact_query { UrlLogMode::RedactQuery } else { UrlLogMode::Full }
+        }
+    };
+    logging::init(log_format, cli.log_level.as_deref(), url_mode);
+    match (loaded_env_file, &env_file) {
+        (Ok(path), _) => info!("Loaded environment file: {}", path.display()),
+        (Err(e), Some(path)) => {
+            error!("Failed to load environment file {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        (Err(_), None) => debug!("No .env file found, using the environment as is"),
+    }
+
+    // Load settings from the command line, environment and optional config
+    // file, refusing to start on a bad value
+    let config_path = cli.config.clone().or_else(|| env::var_os("CONFIG_PATH").map(PathBuf::from));
+    if let Some(path) = &config_path {
+        info!("Loading config file: {}", path.display());
+    }
+    let mut config = match Config::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    cli.apply(&mut config);
+    if let Err(e) = check_config(&config) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    // A log_level from the config file applies unless something above it in
+    // the logging precedence is set
+    let log_level_overridden = cli.log_level.is_some() || env::var_os("RUST_LOG").is_some();
+    if !log_level_overridden
+        && env::var_os("LOG_LEVEL").is_none()
+        && let Some(level) = &config.log_level
+        && logging::set_level(level).is_none()
+    {
+        warn!("Invalid log_level value {:?}, logging at info", level);
+    }
+
+    logging::set_display_zone(config.display_timezone.clone());
+
+    // Run a one-off command instead of listening
+    if let Some(command) = &cli.command {
+        return commands::run(command, &config).await;
+    }
+    info!("Using project: {}", config.project_id);
+    for collection in &config.collections {
+        if collection.max_opens_per_minute > 0 {
+            info!(
+                "Using collection: {} (target {}, at most {} opens per minute)",
+                collection.name, collection.target_id, collection.max_opens_per_minute
+            );
+        } else {
+            info!("Using collection: {} (target {})", collection.name, collection.target_id);
+        }
+    }
+    if env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some() {
+        warn!("Ignoring OTEL_EXPORTER_OTLP_ENDPOINT, as there is no OTLP export; scrape METRICS_PORT instead");
+    }
+    for (host, max_opens) in &config.domain_rate_limits {
+        match max_opens {
+            0 => info!("Opening URLs on {} without a rate limit", host),
+            _ => info!("Opening at most {} URLs per minute on {}", max_opens, host),
+        }
+    }
+    for project in &config.projects {
+        let names: Vec<&str> = project.collections.iter().map(|collection| collection.name.as_str()).collect();
+        info!("Also using project: {} (collections {})", project.project_id, names.join(", "));
+    }
+    info!("Opened URLs expire after {} hours", config.expiration.num_hours());
+    if config.display_timezone != timezone::DisplayZone::Utc {
+        info!("Showing times in logs in DISPLAY_TIMEZONE, now {}", display_time(Utc::now()));
+    }
+    if config.dry_run {
+        info!("Dry run enabled, URLs will be logged but not opened");
+    }
+    if config.read_only {
+        info!("Read-only mode enabled, documents will not be updated");
+    } else if config.delete_after_open {
+        info!("Documents will be deleted after opening");
+    }
+    if let Some(command) = &config.open_command {
+        info!("Opening URLs with: {} (OPEN_COMMAND, in place of the browser)", command);
+        if !config.domain_browsers.is_empty() {
+            warn!("Ignoring DOMAIN_BROWSER_MAP, as OPEN_COMMAND opens every URL");
+        }
+    } else {
+        let browser_names: Vec<String> = BROWSER_NAMES
+            .iter()
+            .map(|(name, browser)| format!("{}={:?}", name, browser))
+            .collect();
+        info!("Opening URLs with {:?} (BROWSER values: {})", config.browser, browser_names.join(", "));
+        for (host, browser) in &config.domain_browsers {
+            info!("Opening URLs on {} with {:?}", host, browser);
+        }
+    }
+    for (kind, command) in &config.open_with {
+        info!("Opening {} links with: {}", kind, command);
+    }
+    if config.incognito {
+        info!("Opening URLs in a private window");
+    }
+    if let Some(profile) = &config.browser_profile {
+        info!("Opening URLs in browser profile {}", profile);
+    }
+    if config.verify_open {
+        info!("Checking that the browser launched, waiting up to {:?}", config.verify_open_wait);
+    }
+    if !config.debounce.is_zero() {
+        info!("Waiting {:?} for document changes to settle before handling them", config.debounce);
+    }
+    if config.safe_mode && config.allowed_domains.is_empty() {
+        warn!("SAFE_MODE is on and ALLOWED_DOMAINS is empty, so no URL will be opened");
+    } else if config.safe_mode {
+        info!("SAFE_MODE is on, only opening https URLs from: {}", config.allowed_domains.join(", "));
+    } else if !config.allowed_domains.is_empty() {
+        info!("Only opening URLs from: {}", config.allowed_domains.join(", "));
+    }
+    if config.use_status_field {
+        info!("Only opening documents with status \"{}\", marking them \"{}\"", STATUS_PENDING, STATUS_OPENED);
+    }
+    if config.resolve_redirects {
+        info!(
+            "Resolving redirects before opening, following up to {} hops with a {:?} timeout",
+            config.resolve_max_redirects, config.resolve_timeout
+        );
+    }
+    if let Some((setting, proxy)) = http::proxy_in_use(&config) {
+        info!("Sending outbound HTTP requests through proxy {} (from {})", proxy, setting);
+    }
+    if config.breaker_threshold > 0 {
+        info!(
+            "Pausing opens for {:?} after {} failed opens in a row",
+            config.breaker_cooldown, config.breaker_threshold
+        );
+    }
+    if config.fetch_title {
+        info!(
+            "Fetching page titles for URLs stored without one, with a {:?} timeout, as {}",
+            config.fetch_title_timeout, config.user_agent
+        );
+    }
+    if let Some(path) = &config.pause_file {
+        info!("Holding URLs while {} exists (PAUSE_FILE)", path.display());
+    }
+    if let Some(active_hours) = &config.active_hours {
+        info!("Only opening URLs during {}", active_hours);
+    }
+    if let Some(audit_collection) = &config.audit_collection {
+        info!("Recording opened URLs in audit collection: {}", audit_collection);
+    }
+    if config.slack_webhook_url.is_some() {
+        info!("Posting opened URLs to Slack as instance: {}", config.instance_id);
+    }
+    if config.claim_before_open {
+        info!("Claiming documents before opening as instance: {}", config.instance_id);
+    }
+    if config.confirm_before_open {
+        info!("Asking for confirmation before opening, denying after {:?}", config.confirm_timeout);
+    }
+    if let Some(url_filter) = &config.url_filter {
+        info!("Only opening URLs matching: {}", url_filter);
+    }
+    if config.max_concurrent_opens > 1 {
+        info!("Running up to {} browser launches at once", config.max_concurrent_opens);
+    }
+    if config.max_open_retries > 0 {
+        info!(
+            "Retrying failed browser opens up to {} times, {:?} apart",
+            config.max_open_retries, config.open_retry_delay
+        );
+    }
+    if let Some(threshold) = config.stale_threshold {
+        let action = if config.reconnect_when_stale { "reconnecting" } else { "warning" };
+        info!("Treating the listener as stale after {:?} without events, {}", threshold, action);
+    }
+    info!("Keeping listener resume tokens in {}", config.state_storage);
+    if config.max_reconnect_attempts > 0 {
+        info!(
+            "Retrying listener startup up to {} times, backing off from {:?} to at most {:?}",
+            config.max_reconnect_attempts, config.reconnect_base, config.reconnect_max
+        );
+    }
+    let mut state = AppState::new(config);
+    if let Some(path) = &state.config.record_events_path {
+        let recorder = EventRecorder::open(path).map_err(|source| AppError::EventLog {
+            path: path.clone(),
+            source,
+        })?;
+        info!("Recording listener events to {}", path.display());
+        state.recorder = Some(recorder);
+    }
+    let state = Arc::new(state);
+    if let Some(path) = &state.config.dedup_state_path {
+        info!(
+            "Loaded {} recently opened URLs from {}",
+            state.recent_urls.lock().unwrap().len(),
+            path.display()
+        );
+    }
+
+    // Serve the health and metrics endpoints, if enabled, for the lifetime of
+    // the listener; both share one server when configured on the same port
+    let mut routers: BTreeMap<u16, axum::Router> = BTreeMap::new();
+    if let Some(port) = state.config.health_port {
+        let router = routers.remove(&port).unwrap_or_default();
+        routers.insert(port, router.merge(health::routes(state.health.clone(), state.metrics.clone())));
+    }
+    if let Some(port) = state.config.metrics_port {
+        let router = routers.remove(&port).unwrap_or_default();
+        routers.insert(port, router.merge(metrics::routes(state.metrics.clone())));
+    }
+    let http_servers: Vec<_> = routers
+        .into_iter()
+        .map(|(port, router)| {
+            let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+            let task = tokio::spawn(server::serve(port, router, shutdown_rx));
+            (shutdown_tx, task)
+        })
+        .collect();
+
+    // Initialize Firestore and start listening, retrying transient failures
+    let db = initialize_firestore(&state.config, &state.config.project_id).await?;
+    state.db.set(Arc::new(db)).ok();
+    connect_projects(&state).await?;
+    probe_collections(&state).await;
+    let draining = CancellationToken::new();
+    let worker = state
+        .queue
+        .take_receiver()
+        .map(|receiver| tokio::spawn(run_worker(state.clone(), receiver, draining.clone())));
+    restore_queue(&state).await;
+    if state.config.replay_backlog {
+        replay_backlog(&state).await?;
+    }
+    reschedule_snoozed(&state).await?;
+    let mut listeners = start_listeners(&state).await?;
+    state.health.set_listener_running(true);
+    let pause_watcher = state
+        .pause
+        .clone()
+        .map(|pause| tokio::spawn(async move { pause.watch().await }));
+
+    let stale = Arc::new(Notify::new());
+    let heartbeat = (!state.config.heartbeat_interval.is_zero() || state.config.stale_threshold.is_some()).then(|| {
+        tokio::spawn(heartbeat::run(
+            state.health.clone(),
+            state.metrics.clone(),
+            state.config.heartbeat_interval,
+            state.config.stale_threshold,
+            stale.clone(),
+        ))
+    });
+
+    // Wait for Ctrl+C or, on Unix, SIGTERM from systemd or Docker, restarting
+    // the listener in the meantime if it goes stale and RECONNECT_WHEN_STALE
+    // is set, and reloading the config on SIGHUP
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    let mut hangups = hangups().map_err(AppError::Signal)?;
+    let signal_name = loop {
+        tokio::select! {
+            signal_name = &mut signal => break signal_name.map_err(AppError::Signal)?,
+            _ = hangup(&mut hangups) => reload_config(&state, config_path.as_deref(), &cli, log_level_overridden),
+            _ = stale.notified(), if state.config.reconnect_when_stale => {
+                warn!("Restarting the stale listener");
+                state.health.set_listener_running(false);
+                for mut listener in listeners.drain(..) {
+                    if let Err(e) = listener.shutdown().await {
+                        warn!("Failed to stop the stale listener: {}", e);
+                    }
+                }
+                listeners = start_listeners(&state).await?;
+                state.health.set_listener_running(true);
+            }
+        }
+    };
+    if let Some(heartbeat) = heartbeat {
+        heartbeat.abort();
+    }
+    if let Some(pause_watcher) = pause_watcher {
+        pause_watcher.abort();
+    }
+    info!("Received {}, shutting down...", signal_name);
+    state.health.set_listener_running(false);
+
+    // Stop taking new URLs, then open the queued ones and let opens already
+    // under way finish, and stop the listeners, all within SHUTDOWN_TIMEOUT_SECS.
+    // Documents of abandoned URLs are left untouched, so they are seen again on
+    // restart.
+    let deadline = tokio::time::Instant::now() + state.config.shutdown_timeout;
+    state.shutting_down.store(true, Ordering::Relaxed);
+    draining.cancel();
+    let queued = state.queue.depth();
+    if queued > 0 {
+        info!("Opening {} queued URLs before exiting", queued);
+    }
+    let drain = async {
+        if let Some(worker) = worker {
+            worker.await.ok();
+        }
+        state.open_tasks.close();
+        state.open_tasks.wait().await;
+    };
+    if tokio::time::timeout_at(deadline, drain).await.is_err() {
+        warn!(
+            "Shutdown timed out after {:?}, abandoning {} queued URLs and {} opens in progress",
+            state.config.shutdown_timeout,
+            state.queue.depth(),
+            state.open_tasks.len()
+        );
+    }
+    // A hung Firestore connection would otherwise keep the process alive
+    // after Ctrl+C
+    let stop_listeners = async {
+        for listener in &mut listeners {
+            listener.shutdown().await?;
+        }
+        Ok(())
+    };
+    match tokio::time::timeout_at(deadline, stop_listeners).await {
+        Ok(result) => result.map_err(AppError::ListenerShutdown)?,
+        Err(_) => {
+            warn!(
+                "Listener didn't stop within the shutdown timeout of {:?}, exiting anyway",
+                state.config.shutdown_timeout
+            );
+            std::process::exit(1);
+        }
+    }
+
+    for (shutdown_tx, task) in http_servers {
+        shutdown_tx.send(()).ok();
+        task.await.ok();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_urls_are_never_opened() {
+        let config = Config::default();
+        assert_eq!(prepare_url("file:///etc/passwd", &config, &config.allowed_domains), None);
+        assert_eq!(prepare_url("file%3A%2F%2F%2Fetc%2Fpasswd", &config, &config.allowed_domains), None);
+    }
+
+    #[test]
+    fn percent_encoded_urls_are_decoded_once() {
+        let config = Config::default();
+        // Single-encoded
+        assert_eq!(
+            prepare_url("https%3A%2F%2Fexample.com%2Fa%20b", &config, &config.allowed_domains).as_deref(),
+            Some("https://example.com/a b")
+        );
+        // Double-encoded: only one layer is removed, and the rest is flagged
+        assert_eq!(prepare_url("https%253A%252F%252Fexample.com", &config, &config.allowed_domains), None);
+        let decoded = prepare_url("https://example.com/a%2520b", &config, &config.allowed_domains);
+        assert_eq!(decoded.as_deref(), Some("https://example.com/a%20b"));
+        assert!(has_percent_escapes(&decoded.unwrap()));
+        // Already decoded
+        assert_eq!(
+            prepare_url("https://example.com/page?q=1", &config, &config.allowed_domains).as_deref(),
+            Some("https://example.com/page?q=1")
+        );
+        assert!(!has_percent_escapes("https://example.com/100%"));
+    }
+
+    #[test]
+    fn percent_decoding_can_be_turned_off() {
+        let config = Config {
+            percent_decode: false,
+            ..Config::default()
+        };
+        assert_eq!(
+            prepare_url("https://example.com/a%20b", &config, &config.allowed_domains).as_deref(),
+            Some("https://example.com/a%20b")
+        );
+    }
+
+    #[test]
+    fn web_urls_are_opened() {
+        let config = Config::default();
+        assert_eq!(
+            prepare_url("https://example.com/page", &config, &config.allowed_domains).as_deref(),
+            Some("https://example.com/page")
+        );
+    }
+
+    /// Records opened URLs instead of launching a browser, optionally taking
+    /// `launch_time` to do so like a slow browser; with `failing`, fails every
+    /// launch instead.
+    #[derive(Default)]
+    pub(super) struct RecordingOpener {
+        launch_time: std::time::Duration,
+        failing: bool,
+        opened: Mutex<Vec<String>>,
+    }
+
+    impl UrlOpener for RecordingOpener {
+        fn open(&self, url: &str) -> Result<(), AppError> {
+            std::thread::sleep(self.launch_time);
+            if self.failing {
+                return Err(AppError::BrowserOpen(std::io::Error::other("browser crashed")));
+            }
+            self.opened.lock().unwrap().push(url.to_string());
+            Ok(())
+        }
+    }
+
+    impl RecordingOpener {
+        pub(super) fn opened(&self) -> Vec<String> {
+            self.opened.lock().unwrap().clone()
+        }
+    }
+
+    /// Waits for the opens started so far to finish.
+    pub(super) async fn wait_for_opens(state: &AppState) {
+        state.open_tasks.close();
+        state.open_tasks.wait().await;
+        state.open_tasks.reopen();
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Written {
+        Expired(ExpiryUpdate),
+        Status(StatusUpdate),
+        Deleted,
+    }
+
+    /// Records writes back to documents, by collection and document id,
+    /// instead of sending them to Firestore; with `failing`, records them and
+    /// fails them for good.
+    #[derive(Default)]
+    struct RecordingUpdater {
+        failing: bool,
+        written: Mutex<Vec<(String, String, Written)>>,
+    }
+
+    impl RecordingUpdater {
+        fn record(&self, collection_name: &str, doc_id: &str, written: Written) -> FirestoreResult<()> {
+            let mut all = self.written.lock().unwrap();
+            all.push((collection_name.to_string(), doc_id.to_string(), written));
+            if self.failing {
+                return Err(firestore::errors::FirestoreError::DatabaseError(
+                    firestore::errors::FirestoreDatabaseError {
+                        public: firestore::errors::FirestoreErrorPublicGenericDetails {
+                            code: "PermissionDenied".to_string(),
+                        },
+                        details: "read-only database".to_string(),
+                        retry_possible: false,
+                    },
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl DocUpdater for RecordingUpdater {
+        async fn expire(&self, collection_name: &str, doc_id: &str, update: &ExpiryUpdate) -> FirestoreResult<()> {
+            self.record(collection_name, doc_id, Written::Expired(update.clone()))
+        }
+
+        async fn set_status(&self, collection_name: &str, doc_id: &str, update: &StatusUpdate) -> FirestoreResult<()> {
+            self.record(collection_name, doc_id, Written::Status(update.clone()))
+        }
+
+        async fn delete(&self, collection_name: &str, doc_id: &str) -> FirestoreResult<()> {
+            self.record(collection_name, doc_id, Written::Deleted)
+        }
+    }
+
+    fn updating_state(config: Config, updater: RecordingUpdater) -> (Arc<AppState>, Arc<RecordingUpdater>) {
+        opening_state(config, Arc::default(), updater)
+    }
+
+    fn opening_state(
+        config: Config,
+        opener: Arc<RecordingOpener>,
+        updater: RecordingUpdater,
+    ) -> (Arc<AppState>, Arc<RecordingUpdater>) {
+        let updater = Arc::new(updater);
+        let state = AppState {
+            updater: Some(updater.clone()),
+            ..AppState::with_opener(config, opener)
+        };
+        (Arc::new(state), updater)
+    }
+
+    fn shared(url: &str) -> SharedUrl {
+        SharedUrl {
+            doc_id: None,
+            url: url.to_string(),
+            urls: Vec::new(),
+            title: None,
+            timestamp: Utc::now(),
+            expired_at: None,
+            status: None,
+            open_at: None,
+            priority: 0,
+            target_device: None,
+        }
+    }
+
+    pub(super) fn recording_state(config: Config) -> (Arc<AppState>, Arc<RecordingOpener>) {
+        let opener = Arc::new(RecordingOpener::default());
+        (Arc::new(AppState::with_opener(config, opener.clone())), opener)
+    }
+
+    #[test]
+    fn collections_of_every_project_are_handled() {
+        let mut config = Config::default();
+        let main_collection = &config.collections[0];
+        config.projects = vec![config::ProjectConfig {
+            project_id: "other-project".to_string(),
+            collections: vec![CollectionConfig {
+                target_id: main_collection.target_id + 1,
+                ..main_collection.clone()
+            }],
+        }];
+        let (state, _) = recording_state(config.clone());
+        assert_eq!(state.collections.len(), 2);
+        let other = state.collection_for(&[config.collections[0].target_id as i32 + 1]).unwrap();
+        assert_eq!(other.project_id.as_deref(), Some("other-project"));
+        assert!(state.db_for(other).is_none());
+
+        config.project_id = "other-project".to_string();
+        assert!(matches!(check_config(&config), Err(AppError::InvalidProjectId { .. })));
+        config.project_id = "main-project".to_string();
+        assert!(check_config(&config).is_ok());
+        config.projects[0].collections[0].target_id = config.collections[0].target_id;
+        assert!(matches!(check_config(&config), Err(AppError::DuplicateTargetId { .. })));
+    }
+
+    #[test]
+    fn domain_browser_rules_pick_the_opener() {
+        let config = Config {
+            domain_browsers: vec![("github.com".to_string(), Browser::Chrome)],
+            ..Config::default()
+        };
+        let (state, _) = recording_state(config);
+        assert_eq!(state.opener_for("GitHub.com").1, Some(Browser::Chrome));
+        assert_eq!(state.opener_for("gist.github.com").1, None);
+        assert_eq!(state.opener_for("example.com").1, None);
+    }
+
+    #[tokio::test]
+    async fn opens_only_allowed_urls() {
+        let config = Config {
+            allowed_domains: vec!["example.com".to_string()],
+            ..Config::default()
+        };
+        let (state, opener) = recording_state(config);
+        let collection = &state.collections[0];
+        handle_url(&shared("https%3A%2F%2Fexample.com%2Fpage"), &state, collection).await;
+        handle_url(&shared("https://other.com/page"), &state, collection).await;
+        handle_url(&shared("file:///etc/passwd"), &state, collection).await;
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened(), ["https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn safe_mode_opens_only_https_urls_on_allowed_hosts() {
+        let config = Config {
+            safe_mode: true,
+            allowed_domains: vec!["example.com".to_string()],
+            ..Config::default()
+        };
+        assert_eq!(safe_mode_refusal("http://example.com/page", &config.allowed_domains), Some("not https"));
+        assert_eq!(safe_mode_refusal("https://example.com/page", &[]), Some("host not in ALLOWED_DOMAINS"));
+        let (state, opener) = recording_state(config);
+        let collection = &state.collections[0];
+        handle_url(&shared("https://example.com/page"), &state, collection).await;
+        handle_url(&shared("http://example.com/plain"), &state, collection).await;
+        handle_url(&shared("https://other.com/page"), &state, collection).await;
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened(), ["https://example.com/page"]);
+
+        let (state, opener) = recording_state(Config {
+            safe_mode: true,
+            ..Config::default()
+        });
+        handle_url(&shared("https://example.com/page"), &state, &state.collections[0]).await;
+        wait_for_opens(&state).await;
+        assert!(opener.opened().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reloads_apply_the_new_allowlist_and_rate_limit() {
+        let config = Config {
+            allowed_domains: vec!["example.com".to_string()],
+            ..Config::default()
+        };
+        let (state, opener) = recording_state(config.clone());
+        let reloaded = Config {
+            allowed_domains: vec!["other.com".to_string()],
+            collections: vec![CollectionConfig {
+                max_opens_per_minute: 1,
+                ..config.collections[0].clone()
+            }],
+            ..config
+        };
+        apply_reload(&state, &reloaded, true);
+
+        let collection = &state.collections[0];
+        handle_url(&shared("https://example.com/page"), &state, collection).await;
+        handle_url(&shared("https://other.com/page"), &state, collection).await;
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened(), ["https://other.com/page"]);
+        assert!(collection.rate_limiter.try_acquire_at(std::time::Instant::now()).is_err());
+        assert_eq!(state.config.allowed_domains, ["example.com"]);
+    }
+
+    #[tokio::test]
+    async fn domain_rate_limits_replace_the_collection_limit() {
+        let mut config = Config {
+            domain_rate_limits: vec![("youtube.com".to_string(), 1), ("wiki.corp".to_string(), 0)],
+            ..Config::default()
+        };
+        config.collections[0].max_opens_per_minute = 2;
+        let (state, opener) = recording_state(config);
+        let collection = &state.collections[0];
+        for url in ["https://youtube.com/a", "https://wiki.corp/a", "https://wiki.corp/b", "https://wiki.corp/c"] {
+            handle_url(&shared(url), &state, collection).await;
+        }
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened().len(), 4);
+
+        let now = std::time::Instant::now();
+        assert!(state.rate_limiter_for("YouTube.com", collection).try_acquire_at(now).is_err());
+        assert!(state.rate_limiter_for("example.com", collection).try_acquire_at(now).is_ok());
+        assert!(state.rate_limiter_for("example.com", collection).try_acquire_at(now).is_ok());
+        assert!(state.rate_limiter_for("example.com", collection).try_acquire_at(now).is_err());
+    }
+
+    #[tokio::test]
+    async fn saturated_hosts_do_not_hold_up_others() {
+        let config = Config {
+            domain_rate_limits: vec![("youtube.com".to_string(), 1)],
+            ..Config::default()
+        };
+        let (state, opener) = recording_state(config);
+        let target_id = state.config.collections[0].target_id;
+        for (doc_id, url) in [("a", "https://youtube.com/a"), ("b", "https://youtube.com/b"), ("c", "https://example.com/c")] {
+            let shared_url = SharedUrl {
+                doc_id: Some(doc_id.to_string()),
+                ..shared(url)
+            };
+            state.queue.push(target_id, Some(doc_id), QueuedUrl { target_id, shared_url }).await;
+        }
+        let receiver = state.queue.take_receiver().unwrap();
+        let worker = tokio::spawn(run_worker(state.clone(), receiver, CancellationToken::new()));
+        let opened_both = async {
+            while opener.opened().len() < 2 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), opened_both).await.unwrap();
+        // The second youtube.com URL waits out the minute, still queued
+        assert_eq!(opener.opened(), ["https://youtube.com/a", "https://example.com/c"]);
+        assert_eq!(state.queue.depth(), 1);
+        worker.abort();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn open_command_replaces_the_browser() {
+        let open = |opener: Arc<dyn UrlOpener>| tokio::task::spawn_blocking(move || opener.open("https://example.com"));
+        let mut config = Config {
+            browser_path: Some(PathBuf::from("/nonexistent/browser")),
+            verify_open: true,
+            domain_browsers: vec![("example.com".to_string(), Browser::Chrome)],
+            ..Config::default()
+        };
+        assert!(open(browser_opener(&config, Browser::Default)).await.unwrap().is_err());
+        config.open_command = Some(opener::CommandOpener::parse("OPEN_COMMAND", "true {url}").unwrap());
+        assert!(open(browser_opener(&config, Browser::Firefox)).await.unwrap().is_ok());
+        // VERIFY_OPEN checks the command's exit status too
+        config.open_command = Some(opener::CommandOpener::parse("OPEN_COMMAND", "false {url}").unwrap());
+        assert!(open(browser_opener(&config, Browser::Default)).await.unwrap().is_err());
+        // and DOMAIN_BROWSER_MAP is ignored
+        let state = AppState::new(config);
+        assert_eq!(state.opener_for("example.com").1, None);
+    }
+
+    #[tokio::test]
+    async fn open_with_rules_take_matching_links() {
+        let config = Config {
+            open_with: vec![
+                (LinkKind::parse("pdf").unwrap(), opener::CommandOpener::parse("OPEN_WITH", "evince {url}").unwrap()),
+                (LinkKind::parse("gs:").unwrap(), opener::CommandOpener::parse("OPEN_WITH", "gsutil-open {url}").unwrap()),
+            ],
+            ..Config::default()
+        };
+        let browser = Arc::new(RecordingOpener::default());
+        let viewer = Arc::new(RecordingOpener::default());
+        let mut state = AppState::with_opener(config, browser.clone());
+        for (_, opener) in &mut state.link_openers {
+            *opener = viewer.clone();
+        }
+        let state = Arc::new(state);
+        let collection = &state.collections[0];
+        handle_url(&shared("https://example.com/paper.pdf"), &state, collection).await;
+        handle_url(&shared("gs://bucket/report.csv"), &state, collection).await;
+        handle_url(&shared("https://example.com/page"), &state, collection).await;
+        handle_url(&shared("ftp://example.com/file.txt"), &state, collection).await;
+        wait_for_opens(&state).await;
+        let mut opened = viewer.opened();
+        opened.sort();
+        assert_eq!(opened, ["gs://bucket/report.csv", "https://example.com/paper.pdf"]);
+        assert_eq!(browser.opened(), ["https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn overlong_urls_are_skipped() {
+        let config = Config {
+            max_url_length: 40,
+            ..Config::default()
+        };
+        let (state, opener) = recording_state(config);
+        let collection = &state.collections[0];
+        let long_url = format!("https://example.com/{}", "a".repeat(40));
+        handle_url(&shared(&long_url), &state, collection).await;
+        handle_url(&shared("https://example.com/page"), &state, collection).await;
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened(), ["https://example.com/page"]);
+    }
+
+    #[test]
+    fn unicode_and_punycode_domains_match() {
+        let allowlist = |domain| vec![normalize::ascii_host(domain)];
+        assert!(is_allowed_domain("https://café.example/menu", &allowlist("xn--caf-dma.example")));
+        assert!(is_allowed_domain("https://xn--caf-dma.example/menu", &allowlist("café.example")));
+        assert!(is_allowed_domain("https://CAFÉ.example/menu", &allowlist("café.example")));
+        assert!(!is_allowed_domain("https://cafe.example/menu", &allowlist("café.example")));
+    }
+
+    #[tokio::test]
+    async fn recently_opened_urls_are_skipped() {
+        let (state, opener) = recording_state(Config::default());
+        let collection = &state.collections[0];
+        handle_url(&shared("https://example.com/page"), &state, collection).await;
+        handle_url(&shared("https://example.com/page?utm_source=x"), &state, collection).await;
+        handle_url(&shared("https://example.com/other"), &state, collection).await;
+        wait_for_opens(&state).await;
+        let mut opened = opener.opened();
+        opened.sort();
+        assert_eq!(opened, ["https://example.com/other", "https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn declined_urls_are_asked_about_again() {
+        let opener = Arc::new(RecordingOpener::default());
+        let state = Arc::new(AppState {
+            confirmer: Some(Confirmer::with_input(Box::new(&b"n\ny\n"[..]), std::time::Duration::from_secs(1))),
+            ..AppState::with_opener(Config::default(), opener.clone())
+        });
+        for _ in 0..2 {
+            handle_url(&shared("https://example.com/page"), &state, &state.collections[0]).await;
+        }
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened(), ["https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_opens_nothing() {
+        let config = Config {
+            dry_run: true,
+            ..Config::default()
+        };
+        let (state, opener) = recording_state(config);
+        handle_url(&shared("https://example.com/page"), &state, &state.collections[0]).await;
+        wait_for_opens(&state).await;
+        assert!(opener.opened().is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_opens_are_capped() {
+        let opener = Arc::new(RecordingOpener {
+            launch_time: std::time::Duration::from_millis(200),
+            ..RecordingOpener::default()
+        });
+        let config = Config {
+            max_concurrent_opens: 2,
+            ..Config::default()
+        };
+        let state = Arc::new(AppState::with_opener(config, opener.clone()));
+        let started = std::time::Instant::now();
+        for page in ["a", "b", "c"] {
+            let url = format!("https://example.com/{}", page);
+            handle_url(&shared(&url), &state, &state.collections[0]).await;
+        }
+        wait_for_opens(&state).await;
+        // Two launches run side by side, the third waits for a permit
+        let elapsed = started.elapsed();
+        assert!(elapsed >= std::time::Duration::from_millis(400), "{:?}", elapsed);
+        assert!(elapsed < std::time::Duration::from_millis(600), "{:?}", elapsed);
+        assert_eq!(opener.opened().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn slow_browser_launches_do_not_block_the_handler() {
+        let opener = Arc::new(RecordingOpener {
+            launch_time: std::time::Duration::from_millis(500),
+            ..RecordingOpener::default()
+        });
+        let state = Arc::new(AppState::with_opener(Config::default(), opener.clone()));
+        let started = std::time::Instant::now();
+        handle_url(&shared("https://example.com/page"), &state, &state.collections[0]).await;
+        assert!(started.elapsed() < std::time::Duration::from_millis(250));
+        assert!(opener.opened().is_empty());
+
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened(), ["https://example.com/page"]);
+    }
+
+    #[test]
+    fn malformed_urls_are_rejected() {
+        assert!(validate_url("").is_err());
+        assert!(validate_url("   ").is_err());
+        assert!(validate_url("/relative/path").is_err());
+        assert!(validate_url("example.com/page").is_err());
+        assert!(validate_url("htp://").is_err());
+        assert!(validate_url("https://example.com/page").is_ok());
+        assert!(validate_url("https%3A%2F%2Fexample.com%2Fpage").is_ok());
+    }
+
+    #[test]
+    fn missing_project_id_is_rejected() {
+        assert!(matches!(check_config(&Config::default()), Err(AppError::MissingProjectId)));
+        let config = Config {
+            project_id: "demo-project".to_string(),
+            ..Config::default()
+        };
+        assert!(check_config(&config).is_ok());
+    }
+
+    #[test]
+    fn duplicate_target_ids_are_rejected() {
+        let collection = |name: &str, target_id| config::CollectionConfig {
+            name: name.to_string(),
+            target_id,
+            max_opens_per_minute: 0,
+        };
+        let config = Config {
+            project_id: "demo-project".to_string(),
+            collections: vec![collection("inbox", 42), collection("work", 42)],
+            ..Config::default()
+        };
+        assert!(matches!(
+            check_config(&config),
+            Err(AppError::DuplicateTargetId { target_id: 42, .. })
+        ));
+    }
+
+    #[test]
+    fn project_ids_follow_gcp_rules() {
+        for valid in ["my-project-123", "abcdef", "a23456789012345678901234567890"] {
+            assert!(validate_project_id(valid).is_ok(), "{}", valid);
+        }
+        for invalid in ["short", "My-Project", "my_project", "1project", "my-project-", " my-project"] {
+            assert!(
+                matches!(validate_project_id(invalid), Err(AppError::InvalidProjectId { .. })),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn every_link_of_a_document_is_opened_in_order() {
+        let (state, opener) = recording_state(Config::default());
+        let batch = SharedUrl {
+            urls: vec![
+                "https://example.com/b".to_string(),
+                " ".to_string(),
+                "https://example.com/c".to_string(),
+            ],
+            ..shared("https://example.com/a")
+        };
+        assert_eq!(batch.links().len(), 3);
+        handle_url(&batch, &state, &state.collections[0]).await;
+        wait_for_opens(&state).await;
+        assert_eq!(
+            opener.opened(),
+            ["https://example.com/a", "https://example.com/b", "https://example.com/c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn tabs_of_a_document_are_spaced_out() {
+        let config = Config {
+            tab_delay: std::time::Duration::from_millis(100),
+            ..Config::default()
+        };
+        let (state, opener) = recording_state(config);
+        let batch = SharedUrl {
+            urls: vec!["https://example.com/b".to_string(), "https://example.com/c".to_string()],
+            ..shared("https://example.com/a")
+        };
+        let started = std::time::Instant::now();
+        handle_url(&batch, &state, &state.collections[0]).await;
+        assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened().len(), 3);
+
+        // A single link doesn't wait
+        let started = std::time::Instant::now();
+        handle_url(&shared("https://example.com/d"), &state, &state.collections[0]).await;
+        assert!(started.elapsed() < std::time::Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn urls_with_a_malformed_timestamp_are_still_opened() {
+        let doc = FirestoreDb::serialize_to_doc(
+            "projects/demo/databases/(default)/documents/shared_urls/abc",
+            &serde_json::json!({ "url": "https://example.com/page", "timestamp": "last tuesday" }),
+        )
+        .unwrap();
+        let shared_url = FirestoreDb::deserialize_doc_to::<SharedUrl>(&doc).unwrap();
+        assert!(Utc::now() - shared_url.timestamp < chrono::Duration::minutes(1));
+
+        let (state, opener) = recording_state(Config::default());
+        handle_url(&shared_url, &state, &state.collections[0]).await;
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened(), ["https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn higher_priorities_jump_the_queue() {
+        let (state, _) = recording_state(Config::default());
+        let collection = &state.collections[0];
+        let start = Utc::now();
+        let doc = |id: &str, seconds: i64, priority: Option<i32>| {
+            let mut fields = serde_json::json!({
+                "url": format!("https://example.com/{}", id),
+                "timestamp": (start + chrono::Duration::seconds(seconds)).to_rfc3339(),
+            });
+            if let Some(priority) = priority {
+                fields["priority"] = priority.into();
+            }
+            FirestoreDb::serialize_to_doc(format!("projects/demo/databases/(default)/documents/shared_urls/{}", id), &fields)
+                .unwrap()
+        };
+        handle_document_change(&state, collection, &doc("newer", 2, None)).await;
+        handle_document_change(&state, collection, &doc("older", 1, Some(0))).await;
+        handle_document_change(&state, collection, &doc("urgent", 3, Some(10))).await;
+        handle_document_change(&state, collection, &doc("later", 0, Some(-1))).await;
+
+        let mut receiver = state.queue.take_receiver().unwrap();
+        let mut order = Vec::new();
+        while let Some(queued) = receiver.try_recv() {
+            order.push(queued.shared_url.doc_id.unwrap());
+        }
+        assert_eq!(order, ["urgent", "older", "newer", "later"]);
+    }
+
+    #[tokio::test]
+    async fn documents_for_another_device_are_skipped() {
+        let (state, _) = recording_state(Config {
+            instance_id: "laptop".to_string(),
+            ..Config::default()
+        });
+        let collection = &state.collections[0];
+        let doc = |id: &str, target_device: Option<&str>| {
+            let mut fields = serde_json::json!({
+                "url": format!("https://example.com/{}", id),
+                "timestamp": Utc::now().to_rfc3339(),
+            });
+            if let Some(target_device) = target_device {
+                fields["target_device"] = target_device.into();
+            }
+            FirestoreDb::serialize_to_doc(format!("projects/demo/databases/(default)/documents/shared_urls/{}", id), &fields)
+                .unwrap()
+        };
+        handle_document_change(&state, collection, &doc("anywhere", None)).await;
+        handle_document_change(&state, collection, &doc("mine", Some("Laptop"))).await;
+        handle_document_change(&state, collection, &doc("desktop", Some("desktop"))).await;
+
+        let mut receiver = state.queue.take_receiver().unwrap();
+        let mut queued = Vec::new();
+        while let Some(url) = receiver.try_recv() {
+            queued.push(url.shared_url.doc_id.unwrap());
+        }
+        assert_eq!(queued, ["anywhere", "mine"]);
+    }
+
+    #[test]
+    fn url_field_names_the_field_holding_the_url() {
+        let doc = FirestoreDb::serialize_to_doc(
+            "projects/demo/databases/(default)/documents/shared_urls/linked",
+            &serde_json::json!({
+                "link": "https://example.com/linked",
+                "url": "https://example.com/other",
+                "timestamp": Utc::now().to_rfc3339(),
+            }),
+        )
+        .unwrap();
+        assert_eq!(read_shared_url(&doc, "link").unwrap().url, "https://example.com/linked");
+        assert_eq!(read_shared_url(&doc, "url").unwrap().url, "https://example.com/other");
+        assert!(read_shared_url(&doc, "href").unwrap().url.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_documents_reopen_only_once_their_expiry_passed() {
+        let doc = |id: &str, expired_at: DateTime<Utc>| {
+            FirestoreDb::serialize_to_doc(
+                format!("projects/demo/databases/(default)/documents/shared_urls/{}", id),
+                &serde_json::json!({
+                    "url": format!("https://example.com/{}", id),
+                    "timestamp": Utc::now().to_rfc3339(),
+                    "expired_at": expired_at.to_rfc3339(),
+                }),
+            )
+            .unwrap()
+        };
+        let past = Utc::now() - chrono::Duration::hours(1);
+        let future = Utc::now() + chrono::Duration::hours(1);
+
+        let (state, _) = recording_state(Config::default());
+        handle_expired_document(&state, &state.collections[0], &doc("expired", past)).await;
+        assert_eq!(state.queue.depth(), 0);
+
+        let (state, _) = recording_state(Config {
+            expired_doc_action: ExpiredDocAction::Reopen,
+            ..Config::default()
+        });
+        let collection = &state.collections[0];
+        handle_expired_document(&state, collection, &doc("expired", past)).await;
+        handle_expired_document(&state, collection, &doc("just_opened", future)).await;
+        let mut receiver = state.queue.take_receiver().unwrap();
+        assert_eq!(receiver.try_recv().unwrap().shared_url.doc_id.as_deref(), Some("expired"));
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn snoozed_documents_are_queued_at_open_at() {
+        let (state, _) = recording_state(Config::default());
+        let collection = &state.collections[0];
+        let doc = |id: &str, open_at: DateTime<Utc>| {
+            FirestoreDb::serialize_to_doc(
+                format!("projects/demo/databases/(default)/documents/shared_urls/{}", id),
+                &serde_json::json!({
+                    "url": "https://example.com/page",
+                    "timestamp": Utc::now().to_rfc3339(),
+                    "open_at": open_at.to_rfc3339(),
+                }),
+            )
+            .unwrap()
+        };
+        let soon = Utc::now() + chrono::Duration::milliseconds(300);
+        handle_document_change(&state, collection, &doc("soon", soon)).await;
+        handle_document_change(&state, collection, &doc("later", soon)).await;
+        handle_document_change(&state, collection, &doc("past", Utc::now())).await;
+        assert_eq!(state.queue.depth(), 1);
+        assert_eq!(state.snoozed.lock().unwrap().len(), 2);
+
+        handle_document_delete(&state, "projects/demo/databases/(default)/documents/shared_urls/later");
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+        assert_eq!(state.queue.depth(), 2);
+        assert!(state.snoozed.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn debounced_changes_are_handled_once_settled() {
+        let (state, _) = recording_state(Config {
+            debounce: std::time::Duration::from_millis(200),
+            ..Config::default()
+        });
+        let collection = &state.collections[0];
+        let doc = |id: &str, url: &str| {
+            FirestoreDb::serialize_to_doc(
+                format!("projects/demo/databases/(default)/documents/shared_urls/{}", id),
+                &serde_json::json!({ "url": url, "timestamp": Utc::now().to_rfc3339() }),
+            )
+            .unwrap()
+        };
+        debounce(&state, collection, doc("edited", "https://example.com/draft"));
+        debounce(&state, collection, doc("deleted", "https://example.com/deleted"));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        debounce(&state, collection, doc("edited", "https://example.com/final"));
+        handle_document_delete(&state, "projects/demo/databases/(default)/documents/shared_urls/deleted");
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        assert_eq!(state.queue.depth(), 0);
+
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(state.debounced.lock().unwrap().is_empty());
+        let mut receiver = state.queue.take_receiver().unwrap();
+        assert_eq!(receiver.try_recv().unwrap().shared_url.links(), ["https://example.com/final"]);
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn only_opened_urls_are_saved_to_the_dedup_state() {
+        let path = std::env::temp_dir().join(format!("opener_dedup_{}.json", std::process::id()));
+        for (dry_run, saved) in [(true, 0), (false, 1)] {
+            let (state, _) = recording_state(Config {
+                dry_run,
+                dedup_state_path: Some(path.clone()),
+                ..Config::default()
+            });
+            handle_url(&shared("https://example.com/page"), &state, &state.collections[0]).await;
+            wait_for_opens(&state).await;
+            let restarted = RecentUrls::persisted(state.config.dedup_window, 0, path.clone());
+            assert_eq!(restarted.len(), saved, "dry_run: {}", dry_run);
+        }
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn urls_are_held_while_the_pause_file_exists() {
+        let path = std::env::temp_dir().join(format!("opener_pause_{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let (state, opener) = recording_state(Config {
+            pause_file: Some(path.clone()),
+            ..Config::default()
+        });
+        let handled = tokio::spawn({
+            let state = state.clone();
+            async move { handle_url(&shared("https://example.com/page"), &state, &state.collections[0]).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert!(!handled.is_finished());
+
+        std::fs::remove_file(&path).unwrap();
+        handled.await.unwrap();
+        wait_for_opens(&state).await;
+        assert_eq!(opener.opened(), ["https://example.com/page"]);
+    }
+
+    #[tokio::test]
+    async fn opened_documents_are_written_back_to_their_collection() {
+        let shared_url = SharedUrl {
+            doc_id: Some("abc".to_string()),
+            ..shared("https://example.com/page")
+        };
+        let written = |config: Config| async {
+            let (state, updater) = updating_state(config, RecordingUpdater::default());
+            process_url(&state, &state.collections[0], &shared_url).await;
+            std::mem::take(&mut *updater.written.lock().unwrap())
+        };
+
+        let before = Utc::now();
+        let mut expired = written(Config::default()).await;
+        let (collection_name, doc_id, Written::Expired(update)) = expired.remove(0) else {
+            panic!("expected an expiry update");
+        };
+        assert_eq!((collection_name.as_str(), doc_id.as_str()), ("shared_urls", "abc"));
+        assert_eq!(update.timestamp, shared_url.timestamp);
+        assert!(update.expired_at >= before + chrono::Duration::hours(72));
+        assert!(expired.is_empty());
+
+        let mut opened = written(Config {
+            use_status_field: true,
+            ..Config::default()
+        })
+        .await;
+        let (_, _, Written::Status(update)) = opened.remove(0) else {
+            panic!("expected a status update");
+        };
+        assert_eq!(update.status, STATUS_OPENED);
+        assert!(update.opened_at.is_some());
+
+        let deleted = written(Config {
+            delete_after_open: true,
+            ..Config::default()
+        })
+        .await;
+        assert_eq!(deleted, [("shared_urls".to_string(), "abc".to_string(), Written::Deleted)]);
+
+        let read_only = written(Config {
+            read_only: true,
+            ..Config::default()
+        })
+        .await;
+        assert!(read_only.is_empty());
+    }
+
+    #[tokio::test]
+    async fn documents_are_deleted_only_once_opened() {
+        let shared_url = SharedUrl {
+            doc_id: Some("abc".to_string()),
+            ..shared("https://example.com/page")
+        };
+        let config = || Config {
+            delete_after_open: true,
+            ..Config::default()
+        };
+        let opener = Arc::new(RecordingOpener {
+            launch_time: std::time::Duration::from_millis(200),
+            ..RecordingOpener::default()
+        });
+        let (state, updater) = opening_state(config(), opener.clone(), RecordingUpdater::default());
+        process_url(&state, &state.collections[0], &shared_url).await;
+        assert_eq!(opener.opened(), ["https://example.com/page"]);
+        assert_eq!(*updater.written.lock().unwrap(), [("shared_urls".to_string(), "abc".to_string(), Written::Deleted)]);
+
+        let failing = Arc::new(RecordingOpener {
+            failing: true,
+            ..RecordingOpener::default()
+        });
+        let (state, updater) = opening_state(config(), failing.clone(), RecordingUpdater::default());
+        process_url(&state, &state.collections[0], &shared_url).await;
+        assert!(updater.written.lock().unwrap().is_empty());
+
+        // With a status field, the failure is written down instead
+        let config = Config {
+            use_status_field: true,
+            ..config()
+        };
+        let (state, updater) = opening_state(config, failing, RecordingUpdater::default());
+        process_url(&state, &state.collections[0], &shared_url).await;
+        let written = updater.written.lock().unwrap();
+        let [(_, _, Written::Status(update))] = written.as_slice() else {
+            panic!("expected a status update, got {:?}", written);
+        };
+        assert_eq!(update.status, STATUS_FAILED);
+        assert_eq!(update.opened_at, None);
+    }
+
+    #[tokio::test]
+    async fn documents_failing_every_retry_stay_unexpired() {
+        let shared_url = SharedUrl {
+            doc_id: Some("abc".to_string()),
+            ..shared("https://example.com/page")
+        };
+        let config = Config {
+            max_open_retries: 2,
+            open_retry_delay: std::time::Duration::from_millis(1),
+            ..Config::default()
+        };
+        let failing = Arc::new(RecordingOpener {
+            failing: true,
+            ..RecordingOpener::default()
+        });
+        let (state, updater) = opening_state(config, failing, RecordingUpdater::default());
+        process_url(&state, &state.collections[0], &shared_url).await;
+        assert!(updater.written.lock().unwrap().is_empty());
+        assert_eq!(state.metrics.open_failures(), 1);
+        // Nor is the URL held back by the dedup window
+        assert!(!state.recent_urls.lock().unwrap().contains("https://example.com/page"));
+    }
+
+    #[tokio::test]
+    async fn documents_left_unmarked_are_not_opened_again() {
+        let updater = RecordingUpdater {
+            failing: true,
+            ..RecordingUpdater::default()
+        };
+        let (state, updater) = updating_state(Config::default(), updater);
+        let collection = &state.collections[0];
+        let doc = FirestoreDb::serialize_to_doc(
+            "projects/demo/databases/(default)/documents/shared_urls/abc",
+            &serde_json::json!({ "url": "https://example.com/page", "timestamp": Utc::now().to_rfc3339() }),
+        )
+        .unwrap();
+        handle_document_change(&state, collection, &doc).await;
+        let mut receiver = state.queue.take_receiver().unwrap();
+        let queued = receiver.try_recv().unwrap();
+        state.queue.finish(queued.shared_url.doc_id.as_deref());
+        process_url(&state, collection, &queued.shared_url).await;
+        // Failed for good, so not retried
+        assert_eq!(updater.written.lock().unwrap().len(), 1);
+        assert!(state.unmarked.lock().unwrap().contains("abc"));
+
+        handle_document_change(&state, collection, &doc).await;
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[test]
+    fn invalid_documents_name_the_bad_field() {
+        let doc = |fields: serde_json::Value| {
+            FirestoreDb::serialize_to_doc("projects/demo/databases/(default)/documents/shared_urls/abc", &fields)
+                .unwrap()
+        };
+        let missing_url = doc(serde_json::json!({ "timestamp": "2024-01-01T00:00:00Z" }));
+        assert_eq!(document_id(&missing_url), "abc");
+        assert_eq!(invalid_document_reason(&missing_url), "missing url field");
+        assert_eq!(
+            invalid_document_reason(&doc(serde_json::json!({ "url": "https://example.com" }))),
+            "missing timestamp field"
+        );
+        assert_eq!(
+            invalid_document_reason(&doc(serde_json::json!({ "url": "https://example.com", "timestamp": 5 }))),
+            "malformed timestamp"
+        );
+        assert_eq!(
+            invalid_document_reason(&doc(serde_json::json!({ "urls": "https://example.com" }))),
+            "urls is not a list of strings"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use axum::Router;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use chrono::Utc;
+use std::fmt::Write;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+/// Process-wide counters, exposed in the Prometheus text format on `/metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    urls_opened: AtomicU64,
+    url_open_failures: AtomicU64,
+    firestore_events: AtomicU64,
+    deserialization_failures: AtomicU64,
+    last_event_timestamp: AtomicI64,
+    queue_depth: AtomicU64,
+    /// Queue depth by collection.
+    lane_queue_depths: Mutex<BTreeMap<String, usize>>,
+}
+
+impl Metrics {
+    pub fn record_event(&self) {
+        self.firestore_events.fetch_add(1, Ordering::Relaxed);
+        self.last_event_timestamp
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    /// Counts a document that didn't deserialize, returning the new total.
+    pub fn record_deserialization_failure(&self) -> u64 {
+        self.deserialization_failures.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn deserialization_failures(&self) -> u64 {
+        self.deserialization_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn record_open(&self) {
+        self.urls_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn urls_opened(&self) -> u64 {
+        self.urls_opened.load(Ordering::Relaxed)
+    }
+
+    pub fn record_open_failure(&self) {
+        self.url_open_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn open_failures(&self) -> u64 {
+        self.url_open_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_lane_queue_depth(&self, collection: &str, depth: usize) {
+        self.lane_queue_depths.lock().unwrap().insert(collection.to_string(), depth);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            writeln!(out, "# HELP {} {}", name, help).ok();
+            writeln!(out, "# TYPE {} {}", name, kind).ok();
+            writeln!(out, "{} {}", name, value).ok();
+        };
+        metric(
+            "urls_opened_total",
+            "counter",
+            "URLs opened in the browser.",
+            self.urls_opened.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "url_open_failures_total",
+            "counter",
+            "URLs the browser failed to open.",
+            self.url_open_failures.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "firestore_events_total",
+            "counter",
+            "Listen events received from Firestore.",
+            self.firestore_events.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "deserialization_failures_total",
+            "counter",
+            "Documents that failed to deserialize as a shared URL.",
+            self.deserialization_failures.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "last_event_timestamp_seconds",
+            "gauge",
+            "Unix time of the last processed Firestore event.",
+            self.last_event_timestamp.load(Ordering::Relaxed).to_string(),
+        );
+        metric(
+            "queue_depth",
+            "gauge",
+            "URLs waiting in the queue to be opened.",
+            self.queue_depth.load(Ordering::Relaxed).to_string(),
+        );
+        writeln!(out, "# HELP collection_queue_depth URLs waiting in the queue to be opened, by collection.").ok();
+        writeln!(out, "# TYPE collection_queue_depth gauge").ok();
+        for (collection, depth) in self.lane_queue_depths.lock().unwrap().iter() {
+            writeln!(out, "collection_queue_depth{{collection=\"{}\"}} {}", collection, depth).ok();
+        }
+        out
+    }
+}
+
+pub fn routes(metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route("/metrics", get(render))
+        .with_state(metrics)
+}
+
+async fn render(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    )
+}
@@ -0,0 +1,133 @@
+use url::{Host, Url};
+
+/// Query parameters dropped by default: `utm_*` campaign tags and Facebook's
+/// click id.
+pub const DEFAULT_TRACKING_PARAMS: &[&str] = &["utm_*", "fbclid"];
+
+/// Canonical form of a URL for comparing links: the host is lowercased,
+/// default ports are dropped and query parameters matching `tracking_params`
+/// are removed. A pattern ending in `*` matches by prefix. URLs that don't
+/// parse are returned unchanged.
+pub fn normalize_url(url: &str, tracking_params: &[String]) -> String {
+    // Parsing already lowercases the host and drops the scheme's default port
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    let pairs: Vec<(String, String)> = parsed.query_pairs().into_owned().collect();
+    let kept: Vec<_> = pairs
+        .iter()
+        .filter(|(name, _)| !is_tracking_param(name, tracking_params))
+        .collect();
+    if kept.len() != pairs.len() {
+        if kept.is_empty() {
+            parsed.set_query(None);
+        } else {
+            parsed.query_pairs_mut().clear().extend_pairs(kept);
+        }
+    }
+    parsed.to_string()
+}
+
+/// Sets each of `params` on the URL, replacing any value it already has, so
+/// applying the same params twice gives the same URL. URLs that don't parse
+/// are returned unchanged.
+pub fn append_params(url: &str, params: &[(String, String)]) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    let kept: Vec<(String, String)> = parsed
+        .query_pairs()
+        .into_owned()
+        .filter(|(name, _)| !params.iter().any(|(param, _)| param == name))
+        .collect();
+    parsed.query_pairs_mut().clear().extend_pairs(kept).extend_pairs(params);
+    parsed.to_string()
+}
+
+/// ASCII form of a host name as it appears in a parsed URL: lowercased, with
+/// internationalized labels converted to punycode, so `café.example` becomes
+/// `xn--caf-dma.example`. Hosts that don't parse are only lowercased.
+pub fn ascii_host(host: &str) -> String {
+    match Host::parse(host.trim()) {
+        Ok(host) => host.to_string(),
+        Err(_) => host.trim().to_lowercase(),
+    }
+}
+
+fn is_tracking_param(name: &str, tracking_params: &[String]) -> bool {
+    tracking_params.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_urls() {
+        let tracking_params: Vec<String> =
+            DEFAULT_TRACKING_PARAMS.iter().map(|param| param.to_string()).collect();
+        let cases = [
+            ("https://example.com/page", "https://example.com/page"),
+            ("https://EXAMPLE.com/page", "https://example.com/page"),
+            ("https://example.com:443/page", "https://example.com/page"),
+            ("http://example.com:80/page", "http://example.com/page"),
+            ("http://example.com:8080/page", "http://example.com:8080/page"),
+            ("https://example.com/page?utm_source=x", "https://example.com/page"),
+            ("https://example.com/page?utm_source=x&utm_medium=y&fbclid=z", "https://example.com/page"),
+            ("https://example.com/page?id=1&utm_source=x", "https://example.com/page?id=1"),
+            ("https://example.com/page?id=1#top", "https://example.com/page?id=1#top"),
+            ("https://example.com/Page", "https://example.com/Page"),
+            ("not a url", "not a url"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(normalize_url(input, &tracking_params), expected, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn keeps_query_without_tracking_params() {
+        assert_eq!(
+            normalize_url("https://example.com/page?utm_source=x", &[]),
+            "https://example.com/page?utm_source=x"
+        );
+    }
+
+    #[test]
+    fn converts_hosts_to_ascii() {
+        assert_eq!(ascii_host("café.example"), "xn--caf-dma.example");
+        assert_eq!(ascii_host("CAFÉ.example"), "xn--caf-dma.example");
+        assert_eq!(ascii_host("xn--caf-dma.example"), "xn--caf-dma.example");
+        assert_eq!(ascii_host("Example.com"), "example.com");
+    }
+
+    #[test]
+    fn appends_params_once() {
+        let params = vec![
+            ("utm_source".to_string(), "firestore_opener".to_string()),
+            ("utm_medium".to_string(), "share".to_string()),
+        ];
+        let cases = [
+            (
+                "https://example.com/page",
+                "https://example.com/page?utm_source=firestore_opener&utm_medium=share",
+            ),
+            (
+                "https://example.com/page?id=1#top",
+                "https://example.com/page?id=1&utm_source=firestore_opener&utm_medium=share#top",
+            ),
+            (
+                "https://example.com/page?utm_source=other&id=1",
+                "https://example.com/page?id=1&utm_source=firestore_opener&utm_medium=share",
+            ),
+            ("not a url", "not a url"),
+        ];
+        for (input, expected) in cases {
+            let appended = append_params(input, &params);
+            assert_eq!(appended, expected, "input: {}", input);
+            assert_eq!(append_params(&appended, &params), expected, "input: {}", input);
+        }
+    }
+}
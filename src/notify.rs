@@ -0,0 +1,32 @@
+use log::{info, warn};
+use tokio::process::Command;
+
+/// Shows a desktop notification for an opened URL, headed with its host and
+/// with the full URL as the body. Falls back to a log line when no
+/// notification backend is available.
+pub async fn notify_opened(host: &str, url: &str) {
+    match notification_command(host, url).status().await {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("Notification backend exited with {}; opened {}", status, url),
+        Err(e) => info!("Desktop notifications unavailable ({}); opened {}", e, url),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn notification_command(title: &str, body: &str) -> Command {
+    let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+    let mut command = Command::new("osascript");
+    command.arg("-e").arg(format!(
+        "display notification \"{}\" with title \"{}\"",
+        escape(body),
+        escape(title)
+    ));
+    command
+}
+
+#[cfg(not(target_os = "macos"))]
+fn notification_command(title: &str, body: &str) -> Command {
+    let mut command = Command::new("notify-send");
+    command.arg("--app-name=firestore_url_opener").arg(title).arg(body);
+    command
+}
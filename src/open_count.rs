@@ -0,0 +1,49 @@
+use firestore::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct OpenCount {
+    #[serde(default)]
+    open_count: i64,
+}
+
+/// Adds one to the document's `open_count`, a missing field counting as 0,
+/// and returns the new count, or `None` if the document is gone.
+///
+/// Received documents only get the field ahead of this with
+/// CLAIM_BEFORE_OPEN, as part of the claim. A write of its own would come
+/// back as another listen event and queue the document a second time, so
+/// without a claim the field first appears here, at 1.
+///
+/// The read and the write happen in one transaction, which Firestore retries
+/// when another instance changed the document in between, so concurrent
+/// opens are all counted.
+pub async fn increment(db: &FirestoreDb, collection_name: &str, doc_id: &str) -> FirestoreResult<Option<i64>> {
+    db.run_transaction(|db, transaction| {
+        let (collection_name, doc_id) = (collection_name.to_string(), doc_id.to_string());
+        Box::pin(async move {
+            let current: Option<OpenCount> = db
+                .fluent()
+                .select()
+                .by_id_in(&collection_name)
+                .obj()
+                .one(&doc_id)
+                .await?;
+            let Some(current) = current else {
+                return Ok(None);
+            };
+            let updated = OpenCount {
+                open_count: current.open_count + 1,
+            };
+            db.fluent()
+                .update()
+                .fields(["open_count"])
+                .in_col(&collection_name)
+                .document_id(&doc_id)
+                .object(&updated)
+                .add_to_transaction(transaction)?;
+            Ok(Some(updated.open_count))
+        })
+    })
+    .await
+}
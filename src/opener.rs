@@ -0,0 +1,411 @@
+use crate::error::AppError;
+use log::warn;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use url::Url;
+use webbrowser::Browser;
+
+/// Hands a URL to whatever displays it. The listener only ever talks to this
+/// trait, so tests can swap in an opener that records calls instead.
+pub trait UrlOpener: Send + Sync {
+    fn open(&self, url: &str) -> Result<(), AppError>;
+}
+
+/// Opens URLs with the `webbrowser` crate.
+#[derive(Debug)]
+pub struct WebBrowserOpener {
+    browser: Browser,
+}
+
+impl WebBrowserOpener {
+    pub fn new(browser: Browser) -> Self {
+        WebBrowserOpener { browser }
+    }
+}
+
+impl UrlOpener for WebBrowserOpener {
+    fn open(&self, url: &str) -> Result<(), AppError> {
+        webbrowser::open_browser(self.browser, url).map_err(AppError::BrowserOpen)
+    }
+}
+
+/// Runs `command`. With `verify`, waits up to that long and fails if the
+/// process exits unsuccessfully in that time; one still running by then, such
+/// as a browser that stays open, counts as a success.
+///
+/// Openers run on the blocking pool, off the async runtime, so the wait
+/// blocks on the runtime's handle there.
+fn spawn_checked(command: &mut Command, verify: Option<Duration>) -> Result<(), AppError> {
+    let mut child = command.spawn().map_err(AppError::BrowserOpen)?;
+    if let Some(wait) = verify {
+        let exited = tokio::runtime::Handle::current().block_on(tokio::time::timeout(wait, child.wait()));
+        match exited {
+            Ok(Ok(status)) if !status.success() => {
+                return Err(AppError::BrowserOpen(std::io::Error::other(format!(
+                    "{:?} exited with {}",
+                    command.as_std().get_program(),
+                    status
+                ))));
+            }
+            Ok(Err(e)) => return Err(AppError::BrowserOpen(e)),
+            Ok(Ok(_)) | Err(_) => {}
+        }
+    }
+    // The browser may keep running; tokio reaps it whenever it exits
+    Ok(())
+}
+
+/// Opens URLs by running the browser binary with flags `webbrowser` can't
+/// pass, such as a private window or a profile.
+#[derive(Debug)]
+pub struct DirectOpener {
+    binary: PathBuf,
+    args: Vec<String>,
+    verify: Option<Duration>,
+}
+
+impl UrlOpener for DirectOpener {
+    fn open(&self, url: &str) -> Result<(), AppError> {
+        spawn_checked(Command::new(&self.binary).args(&self.args).arg(url), self.verify)
+    }
+}
+
+/// Opens URLs by running the browser, or the system's URL handler, directly
+/// and checking its exit status, for `VERIFY_OPEN`. `webbrowser` reports
+/// success as soon as the process starts.
+#[derive(Debug)]
+pub struct VerifiedOpener {
+    binary: PathBuf,
+    args: &'static [&'static str],
+    wait: Duration,
+}
+
+impl VerifiedOpener {
+    /// Runs the binary at `binary` if given, else the one for `browser`, else
+    /// the system's default handler.
+    pub fn new(browser: Browser, binary: Option<&Path>, wait: Duration) -> Self {
+        let (binary, args) = match (binary, known_binary(browser)) {
+            (Some(binary), _) => (binary.to_path_buf(), &[][..]),
+            (None, Some((name, _))) => (PathBuf::from(name), &[][..]),
+            (None, None) => {
+                let (name, args) = system_handler();
+                (PathBuf::from(name), args)
+            }
+        };
+        VerifiedOpener { binary, args, wait }
+    }
+}
+
+impl UrlOpener for VerifiedOpener {
+    fn open(&self, url: &str) -> Result<(), AppError> {
+        spawn_checked(Command::new(&self.binary).args(self.args).arg(url), Some(self.wait))
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn system_handler() -> (&'static str, &'static [&'static str]) {
+    ("open", &[])
+}
+
+#[cfg(windows)]
+fn system_handler() -> (&'static str, &'static [&'static str]) {
+    ("rundll32", &["url.dll,FileProtocolHandler"])
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn system_handler() -> (&'static str, &'static [&'static str]) {
+    ("xdg-open", &[])
+}
+
+/// Binary and private browsing flag of the browsers that are run directly.
+fn known_binary(browser: Browser) -> Option<(&'static str, &'static str)> {
+    match browser {
+        Browser::Firefox => Some(("firefox", "--private-window")),
+        Browser::Chrome => Some(("google-chrome", "--incognito")),
+        Browser::Opera => Some(("opera", "--private")),
+        _ => None,
+    }
+}
+
+/// Opener for `INCOGNITO` and `BROWSER_PROFILE`, running the browser with the
+/// flags known for `browser`, or for the binary at `binary`. A setting with no
+/// known flag is warned about and left out, and with neither left URLs open
+/// normally. With `verify`, the launch is checked as `VERIFY_OPEN` does, in
+/// that fallback too.
+pub fn direct_opener(
+    browser: Browser,
+    binary: Option<&Path>,
+    incognito: bool,
+    profile: Option<&str>,
+    verify: Option<Duration>,
+) -> Arc<dyn UrlOpener> {
+    let known = known_binary(browser);
+    let mut args = Vec::new();
+    if incognito {
+        match known.map(|(_, flag)| flag).or_else(|| binary.and_then(flag_for_binary)) {
+            Some(flag) => args.push(flag.to_string()),
+            None => warn!(
+                "No private window flag known for {:?}, opening URLs normally; set BROWSER or BROWSER_PATH to firefox, chrome or opera",
+                browser
+            ),
+        }
+    }
+    if let Some(profile) = profile {
+        match profile_args(browser, binary, profile) {
+            Some(profile_args) => args.extend(profile_args),
+            None => warn!(
+                "No profile flag known for {:?}, opening URLs in the default profile; set BROWSER or BROWSER_PATH to firefox or a Chromium-based browser",
+                browser
+            ),
+        }
+    }
+    let path = binary
+        .map(Path::to_path_buf)
+        .or_else(|| known.map(|(name, _)| PathBuf::from(name)));
+    match (path, verify) {
+        (Some(binary), _) if !args.is_empty() => Arc::new(DirectOpener { binary, args, verify }),
+        (_, Some(wait)) => Arc::new(VerifiedOpener::new(browser, binary, wait)),
+        (_, None) => Arc::new(WebBrowserOpener::new(browser)),
+    }
+}
+
+/// Arguments selecting `profile`: `-P <profile>` for Firefox and
+/// `--profile-directory=<profile>` for Chromium-based browsers.
+fn profile_args(browser: Browser, binary: Option<&Path>, profile: &str) -> Option<Vec<String>> {
+    let firefox = || vec!["-P".to_string(), profile.to_string()];
+    let chromium = || vec![format!("--profile-directory={}", profile)];
+    match browser {
+        Browser::Firefox => Some(firefox()),
+        Browser::Chrome => Some(chromium()),
+        _ => {
+            let name = binary?.file_stem()?.to_str()?.to_lowercase();
+            if name.contains("firefox") {
+                Some(firefox())
+            } else if ["chrom", "brave", "vivaldi", "edge"].iter().any(|known| name.contains(known)) {
+                Some(chromium())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Private browsing flag for a browser binary, recognised by its file name.
+fn flag_for_binary(binary: &Path) -> Option<&'static str> {
+    let name = binary.file_stem()?.to_str()?.to_lowercase();
+    if name.contains("firefox") {
+        Some("--private-window")
+    } else if name.contains("chrom") || name.contains("brave") || name.contains("vivaldi") {
+        Some("--incognito")
+    } else if name.contains("edge") {
+        Some("--inprivate")
+    } else if name.contains("opera") {
+        Some("--private")
+    } else {
+        None
+    }
+}
+
+/// What an `OPEN_WITH` rule applies to: links whose path ends in a file
+/// extension, or links with a given scheme.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkKind {
+    Extension(String),
+    Scheme(String),
+}
+
+impl LinkKind {
+    /// Parses an `OPEN_WITH` key: `gs:` names a scheme, `pdf` or `.pdf` an
+    /// extension.
+    pub fn parse(key: &str) -> Result<Self, String> {
+        let key = key.trim().to_lowercase();
+        let invalid = || format!("Invalid OPEN_WITH key: {}", key);
+        let kind = match key.strip_suffix(':') {
+            Some(scheme) => LinkKind::Scheme(scheme.to_string()),
+            None => LinkKind::Extension(key.trim_start_matches('.').to_string()),
+        };
+        match &kind {
+            LinkKind::Scheme(name) | LinkKind::Extension(name)
+                if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) =>
+            {
+                Ok(kind)
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn matches(&self, url: &Url) -> bool {
+        match self {
+            LinkKind::Scheme(scheme) => url.scheme() == scheme,
+            LinkKind::Extension(extension) => url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .and_then(|file_name| file_name.rsplit_once('.'))
+                .is_some_and(|(_, found)| found.eq_ignore_ascii_case(extension)),
+        }
+    }
+}
+
+impl std::fmt::Display for LinkKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkKind::Extension(extension) => write!(f, ".{}", extension),
+            LinkKind::Scheme(scheme) => write!(f, "{}:", scheme),
+        }
+    }
+}
+
+/// Opens URLs with a command template from OPEN_WITH or OPEN_COMMAND, such as
+/// `evince {url}`. The template is split into arguments up front and run
+/// without a shell, so nothing in a URL can be read as shell syntax.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandOpener {
+    argv: Vec<String>,
+    /// How long to watch the command's exit status, as with VERIFY_OPEN.
+    verify: Option<Duration>,
+}
+
+impl CommandOpener {
+    /// Parses the template given in `setting`, named in errors.
+    pub fn parse(setting: &str, template: &str) -> Result<Self, String> {
+        let argv: Vec<String> = template.split_whitespace().map(str::to_string).collect();
+        let invalid = |reason| format!("Invalid command {:?} in {}: {}", template, setting, reason);
+        match argv.split_first() {
+            None => Err(invalid("empty command")),
+            Some((program, _)) if program.contains("{url}") => Err(invalid("the URL can't be the program")),
+            Some((_, args)) if !args.iter().any(|arg| arg.contains("{url}")) => {
+                Err(invalid("no {url} placeholder"))
+            }
+            Some(_) => Ok(CommandOpener { argv, verify: None }),
+        }
+    }
+
+    /// Checks the command's exit status as `VERIFY_OPEN` does, with `verify`.
+    pub fn verified(self, verify: Option<Duration>) -> Self {
+        CommandOpener { verify, ..self }
+    }
+
+    fn args<'a>(&'a self, url: &'a str) -> impl Iterator<Item = String> + 'a {
+        self.argv[1..].iter().map(move |arg| arg.replace("{url}", url))
+    }
+}
+
+impl std::fmt::Display for CommandOpener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.argv.join(" "))
+    }
+}
+
+impl UrlOpener for CommandOpener {
+    fn open(&self, url: &str) -> Result<(), AppError> {
+        spawn_checked(Command::new(&self.argv[0]).args(self.args(url)), self.verify)
+    }
+}
+
+/// Prints URLs to stdout instead of opening them, for `replay`.
+#[derive(Debug)]
+pub struct PrintOpener;
+
+impl UrlOpener for PrintOpener {
+    fn open(&self, url: &str) -> Result<(), AppError> {
+        println!("Would open: {}", url);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_window_flags_follow_the_binary_name() {
+        let flag = |path: &str| flag_for_binary(Path::new(path));
+        assert_eq!(flag("/usr/bin/firefox"), Some("--private-window"));
+        assert_eq!(flag("/usr/bin/chromium-browser"), Some("--incognito"));
+        assert_eq!(flag("C:/Program Files/Microsoft/Edge/msedge.exe"), Some("--inprivate"));
+        assert_eq!(flag("/usr/bin/lynx"), None);
+    }
+
+    #[test]
+    fn profile_flags_follow_the_browser() {
+        let args = |browser, binary: Option<&str>| profile_args(browser, binary.map(Path::new), "Profile 1");
+        assert_eq!(args(Browser::Chrome, None), Some(vec!["--profile-directory=Profile 1".to_string()]));
+        assert_eq!(args(Browser::Firefox, None), Some(vec!["-P".to_string(), "Profile 1".to_string()]));
+        assert_eq!(
+            args(Browser::Default, Some("/usr/bin/brave-browser")),
+            Some(vec!["--profile-directory=Profile 1".to_string()])
+        );
+        assert_eq!(args(Browser::Default, Some("/usr/bin/firefox-esr")), Some(vec!["-P".to_string(), "Profile 1".to_string()]));
+        assert_eq!(args(Browser::Safari, None), None);
+        assert_eq!(args(Browser::Default, Some("/usr/bin/opera")), None);
+    }
+
+    /// Opens `url` on the blocking pool of a runtime, as the worker does.
+    fn open(opener: Arc<dyn UrlOpener>, url: &str) -> Result<(), AppError> {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let url = url.to_string();
+        runtime.block_on(runtime.spawn_blocking(move || opener.open(&url))).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn verified_opens_fail_on_an_error_exit() {
+        let wait = Duration::from_secs(5);
+        let opener = |binary: &str| Arc::new(VerifiedOpener::new(Browser::Default, Some(Path::new(binary)), wait));
+        assert!(open(opener("true"), "https://example.com").is_ok());
+        assert!(open(opener("false"), "https://example.com").is_err());
+        assert!(open(opener("/nonexistent/browser"), "https://example.com").is_err());
+        // Still running when the wait is up
+        let opener = VerifiedOpener::new(Browser::Default, Some(Path::new("sleep")), Duration::from_millis(100));
+        assert!(open(Arc::new(opener), "2").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unsupported_browsers_fall_back_to_a_verified_open() {
+        let wait = Duration::from_secs(5);
+        // No private window or profile flag is known for these binaries
+        let incognito = direct_opener(Browser::Default, Some(Path::new("false")), true, None, Some(wait));
+        assert!(open(incognito, "https://example.com").is_err());
+        let profile = direct_opener(Browser::Default, Some(Path::new("true")), false, Some("Work"), Some(wait));
+        assert!(open(profile, "https://example.com").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_are_checked_with_verify_open() {
+        let command = |template: &str| CommandOpener::parse("OPEN_COMMAND", template).unwrap();
+        assert!(open(Arc::new(command("false {url}")), "https://example.com").is_ok());
+        let verified = |template: &str| Arc::new(command(template).verified(Some(Duration::from_secs(5))));
+        assert!(open(verified("false {url}"), "https://example.com").is_err());
+        assert!(open(verified("true {url}"), "https://example.com").is_ok());
+    }
+
+    #[test]
+    fn link_kinds_match_extensions_and_schemes() {
+        let url = |url| Url::parse(url).unwrap();
+        let pdf = LinkKind::parse(".PDF").unwrap();
+        assert!(pdf.matches(&url("https://example.com/papers/a.pdf")));
+        assert!(pdf.matches(&url("https://example.com/a.Pdf?download=1")));
+        assert!(!pdf.matches(&url("https://example.com/pdf")));
+        assert!(!pdf.matches(&url("https://example.com/a.pdf/")));
+        let gs = LinkKind::parse("gs:").unwrap();
+        assert!(gs.matches(&url("gs://bucket/report.csv")));
+        assert!(!gs.matches(&url("https://example.com/report.csv")));
+        assert!(LinkKind::parse("").is_err());
+        assert!(LinkKind::parse("p df").is_err());
+    }
+
+    #[test]
+    fn command_templates_are_split_without_a_shell() {
+        let opener = CommandOpener::parse("OPEN_WITH", "evince --page={url} {url}").unwrap();
+        let url = "https://example.com/a.pdf; rm -rf ~";
+        let args: Vec<String> = opener.args(url).collect();
+        assert_eq!(args, [format!("--page={}", url), url.to_string()]);
+        assert!(CommandOpener::parse("OPEN_WITH", "").is_err());
+        assert!(CommandOpener::parse("OPEN_WITH", "evince").is_err());
+        assert!(CommandOpener::parse("OPEN_WITH", "{url} --help").is_err());
+    }
+}
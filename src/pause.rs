@@ -0,0 +1,54 @@
+use log::info;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How often PAUSE_FILE is looked for.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Holds opens while PAUSE_FILE exists, so they can be suspended for a while,
+/// say during a presentation, by touching a file. Documents keep arriving and
+/// queue up, and are opened once the file is removed.
+#[derive(Debug)]
+pub struct PauseFile {
+    path: PathBuf,
+    paused: AtomicBool,
+}
+
+impl PauseFile {
+    pub fn new(path: PathBuf) -> Self {
+        PauseFile {
+            path,
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Looks for the file every second, logging when opening is paused and
+    /// resumed. Runs until aborted.
+    pub async fn watch(&self) {
+        loop {
+            self.check();
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Returns once the file doesn't exist.
+    pub async fn wait(&self) {
+        while self.check() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Whether the file exists, logging a change since the last look.
+    fn check(&self) -> bool {
+        let exists = self.path.exists();
+        if self.paused.swap(exists, Ordering::Relaxed) != exists {
+            if exists {
+                info!("PAUSE_FILE {} exists, holding URLs until it is removed", self.path.display());
+            } else {
+                info!("PAUSE_FILE {} removed, opening URLs again", self.path.display());
+            }
+        }
+        exists
+    }
+}
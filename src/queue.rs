@@ -0,0 +1,371 @@
+use crate::metrics::Metrics;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, Semaphore};
+
+/// Bounded hand-off from the listener callback to the worker that opens URLs,
+/// with a lane of its own for each collection. The worker takes from the
+/// lanes in turn, so a flood on one collection can't starve the others, and
+/// within a lane takes the greatest item first, by `T`'s ordering, and equal
+/// items in the order they were pushed, passing over items that have to wait. When a lane is full, `push` waits for
+/// room, which in turn holds up the listener instead of dropping events.
+#[derive(Debug)]
+pub struct UrlQueue<T> {
+    shared: Arc<Shared<T>>,
+    /// Room in each lane.
+    capacity: usize,
+    receiver_taken: Mutex<bool>,
+    /// Documents with a URL in the queue; removing one cancels its URL.
+    queued_docs: Mutex<HashSet<String>>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    /// Key and metrics label of each lane, by index.
+    lanes: Vec<(u32, String)>,
+    items: Mutex<Queued<T>>,
+    /// One permit per free slot, by lane.
+    room: Vec<Semaphore>,
+    pushed: Notify,
+}
+
+#[derive(Debug)]
+struct Queued<T> {
+    heaps: Vec<BinaryHeap<Entry<T>>>,
+    next_seq: u64,
+    /// Lane looked at first, the one after the lane last taken from.
+    next_lane: usize,
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    item: T,
+    seq: u64,
+}
+
+impl<T: Ord> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.item.cmp(&other.item).then_with(|| Reverse(self.seq).cmp(&Reverse(other.seq)))
+    }
+}
+
+impl<T: Ord> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Ord> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: Ord> Eq for Entry<T> {}
+
+/// The worker's end of a `UrlQueue`.
+#[derive(Debug)]
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Ord> QueueReceiver<T> {
+    /// Waits for an item and takes it as `try_recv` does, passing over items
+    /// for which `item_wait`, given the lane's key and the item, says to wait,
+    /// such as URLs at their rate limit. A waiting item doesn't hold up the
+    /// ones behind it.
+    pub async fn recv(&mut self, item_wait: impl Fn(u32, &T) -> Duration) -> Option<T> {
+        let shared = self.shared.clone();
+        loop {
+            let pushed = shared.pushed.notified();
+            match self.take(&item_wait) {
+                Ok(item) => return Some(item),
+                Err(Some(wait)) => {
+                    tokio::select! {
+                        _ = pushed => {}
+                        _ = tokio::time::sleep(wait) => {}
+                    }
+                }
+                Err(None) => pushed.await,
+            }
+        }
+    }
+
+    /// Takes the greatest item of the next lane with any queued, if there is
+    /// one.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.take(|_, _| Duration::ZERO).ok()
+    }
+
+    /// Takes from the lanes in turn, starting after the lane last taken from,
+    /// the greatest item of the lane that needn't wait. Fails with the
+    /// shortest wait if every queued item has to wait, or with `None` if
+    /// nothing is queued.
+    fn take(&mut self, item_wait: impl Fn(u32, &T) -> Duration) -> Result<T, Option<Duration>> {
+        let (lane, entry) = {
+            let mut items = self.shared.items.lock().unwrap();
+            let count = items.heaps.len();
+            let mut shortest_wait = None;
+            let mut ready = None;
+            for offset in 0..count {
+                let lane = (items.next_lane + offset) % count;
+                let key = self.shared.lanes[lane].0;
+                let mut passed_over = Vec::new();
+                while let Some(entry) = items.heaps[lane].pop() {
+                    let wait = item_wait(key, &entry.item);
+                    if wait.is_zero() {
+                        ready = Some((lane, entry));
+                        break;
+                    }
+                    shortest_wait = Some(shortest_wait.map_or(wait, |shortest: Duration| shortest.min(wait)));
+                    passed_over.push(entry);
+                }
+                // Pushed back with their own `seq`, so they keep their place
+                items.heaps[lane].extend(passed_over);
+                if ready.is_some() {
+                    items.next_lane = (lane + 1) % count;
+                    break;
+                }
+            }
+            ready.ok_or(shortest_wait)?
+        };
+        self.shared.room[lane].add_permits(1);
+        Ok(entry.item)
+    }
+}
+
+impl<T: Ord> UrlQueue<T> {
+    /// Creates a queue with one lane of `capacity` items per `(key, label)`
+    /// of `lanes`; the label names the lane's depth in the metrics.
+    pub fn new(lanes: Vec<(u32, String)>, capacity: usize, metrics: Arc<Metrics>) -> Self {
+        UrlQueue {
+            shared: Arc::new(Shared {
+                items: Mutex::new(Queued {
+                    heaps: lanes.iter().map(|_| BinaryHeap::new()).collect(),
+                    next_seq: 0,
+                    next_lane: 0,
+                }),
+                room: lanes.iter().map(|_| Semaphore::new(capacity)).collect(),
+                lanes,
+                pushed: Notify::new(),
+            }),
+            capacity,
+            receiver_taken: Mutex::new(false),
+            queued_docs: Mutex::new(HashSet::new()),
+            metrics,
+        }
+    }
+
+    /// Hands out the receiving end, once, to the worker.
+    pub fn take_receiver(&self) -> Option<QueueReceiver<T>> {
+        let mut taken = self.receiver_taken.lock().unwrap();
+        if *taken {
+            return None;
+        }
+        *taken = true;
+        Some(QueueReceiver {
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Queues `item` in the lane keyed `lane`, waiting while that lane is
+    /// full. Returns `false` without queueing if the document already has a
+    /// URL in the queue, or there is no such lane.
+    pub async fn push(&self, lane: u32, doc_id: Option<&str>, item: T) -> bool {
+        let Some(lane) = self.shared.lanes.iter().position(|(key, _)| *key == lane) else {
+            return false;
+        };
+        if let Some(doc_id) = doc_id
+            && !self.queued_docs.lock().unwrap().insert(doc_id.to_string())
+        {
+            return false;
+        }
+        // The semaphore is never closed, so acquiring only ever waits
+        if let Ok(permit) = self.shared.room[lane].acquire().await {
+            permit.forget();
+        }
+        {
+            let mut items = self.shared.items.lock().unwrap();
+            let seq = items.next_seq;
+            items.next_seq += 1;
+            items.heaps[lane].push(Entry { item, seq });
+        }
+        self.shared.pushed.notify_one();
+        self.report_depth();
+        true
+    }
+
+    /// Marks a received item as taken off the queue, returning `false` if its
+    /// document was deleted while it waited.
+    pub fn finish(&self, doc_id: Option<&str>) -> bool {
+        self.report_depth();
+        match doc_id {
+            Some(doc_id) => self.queued_docs.lock().unwrap().remove(doc_id),
+            None => true,
+        }
+    }
+
+    /// Cancels the queued URL of a deleted document, returning whether there
+    /// was one.
+    pub fn cancel(&self, doc_id: &str) -> bool {
+        self.queued_docs.lock().unwrap().remove(doc_id)
+    }
+
+    /// Number of items waiting in the queue, over all lanes.
+    pub fn depth(&self) -> usize {
+        (0..self.shared.lanes.len()).map(|lane| self.lane_depth(lane)).sum()
+    }
+
+    fn lane_depth(&self, lane: usize) -> usize {
+        self.capacity - self.shared.room[lane].available_permits()
+    }
+
+    fn report_depth(&self) {
+        self.metrics.set_queue_depth(self.depth());
+        for (lane, (_, label)) in self.shared.lanes.iter().enumerate() {
+            self.metrics.set_lane_queue_depth(label, self.lane_depth(lane));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lanes(keys: &[u32]) -> Vec<(u32, String)> {
+        keys.iter().map(|key| (*key, format!("lane_{}", key))).collect()
+    }
+
+    #[tokio::test]
+    async fn tracks_depth_and_cancellations() {
+        let metrics = Arc::new(Metrics::default());
+        let queue = UrlQueue::new(lanes(&[7]), 4, metrics.clone());
+        let mut receiver = queue.take_receiver().unwrap();
+        assert!(queue.take_receiver().is_none());
+        assert!(queue.push(7, Some("a"), 1).await);
+        assert!(!queue.push(7, Some("a"), 2).await);
+        assert!(queue.push(7, Some("b"), 1).await);
+        assert!(queue.push(7, None, 1).await);
+        assert!(!queue.push(8, None, 1).await);
+        assert_eq!(queue.depth(), 3);
+        assert!(metrics.render().contains("collection_queue_depth{collection=\"lane_7\"} 3\n"));
+
+        assert!(queue.cancel("b"));
+        assert_eq!(receiver.recv(|_, _| Duration::ZERO).await, Some(1));
+        assert!(queue.finish(Some("a")));
+        assert_eq!(receiver.recv(|_, _| Duration::ZERO).await, Some(1));
+        assert!(!queue.finish(Some("b")));
+        assert_eq!(receiver.recv(|_, _| Duration::ZERO).await, Some(1));
+        assert!(queue.finish(None));
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn greatest_items_are_taken_first() {
+        /// Ordered by priority alone, so items can tie.
+        #[derive(Debug)]
+        struct Item(i32, &'static str);
+
+        impl Ord for Item {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.0.cmp(&other.0)
+            }
+        }
+
+        impl PartialOrd for Item {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl PartialEq for Item {
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+
+        impl Eq for Item {}
+
+        let queue = UrlQueue::new(lanes(&[7]), 8, Arc::new(Metrics::default()));
+        let mut receiver = queue.take_receiver().unwrap();
+        for (priority, name) in [(0, "a"), (5, "b"), (0, "c"), (5, "d"), (-1, "e")] {
+            queue.push(7, None, Item(priority, name)).await;
+        }
+        let mut taken = Vec::new();
+        while let Some(Item(_, name)) = receiver.try_recv() {
+            taken.push(name);
+        }
+        assert_eq!(taken, ["b", "d", "a", "c", "e"]);
+    }
+
+    #[tokio::test]
+    async fn full_queues_wait_for_room() {
+        let queue = Arc::new(UrlQueue::new(lanes(&[7, 8]), 1, Arc::new(Metrics::default())));
+        let mut receiver = queue.take_receiver().unwrap();
+        queue.push(7, None, 1).await;
+        let pusher = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(7, None, 2).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!pusher.is_finished());
+        assert_eq!(receiver.recv(|_, _| Duration::ZERO).await, Some(1));
+        assert!(pusher.await.unwrap());
+        assert_eq!(receiver.recv(|_, _| Duration::ZERO).await, Some(2));
+
+        // A full lane leaves the others room
+        queue.push(7, None, 1).await;
+        assert!(queue.push(8, None, 3).await);
+    }
+
+    #[tokio::test]
+    async fn lanes_are_taken_from_in_turn() {
+        let queue = UrlQueue::new(lanes(&[7, 8, 9]), 8, Arc::new(Metrics::default()));
+        let mut receiver = queue.take_receiver().unwrap();
+        for item in [10, 11, 12, 13] {
+            queue.push(7, None, item).await;
+        }
+        queue.push(9, None, 90).await;
+        queue.push(8, None, 80).await;
+        let mut taken = Vec::new();
+        while let Some(item) = receiver.try_recv() {
+            taken.push(item);
+        }
+        assert_eq!(taken, [13, 80, 90, 12, 11, 10]);
+    }
+
+    #[tokio::test]
+    async fn waiting_lanes_are_passed_over() {
+        let queue = UrlQueue::new(lanes(&[7, 8]), 8, Arc::new(Metrics::default()));
+        let mut receiver = queue.take_receiver().unwrap();
+        queue.push(7, None, 1).await;
+        queue.push(7, None, 2).await;
+        queue.push(8, None, 3).await;
+        let busy_lane_wait = |lane, _: &i32| if lane == 7 { Duration::from_secs(60) } else { Duration::ZERO };
+        assert_eq!(receiver.recv(busy_lane_wait).await, Some(3));
+
+        // Taken once the lane's wait is over
+        let free_at = tokio::time::Instant::now() + Duration::from_millis(50);
+        let lane_wait = |_, _: &i32| free_at.saturating_duration_since(tokio::time::Instant::now());
+        assert_eq!(receiver.recv(lane_wait).await, Some(2));
+        assert!(tokio::time::Instant::now() >= free_at);
+    }
+
+    #[tokio::test]
+    async fn waiting_items_are_passed_over() {
+        let queue = UrlQueue::new(lanes(&[7]), 8, Arc::new(Metrics::default()));
+        let mut receiver = queue.take_receiver().unwrap();
+        for item in [1, 2, 3, 4] {
+            queue.push(7, None, item).await;
+        }
+        let odd_wait = |_, item: &i32| if item % 2 == 1 { Duration::from_secs(60) } else { Duration::ZERO };
+        assert_eq!(receiver.recv(odd_wait).await, Some(4));
+        assert_eq!(receiver.recv(odd_wait).await, Some(2));
+        // The items passed over are still there, in their order
+        assert_eq!(receiver.try_recv(), Some(3));
+        assert_eq!(receiver.try_recv(), Some(1));
+    }
+}
@@ -0,0 +1,110 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A queued document as saved to QUEUE_STATE_PATH.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SavedUrl {
+    pub target_id: u32,
+    pub doc_id: String,
+    /// The links left to open, after URL_FILTER_REGEX.
+    pub links: Vec<String>,
+}
+
+/// Keeps the documents in the queue, or taken off it but not yet handled, in
+/// a JSON file, so URLs still waiting when the process dies are queued again
+/// on the next start.
+#[derive(Debug)]
+pub struct QueueState {
+    path: PathBuf,
+    saved: Mutex<Vec<SavedUrl>>,
+}
+
+impl QueueState {
+    /// Loads what the last run left in `path`. A missing file starts empty.
+    pub fn load(path: PathBuf) -> Self {
+        let saved = read_state(&path).unwrap_or_else(|e| {
+            warn!("Failed to load queue state from {}: {}", path.display(), e);
+            Vec::new()
+        });
+        QueueState {
+            path,
+            saved: Mutex::new(saved),
+        }
+    }
+
+    /// The saved documents, oldest first.
+    pub fn saved(&self) -> Vec<SavedUrl> {
+        self.saved.lock().unwrap().clone()
+    }
+
+    /// Saves a newly queued document, replacing an earlier entry for it.
+    pub fn add(&self, url: SavedUrl) {
+        let mut saved = self.saved.lock().unwrap();
+        saved.retain(|other| (other.target_id, &other.doc_id) != (url.target_id, &url.doc_id));
+        saved.push(url);
+        self.save(&saved);
+    }
+
+    /// Forgets a document once it has been handled or dropped.
+    pub fn remove(&self, target_id: u32, doc_id: &str) {
+        let mut saved = self.saved.lock().unwrap();
+        let before = saved.len();
+        saved.retain(|other| (other.target_id, other.doc_id.as_str()) != (target_id, doc_id));
+        if saved.len() != before {
+            self.save(&saved);
+        }
+    }
+
+    fn save(&self, saved: &[SavedUrl]) {
+        if let Err(e) = write_state(&self.path, saved) {
+            warn!("Failed to save queue state to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn read_state(path: &Path) -> Result<Vec<SavedUrl>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Writes the state next to `path` and renames it over, so a crash mid-write
+/// never leaves a truncated file.
+fn write_state(path: &Path, saved: &[SavedUrl]) -> Result<(), String> {
+    let contents = serde_json::to_string(saved).map_err(|e| e.to_string())?;
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queued_documents_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("queue_state_{}.json", std::process::id()));
+        let saved = |doc_id: &str, link: &str| SavedUrl {
+            target_id: 42,
+            doc_id: doc_id.to_string(),
+            links: vec![link.to_string()],
+        };
+
+        let state = QueueState::load(path.clone());
+        assert!(state.saved().is_empty());
+        state.add(saved("a", "https://example.com/a"));
+        state.add(saved("b", "https://example.com/b"));
+        state.add(saved("a", "https://example.com/a2"));
+        state.remove(42, "b");
+        state.remove(7, "a");
+
+        let restarted = QueueState::load(path.clone());
+        assert_eq!(restarted.saved(), [saved("a", "https://example.com/a2")]);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,178 @@
+use log::info;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Sliding-window limiter for browser opens. Callers over the limit wait for
+/// capacity instead of being dropped, so URLs are opened in arrival order.
+#[derive(Debug)]
+pub struct RateLimiter {
+    max_opens: AtomicUsize,
+    window: Duration,
+    opens: Mutex<VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    /// A limit of zero disables throttling.
+    pub fn new(max_opens: usize, window: Duration) -> Self {
+        RateLimiter {
+            max_opens: AtomicUsize::new(max_opens),
+            window,
+            opens: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn per_minute(max_opens: usize) -> Self {
+        Self::new(max_opens, Duration::from_secs(60))
+    }
+
+    /// Changes the limit, as a config reload does; opens already in the window
+    /// count against the new one.
+    pub fn set_max_opens(&self, max_opens: usize) {
+        self.max_opens.store(max_opens, Ordering::Relaxed);
+    }
+
+    /// Waits until an open is allowed and records it.
+    pub async fn acquire(&self) {
+        loop {
+            match self.try_acquire_at(Instant::now()) {
+                Ok(()) => return,
+                Err(wait) => {
+                    info!("Rate limit reached, waiting {:?} before opening", wait);
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+    }
+
+    /// Records an open at `now` if under the limit, otherwise returns how long
+    /// until the oldest open leaves the window.
+    pub fn try_acquire_at(&self, now: Instant) -> Result<(), Duration> {
+        let max_opens = self.max_opens.load(Ordering::Relaxed);
+        if max_opens == 0 {
+            return Ok(());
+        }
+        let mut opens = self.opens.lock().unwrap();
+        self.wait_in(&mut opens, max_opens, now)?;
+        opens.push_back(now);
+        Ok(())
+    }
+
+    /// How long from `now` until an open would be allowed, without recording
+    /// one; zero if it would be allowed right away.
+    pub fn wait_at(&self, now: Instant) -> Duration {
+        let max_opens = self.max_opens.load(Ordering::Relaxed);
+        if max_opens == 0 {
+            return Duration::ZERO;
+        }
+        let mut opens = self.opens.lock().unwrap();
+        self.wait_in(&mut opens, max_opens, now).err().unwrap_or_default()
+    }
+
+    /// Forgets opens that left the window, then checks there's room for one
+    /// more.
+    fn wait_in(&self, opens: &mut VecDeque<Instant>, max_opens: usize, now: Instant) -> Result<(), Duration> {
+        while let Some(oldest) = opens.front() {
+            if now.duration_since(*oldest) >= self.window {
+                opens.pop_front();
+            } else {
+                break;
+            }
+        }
+        if opens.len() < max_opens {
+            return Ok(());
+        }
+        let oldest = opens.front().copied().unwrap_or(now);
+        Err(self.window - now.duration_since(oldest))
+    }
+}
+
+/// Spaces the starts of browser opens at least `delay` apart, so tabs from a
+/// burst of events don't all launch at once.
+#[derive(Debug)]
+pub struct OpenPacer {
+    delay: Duration,
+    last_open: tokio::sync::Mutex<Option<tokio::time::Instant>>,
+}
+
+impl OpenPacer {
+    pub fn new(delay: Duration) -> Self {
+        OpenPacer {
+            delay,
+            last_open: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Waits for this caller's turn, then runs `open` to completion. The next
+    /// caller's wait starts when `open` does, not when it finishes.
+    pub async fn run<F: Future>(&self, open: impl FnOnce() -> F) -> F::Output {
+        {
+            let mut last_open = self.last_open.lock().await;
+            if let Some(last) = *last_open {
+                tokio::time::sleep_until(last + self.delay).await;
+            }
+            *last_open = Some(tokio::time::Instant::now());
+        }
+        open().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_up_to_limit_within_window() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(limiter.try_acquire_at(start).is_ok());
+        assert!(limiter.try_acquire_at(start + Duration::from_secs(1)).is_ok());
+        assert_eq!(
+            limiter.try_acquire_at(start + Duration::from_secs(10)),
+            Err(Duration::from_secs(50))
+        );
+    }
+
+    #[test]
+    fn waits_are_checked_without_recording_an_open() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(limiter.wait_at(start), Duration::ZERO);
+        assert!(limiter.try_acquire_at(start).is_ok());
+        assert_eq!(limiter.wait_at(start + Duration::from_secs(20)), Duration::from_secs(40));
+        assert_eq!(limiter.wait_at(start + Duration::from_secs(60)), Duration::ZERO);
+        assert!(limiter.try_acquire_at(start + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn frees_capacity_after_window() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(limiter.try_acquire_at(start).is_ok());
+        assert!(limiter.try_acquire_at(start + Duration::from_secs(59)).is_err());
+        assert!(limiter.try_acquire_at(start + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn zero_limit_is_unlimited() {
+        let limiter = RateLimiter::new(0, Duration::from_secs(60));
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.try_acquire_at(now).is_ok());
+        }
+    }
+
+    #[test]
+    fn limit_can_change_while_running() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(limiter.try_acquire_at(start).is_ok());
+        assert!(limiter.try_acquire_at(start).is_err());
+        limiter.set_max_opens(2);
+        assert!(limiter.try_acquire_at(start).is_ok());
+        limiter.set_max_opens(0);
+        assert!(limiter.try_acquire_at(start).is_ok());
+    }
+}
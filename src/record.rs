@@ -0,0 +1,213 @@
+use crate::config::Config;
+use crate::error::AppError;
+use crate::logging::display_time;
+use crate::opener::PrintOpener;
+use crate::{AppState, handle_event, run_worker};
+use chrono::{DateTime, Utc};
+use firestore::{FirestoreDb, FirestoreListenEvent};
+use gcloud_sdk::google::firestore::v1::{DocumentChange, DocumentDelete};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// One listener event as written to `RECORD_EVENTS_PATH`, one per line.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RecordedEvent {
+    pub received_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: EventRecord,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventRecord {
+    /// A changed document, with its fields as JSON.
+    DocumentChange {
+        target_ids: Vec<i32>,
+        document: String,
+        fields: serde_json::Map<String, serde_json::Value>,
+    },
+    DocumentDelete { document: String },
+    /// Any other event, kept by name only; replaying skips it.
+    Other { event_type: String },
+}
+
+impl RecordedEvent {
+    pub fn from_event(event: &FirestoreListenEvent) -> Self {
+        let event = match event {
+            FirestoreListenEvent::DocumentChange(DocumentChange {
+                document: Some(doc),
+                target_ids,
+                ..
+            }) => {
+                let mut fields: serde_json::Map<String, serde_json::Value> =
+                    FirestoreDb::deserialize_doc_to(doc).unwrap_or_default();
+                // Metadata the deserializer adds, such as `_firestore_id`
+                fields.retain(|name, _| !name.starts_with("_firestore"));
+                EventRecord::DocumentChange {
+                    target_ids: target_ids.clone(),
+                    document: doc.name.clone(),
+                    fields,
+                }
+            }
+            FirestoreListenEvent::DocumentDelete(doc_delete) => EventRecord::DocumentDelete {
+                document: doc_delete.document.clone(),
+            },
+            event => EventRecord::Other {
+                event_type: crate::event_type(event).to_string(),
+            },
+        };
+        RecordedEvent {
+            received_at: Utc::now(),
+            event,
+        }
+    }
+
+    /// Rebuilds the listener event, or `None` for events kept by name only.
+    pub fn to_event(&self) -> Option<FirestoreListenEvent> {
+        match &self.event {
+            EventRecord::DocumentChange {
+                target_ids,
+                document,
+                fields,
+            } => {
+                let doc = FirestoreDb::serialize_to_doc(document.as_str(), fields).ok()?;
+                Some(FirestoreListenEvent::DocumentChange(DocumentChange {
+                    document: Some(doc),
+                    target_ids: target_ids.clone(),
+                    removed_target_ids: Vec::new(),
+                }))
+            }
+            EventRecord::DocumentDelete { document } => Some(FirestoreListenEvent::DocumentDelete(DocumentDelete {
+                document: document.clone(),
+                ..DocumentDelete::default()
+            })),
+            EventRecord::Other { .. } => None,
+        }
+    }
+}
+
+/// Appends listener events to a JSONL file. Lines are written on a thread of
+/// their own, so a slow disk never holds up the listener.
+#[derive(Debug)]
+pub struct EventRecorder {
+    lines: mpsc::Sender<String>,
+}
+
+impl EventRecorder {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let (lines, receiver) = mpsc::channel::<String>();
+        let path = path.to_path_buf();
+        std::thread::spawn(move || {
+            let mut file = LineWriter::new(file);
+            for line in receiver {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    warn!("Failed to record event to {}: {}", path.display(), e);
+                }
+            }
+        });
+        Ok(EventRecorder { lines })
+    }
+
+    pub fn record(&self, event: &FirestoreListenEvent) {
+        match serde_json::to_string(&RecordedEvent::from_event(event)) {
+            // The writer thread lives as long as the process
+            Ok(line) => {
+                self.lines.send(line).ok();
+            }
+            Err(e) => warn!("Failed to serialize event for recording: {}", e),
+        }
+    }
+}
+
+/// Feeds the events recorded in `path` through the listener's handlers. URLs
+/// are printed instead of opened, and no document or state file is written.
+pub async fn replay(path: &Path, mut config: Config) -> Result<(), AppError> {
+    let contents = std::fs::read_to_string(path).map_err(|source| AppError::EventLog {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    config.read_only = true;
+    config.notify = false;
+    config.dedup_state_path = None;
+    config.active_hours = None;
+    let state = Arc::new(AppState::with_opener(config, Arc::new(PrintOpener)));
+    let draining = CancellationToken::new();
+    let worker = state
+        .queue
+        .take_receiver()
+        .map(|receiver| tokio::spawn(run_worker(state.clone(), receiver, draining.clone())));
+
+    let mut replayed = 0;
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let recorded = match serde_json::from_str::<RecordedEvent>(line) {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                warn!("Skipping line {} of {}: {}", index + 1, path.display(), e);
+                continue;
+            }
+        };
+        match recorded.to_event() {
+            Some(event) => {
+                info!("Replaying event received at {}", display_time(recorded.received_at));
+                handle_event(&state, event).await;
+                replayed += 1;
+            }
+            None => info!("Skipping {:?} recorded at {}", recorded.event, display_time(recorded.received_at)),
+        }
+    }
+
+    draining.cancel();
+    if let Some(worker) = worker {
+        worker.await.ok();
+    }
+    state.open_tasks.close();
+    state.open_tasks.wait().await;
+    println!("Replayed {} events from {}", replayed, path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_changes_round_trip() {
+        let name = "projects/demo/databases/(default)/documents/shared_urls/abc";
+        let doc = FirestoreDb::serialize_to_doc(
+            name,
+            &serde_json::json!({ "url": "https://example.com", "timestamp": "2024-01-01T00:00:00+00:00" }),
+        )
+        .unwrap();
+        let event = FirestoreListenEvent::DocumentChange(DocumentChange {
+            document: Some(doc),
+            target_ids: vec![42],
+            removed_target_ids: Vec::new(),
+        });
+
+        let line = serde_json::to_string(&RecordedEvent::from_event(&event)).unwrap();
+        let recorded: RecordedEvent = serde_json::from_str(&line).unwrap();
+        let EventRecord::DocumentChange { target_ids, fields, .. } = &recorded.event else {
+            panic!("unexpected record: {:?}", recorded);
+        };
+        assert_eq!(target_ids, &[42]);
+        assert_eq!(fields.keys().collect::<Vec<_>>(), ["timestamp", "url"]);
+
+        let Some(FirestoreListenEvent::DocumentChange(change)) = recorded.to_event() else {
+            panic!("not replayed as a change");
+        };
+        let replayed = change.document.unwrap();
+        assert_eq!(replayed.name, name);
+        let shared_url = FirestoreDb::deserialize_doc_to::<crate::SharedUrl>(&replayed).unwrap();
+        assert_eq!(shared_url.url, "https://example.com");
+        assert_eq!(shared_url.doc_id.as_deref(), Some("abc"));
+    }
+}
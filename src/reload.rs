@@ -0,0 +1,110 @@
+use crate::config::Config;
+use chrono::Duration;
+
+/// Settings a SIGHUP reload can change while the listeners keep running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveSettings {
+    pub allowed_domains: Vec<String>,
+    pub expiration: Duration,
+    /// Open limit of each collection, in `Config::all_collections` order.
+    pub max_opens_per_minute: Vec<(String, usize)>,
+    pub log_level: Option<String>,
+}
+
+impl LiveSettings {
+    pub fn from_config(config: &Config) -> Self {
+        LiveSettings {
+            allowed_domains: config.allowed_domains.clone(),
+            expiration: config.expiration,
+            max_opens_per_minute: config
+                .all_collections()
+                .map(|(_, collection)| (collection.name.clone(), collection.max_opens_per_minute))
+                .collect(),
+            log_level: config.log_level.clone(),
+        }
+    }
+
+    /// Describes each setting that differs in `new`, for the reload log.
+    pub fn changes(&self, new: &LiveSettings) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.allowed_domains != new.allowed_domains {
+            changes.push(format!(
+                "ALLOWED_DOMAINS from [{}] to [{}]",
+                self.allowed_domains.join(", "),
+                new.allowed_domains.join(", ")
+            ));
+        }
+        if self.expiration != new.expiration {
+            changes.push(format!(
+                "expiration from {} to {} hours",
+                self.expiration.num_hours(),
+                new.expiration.num_hours()
+            ));
+        }
+        for ((name, old), (new_name, new)) in self.max_opens_per_minute.iter().zip(&new.max_opens_per_minute) {
+            if name == new_name && old != new {
+                changes.push(format!("MAX_OPENS_PER_MINUTE of {} from {} to {}", name, old, new));
+            }
+        }
+        if self.log_level != new.log_level {
+            let level = |level: &Option<String>| level.clone().unwrap_or_else(|| "info".to_string());
+            changes.push(format!("LOG_LEVEL from {} to {}", level(&self.log_level), level(&new.log_level)));
+        }
+        changes
+    }
+}
+
+/// Names the settings that differ between `old` and `new` but need new
+/// connections and listeners, so only take effect after a restart.
+pub fn restart_needed(old: &Config, new: &Config) -> Vec<&'static str> {
+    let targets = |config: &Config| -> Vec<(Option<String>, String, u32)> {
+        config
+            .all_collections()
+            .map(|(project, collection)| (project.map(str::to_string), collection.name.clone(), collection.target_id))
+            .collect()
+    };
+    let mut settings = Vec::new();
+    if old.project_id != new.project_id {
+        settings.push("PROJECT_ID");
+    }
+    if targets(old) != targets(new) {
+        settings.push("COLLECTIONS");
+    }
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CollectionConfig, ProjectConfig};
+
+    #[test]
+    fn describes_what_a_reload_changes() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.allowed_domains = vec!["example.com".to_string()];
+        new.collections[0].max_opens_per_minute = 5;
+        new.log_level = Some("debug".to_string());
+        let changes = LiveSettings::from_config(&old).changes(&LiveSettings::from_config(&new));
+        assert_eq!(
+            changes,
+            vec![
+                "ALLOWED_DOMAINS from [] to [example.com]".to_string(),
+                format!("MAX_OPENS_PER_MINUTE of {} from 0 to 5", old.collections[0].name),
+                "LOG_LEVEL from info to debug".to_string(),
+            ]
+        );
+        assert!(restart_needed(&old, &new).is_empty());
+
+        new.project_id = "other-project".to_string();
+        new.projects.push(ProjectConfig {
+            project_id: "third-project".to_string(),
+            collections: vec![CollectionConfig {
+                name: "links".to_string(),
+                target_id: 9,
+                max_opens_per_minute: 0,
+            }],
+        });
+        assert_eq!(restart_needed(&old, &new), vec!["PROJECT_ID", "COLLECTIONS"]);
+    }
+}
@@ -0,0 +1,24 @@
+use reqwest::Client;
+use std::time::Duration;
+
+/// Follows shortener redirects (`bit.ly`, `t.co`, ...) to the final URL with
+/// HEAD requests, so the destination is what gets logged and opened.
+#[derive(Debug)]
+pub struct RedirectResolver {
+    client: Client,
+    timeout: Duration,
+}
+
+impl RedirectResolver {
+    /// Follows as many hops as `client` does and gives up on a request after
+    /// `timeout`, so a misbehaving shortener can't hold up the worker.
+    pub fn new(client: Client, timeout: Duration) -> Self {
+        RedirectResolver { client, timeout }
+    }
+
+    /// Returns the URL the redirect chain starting at `url` ends at.
+    pub async fn resolve(&self, url: &str) -> Result<String, reqwest::Error> {
+        let response = self.client.head(url).timeout(self.timeout).send().await?;
+        Ok(response.url().to_string())
+    }
+}
@@ -0,0 +1,82 @@
+use chrono::{DateTime, Utc};
+use log::warn;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Remembers the newest `timestamp` of the documents processed so far in a
+/// small file, so the backlog replayed after a restart can skip older ones.
+#[derive(Debug)]
+pub struct LastProcessed {
+    path: PathBuf,
+    latest: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl LastProcessed {
+    /// Loads the timestamp saved at `path`. A missing file starts with none.
+    pub fn load(path: PathBuf) -> Self {
+        let latest = match std::fs::read_to_string(&path) {
+            Ok(contents) => match DateTime::parse_from_rfc3339(contents.trim()) {
+                Ok(latest) => Some(latest.with_timezone(&Utc)),
+                Err(e) => {
+                    warn!("Ignoring malformed resume state in {}: {}", path.display(), e);
+                    None
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("Failed to load resume state from {}: {}", path.display(), e);
+                None
+            }
+        };
+        LastProcessed {
+            path,
+            latest: Mutex::new(latest),
+        }
+    }
+
+    pub fn get(&self) -> Option<DateTime<Utc>> {
+        *self.latest.lock().unwrap()
+    }
+
+    /// Records a processed document's timestamp, saving it when it is the
+    /// newest so far.
+    pub fn record(&self, timestamp: DateTime<Utc>) {
+        let mut latest = self.latest.lock().unwrap();
+        if latest.is_some_and(|latest| latest >= timestamp) {
+            return;
+        }
+        *latest = Some(timestamp);
+        if let Err(e) = write_state(&self.path, timestamp) {
+            warn!("Failed to save resume state to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Writes next to `path` and renames over it, so a crash mid-write never
+/// leaves a truncated file.
+fn write_state(path: &Path, timestamp: DateTime<Utc>) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, timestamp.to_rfc3339())?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_timestamp_across_restarts() {
+        let path = std::env::temp_dir().join(format!("last_processed_{}", std::process::id()));
+        let earlier = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let later = earlier + chrono::Duration::minutes(5);
+
+        let last_processed = LastProcessed::load(path.clone());
+        assert_eq!(last_processed.get(), None);
+        last_processed.record(later);
+        last_processed.record(earlier);
+        assert_eq!(last_processed.get(), Some(later));
+        assert_eq!(LastProcessed::load(path.clone()).get(), Some(later));
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,26 @@
+use axum::Router;
+use log::{error, info};
+use std::net::SocketAddr;
+use tokio::sync::oneshot;
+
+/// Serves `app` on `port` until `shutdown` fires.
+pub async fn serve(port: u16, app: Router, shutdown: oneshot::Receiver<()>) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind HTTP endpoint on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Serving HTTP endpoints on http://{}", addr);
+
+    let result = axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            shutdown.await.ok();
+        })
+        .await;
+    if let Err(e) = result {
+        error!("HTTP endpoint on {} failed: {}", addr, e);
+    }
+}
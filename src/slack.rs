@@ -0,0 +1,112 @@
+use log::{debug, warn};
+use reqwest::Client;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Further attempts after a failed post, doubling the delay each time.
+const RETRIES: u32 = 2;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Payload posted for each opened URL. Slack shows `text`; the other fields
+/// are there for workflow webhooks that pick out variables.
+#[derive(Debug, Serialize)]
+pub struct OpenedMessage<'a> {
+    text: String,
+    url: &'a str,
+    title: &'a str,
+    host: &'a str,
+    instance_id: &'a str,
+}
+
+impl<'a> OpenedMessage<'a> {
+    pub fn new(url: &'a str, title: &'a str, host: &'a str, instance_id: &'a str) -> Self {
+        OpenedMessage {
+            text: format!("{} opened <{}|{}> ({})", instance_id, url, title, host),
+            url,
+            title,
+            host,
+            instance_id,
+        }
+    }
+}
+
+/// Posts opened URLs to `SLACK_WEBHOOK_URL`.
+#[derive(Debug, Clone)]
+pub struct SlackWebhook {
+    client: Client,
+    url: String,
+}
+
+impl SlackWebhook {
+    pub fn new(client: Client, url: String) -> Self {
+        SlackWebhook { client, url }
+    }
+
+    /// Posts `message`, retrying failures a couple of times. Errors are only
+    /// logged; the webhook URL is left out since it carries the credentials.
+    pub async fn post(&self, message: &OpenedMessage<'_>) {
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 0..=RETRIES {
+            let result = self
+                .client
+                .post(&self.url)
+                .timeout(POST_TIMEOUT)
+                .json(message)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => {
+                    debug!(url_host = message.host; "Posted opened URL to Slack");
+                    return;
+                }
+                Err(e) if attempt < RETRIES => {
+                    warn!(url_host = message.host; "Failed to post to Slack, retrying in {:?}: {}", delay, crate::http::describe_error(e));
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => warn!(url_host = message.host; "Failed to post to Slack, giving up: {}", crate::http::describe_error(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn retries_failed_posts() {
+        let received: Arc<Mutex<Vec<serde_json::Value>>> = Arc::default();
+        let app = axum::Router::new().route(
+            "/hook",
+            post({
+                let received = received.clone();
+                move |body: String| async move {
+                    let mut received = received.lock().unwrap();
+                    received.push(serde_json::from_str(&body).unwrap());
+                    // The first attempt fails
+                    if received.len() == 1 { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let webhook = SlackWebhook::new(Client::new(), format!("http://{}/hook", addr));
+        let message = OpenedMessage::new("https://example.com/page", "Example", "example.com", "kiosk-1");
+        webhook.post(&message).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1]["url"], "https://example.com/page");
+        assert_eq!(received[1]["host"], "example.com");
+        assert_eq!(received[1]["instance_id"], "kiosk-1");
+        assert_eq!(received[1]["text"], "kiosk-1 opened <https://example.com/page|Example> (example.com)");
+    }
+}
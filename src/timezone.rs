@@ -0,0 +1,552 @@
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc};
+use std::path::PathBuf;
+
+/// Where IANA zones are read from unless TZDIR says otherwise.
+const DEFAULT_TZDIR: &str = "/usr/share/zoneinfo";
+
+/// Time zone of a setting such as DISPLAY_TIMEZONE, for the times in log
+/// messages, or ACTIVE_TIMEZONE, for ACTIVE_HOURS. Values written to
+/// Firestore stay in UTC.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisplayZone {
+    Utc,
+    /// The system time zone.
+    Local,
+    Fixed(FixedOffset),
+    /// An IANA zone such as `Europe/Berlin`, following its daylight saving
+    /// rules.
+    Named(TzRules),
+}
+
+impl DisplayZone {
+    /// Parses the `setting` value `UTC`, `local`, a UTC offset such as
+    /// `+02:00`, or an IANA zone name, which is looked up in the system's tz
+    /// database. A blank value names no zone; callers treat the setting as
+    /// unset instead.
+    pub fn parse(setting: &str, value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        match value.to_lowercase().as_str() {
+            "" => Err(format!("Empty {} value", setting)),
+            "utc" | "z" | "etc/utc" => Ok(DisplayZone::Utc),
+            "local" => Ok(DisplayZone::Local),
+            _ => match value.parse::<FixedOffset>() {
+                Ok(offset) => Ok(DisplayZone::Fixed(offset)),
+                Err(_) => TzRules::load(setting, value).map(DisplayZone::Named),
+            },
+        }
+    }
+
+    /// Offset from UTC in effect in the zone at `at`.
+    pub fn offset_at(&self, at: DateTime<Utc>) -> FixedOffset {
+        let utc = FixedOffset::east_opt(0).unwrap();
+        match self {
+            DisplayZone::Utc => utc,
+            DisplayZone::Local => Local.offset_from_utc_datetime(&at.naive_utc()).fix(),
+            DisplayZone::Fixed(offset) => *offset,
+            DisplayZone::Named(rules) => FixedOffset::east_opt(rules.local_type_at(at.timestamp()).offset).unwrap_or(utc),
+        }
+    }
+
+    /// Wall-clock time in the zone at `at`.
+    pub fn wall_clock(&self, at: DateTime<Utc>) -> NaiveDateTime {
+        at.with_timezone(&self.offset_at(at)).naive_local()
+    }
+
+    /// The moment the zone's clocks show `local`. Times skipped when the
+    /// clocks go forward come out an hour off, and repeated ones pick one of
+    /// the two.
+    pub fn to_utc(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        // Guessed with the offset of a moment near it, then corrected with
+        // the offset of the guess
+        let guess = local.and_utc() - self.offset_at(local.and_utc());
+        local.and_utc() - self.offset_at(guess)
+    }
+
+    /// Formats `at` as wall-clock time in the zone, followed by the zone's
+    /// abbreviation or UTC offset.
+    pub fn format(&self, at: DateTime<Utc>) -> String {
+        let wall_clock = self.wall_clock(at).format("%Y-%m-%d %H:%M:%S");
+        match self {
+            DisplayZone::Utc => format!("{} UTC", wall_clock),
+            DisplayZone::Local | DisplayZone::Fixed(_) => format!("{} {}", wall_clock, self.offset_at(at)),
+            DisplayZone::Named(rules) => format!("{} {}", wall_clock, rules.local_type_at(at.timestamp()).abbreviation),
+        }
+    }
+}
+
+impl std::fmt::Display for DisplayZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisplayZone::Utc => write!(f, "UTC"),
+            DisplayZone::Local => write!(f, "local time"),
+            DisplayZone::Fixed(offset) => write!(f, "UTC{}", offset),
+            DisplayZone::Named(rules) => write!(f, "{}", rules.name),
+        }
+    }
+}
+
+/// Offset from UTC in effect for a stretch of time, with its abbreviation.
+#[derive(Debug, Clone, PartialEq)]
+struct LocalType {
+    /// Seconds east of UTC.
+    offset: i32,
+    abbreviation: String,
+}
+
+/// The rules of an IANA zone, from its TZif file (RFC 8536).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TzRules {
+    /// IANA name of the zone.
+    name: String,
+    /// Unix times at which the offset changes, each with the index of the
+    /// local type it changes to.
+    transitions: Vec<(i64, usize)>,
+    types: Vec<LocalType>,
+    /// Rule for times after the last transition.
+    footer: Option<PosixTz>,
+}
+
+impl TzRules {
+    fn load(setting: &str, name: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("Unknown {} {:?}: {}", setting, name, reason);
+        if name.starts_with('/') || name.split('/').any(|part| part.is_empty() || part == "..") {
+            return Err(invalid("not a time zone name"));
+        }
+        let dir = std::env::var_os("TZDIR").map_or_else(|| PathBuf::from(DEFAULT_TZDIR), PathBuf::from);
+        let data = std::fs::read(dir.join(name)).map_err(|e| invalid(&e.to_string()))?;
+        parse_tzif(name, &data).ok_or_else(|| invalid("not a TZif file"))
+    }
+
+    fn local_type_at(&self, at: i64) -> LocalType {
+        if let (Some(footer), Some((last, _))) = (&self.footer, self.transitions.last())
+            && at >= *last
+        {
+            return footer.local_type_at(at);
+        }
+        // Before the first transition, the first type applies
+        let index = match self.transitions.partition_point(|(transition, _)| *transition <= at) {
+            0 => 0,
+            n => self.transitions[n - 1].1,
+        };
+        self.types[index].clone()
+    }
+}
+
+/// Counts from a TZif header.
+struct Header {
+    version: u8,
+    isutcnt: usize,
+    isstdcnt: usize,
+    leapcnt: usize,
+    timecnt: usize,
+    typecnt: usize,
+    charcnt: usize,
+}
+
+impl Header {
+    fn read(reader: &mut Reader) -> Option<Self> {
+        if reader.take(4)? != b"TZif" {
+            return None;
+        }
+        let version = reader.take(1)?[0];
+        reader.take(15)?;
+        let mut count = || reader.u32().map(|count| count as usize);
+        Some(Header {
+            version,
+            isutcnt: count()?,
+            isstdcnt: count()?,
+            leapcnt: count()?,
+            timecnt: count()?,
+            typecnt: count()?,
+            charcnt: count()?,
+        })
+    }
+
+    /// Length of the data block following the header, with times
+    /// `time_size` bytes long, or `None` if the counts overflow it.
+    fn data_len(&self, time_size: usize) -> Option<usize> {
+        [
+            (self.timecnt, time_size + 1),
+            (self.typecnt, 6),
+            (self.charcnt, 1),
+            (self.leapcnt, time_size + 4),
+            (self.isstdcnt, 1),
+            (self.isutcnt, 1),
+        ]
+        .into_iter()
+        .try_fold(0usize, |len, (count, size)| len.checked_add(count.checked_mul(size)?))
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        Some(i64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+/// Reads a TZif file. Version 1 files have 32-bit times only; later ones
+/// repeat the data with 64-bit times, followed by a TZ string footer.
+fn parse_tzif(name: &str, data: &[u8]) -> Option<TzRules> {
+    let mut reader = Reader(data);
+    let mut header = Header::read(&mut reader)?;
+    let time_size = if header.version >= b'2' {
+        reader.take(header.data_len(4)?)?;
+        header = Header::read(&mut reader)?;
+        8
+    } else {
+        4
+    };
+    if header.typecnt == 0 {
+        return None;
+    }
+    // Taken whole first, so the counts of a corrupt header can't ask for
+    // more than the file holds
+    let mut block = Reader(reader.take(header.data_len(time_size)?)?);
+    let mut times = Vec::with_capacity(header.timecnt);
+    for _ in 0..header.timecnt {
+        times.push(match time_size {
+            8 => block.i64()?,
+            _ => block.u32()? as i32 as i64,
+        });
+    }
+    let indices = block.take(header.timecnt)?;
+    let raw_types = block.take(header.typecnt * 6)?;
+    let chars = block.take(header.charcnt)?;
+
+    let types = raw_types
+        .chunks(6)
+        .map(|raw| {
+            let abbreviation = chars.get(raw[5] as usize..)?;
+            let end = abbreviation.iter().position(|byte| *byte == 0).unwrap_or(abbreviation.len());
+            Some(LocalType {
+                offset: i32::from_be_bytes(raw[..4].try_into().ok()?),
+                abbreviation: String::from_utf8_lossy(&abbreviation[..end]).into_owned(),
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let transitions = times
+        .into_iter()
+        .zip(indices.iter().map(|index| *index as usize))
+        .map(|(at, index)| (index < types.len()).then_some((at, index)))
+        .collect::<Option<Vec<_>>>()?;
+    let footer = match time_size {
+        8 => std::str::from_utf8(reader.0)
+            .ok()
+            .map(|footer| footer.trim_matches('\n'))
+            .filter(|footer| !footer.is_empty())
+            .and_then(PosixTz::parse),
+        _ => None,
+    };
+    Some(TzRules {
+        name: name.to_string(),
+        transitions,
+        types,
+        footer,
+    })
+}
+
+/// A POSIX TZ string such as `CET-1CEST,M3.5.0,M10.5.0/3`, which TZif files
+/// end with to cover times past their last transition.
+#[derive(Debug, Clone, PartialEq)]
+struct PosixTz {
+    standard: LocalType,
+    /// Daylight saving time, with the rules for when it starts and ends.
+    daylight: Option<(LocalType, Rule, Rule)>,
+}
+
+/// When a change happens: a day of the year and a local time on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rule {
+    day: RuleDay,
+    /// Seconds after local midnight; may be negative or past a day.
+    time: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RuleDay {
+    /// `Jn`: day 1 to 365, never counting February 29.
+    Julian(u16),
+    /// `n`: day 0 to 365, counting February 29 in leap years.
+    Ordinal(u16),
+    /// `Mm.w.d`: weekday `d` (0 is Sunday) of week `w` of month `m`, where
+    /// week 5 is the last.
+    MonthWeekDay(u32, u32, u32),
+}
+
+impl PosixTz {
+    fn parse(value: &str) -> Option<Self> {
+        let mut rest = value;
+        let standard_name = take_name(&mut rest)?;
+        let standard = LocalType {
+            offset: -take_time(&mut rest)? as i32,
+            abbreviation: standard_name,
+        };
+        if rest.is_empty() {
+            return Some(PosixTz { standard, daylight: None });
+        }
+        let daylight_name = take_name(&mut rest)?;
+        let daylight_offset = if rest.starts_with(',') {
+            standard.offset + 3600
+        } else {
+            -take_time(&mut rest)? as i32
+        };
+        let daylight = LocalType {
+            offset: daylight_offset,
+            abbreviation: daylight_name,
+        };
+        let (start, end) = match rest.strip_prefix(',') {
+            Some(rules) => {
+                let (start, end) = rules.split_once(',')?;
+                (Rule::parse(start)?, Rule::parse(end)?)
+            }
+            // POSIX leaves the default open; this is the US one, as glibc uses
+            None => (Rule::parse("M3.2.0")?, Rule::parse("M11.1.0")?),
+        };
+        Some(PosixTz {
+            standard,
+            daylight: Some((daylight, start, end)),
+        })
+    }
+
+    fn local_type_at(&self, at: i64) -> LocalType {
+        let Some((daylight, start, end)) = &self.daylight else {
+            return self.standard.clone();
+        };
+        let Some(year) = DateTime::from_timestamp(at + self.standard.offset as i64, 0).map(|local| local.year()) else {
+            return self.standard.clone();
+        };
+        // The start is given in standard time and the end in daylight time
+        let (Some(start), Some(end)) = (start.local_seconds(year), end.local_seconds(year)) else {
+            return self.standard.clone();
+        };
+        let start = start - self.standard.offset as i64;
+        let end = end - daylight.offset as i64;
+        let in_daylight = if start < end {
+            start <= at && at < end
+        } else {
+            // Southern hemisphere: daylight time spans the new year
+            !(end <= at && at < start)
+        };
+        if in_daylight { daylight.clone() } else { self.standard.clone() }
+    }
+}
+
+impl Rule {
+    fn parse(value: &str) -> Option<Self> {
+        let (day, time) = match value.split_once('/') {
+            Some((day, time)) => {
+                let mut time = time;
+                let seconds = take_time(&mut time)?;
+                if !time.is_empty() {
+                    return None;
+                }
+                (day, seconds)
+            }
+            None => (value, 2 * 3600),
+        };
+        let day = if let Some(month_week_day) = day.strip_prefix('M') {
+            let mut parts = month_week_day.split('.').map(|part| part.parse::<u32>().ok());
+            let (month, week, weekday) = (parts.next()??, parts.next()??, parts.next()??);
+            if parts.next().is_some() || !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                return None;
+            }
+            RuleDay::MonthWeekDay(month, week, weekday)
+        } else if let Some(julian) = day.strip_prefix('J') {
+            RuleDay::Julian(julian.parse().ok().filter(|day| (1..=365).contains(day))?)
+        } else {
+            RuleDay::Ordinal(day.parse().ok().filter(|day| *day <= 365)?)
+        };
+        Some(Rule { day, time })
+    }
+
+    /// The rule's moment in `year`, in seconds since the epoch as if local
+    /// time were UTC.
+    fn local_seconds(&self, year: i32) -> Option<i64> {
+        let january_first = NaiveDate::from_ymd_opt(year, 1, 1)?;
+        let date = match self.day {
+            RuleDay::Julian(day) => {
+                let leap_day = january_first.leap_year() && day >= 60;
+                january_first + chrono::Days::new(day as u64 - 1 + leap_day as u64)
+            }
+            RuleDay::Ordinal(day) => january_first + chrono::Days::new(day as u64),
+            RuleDay::MonthWeekDay(month, week, weekday) => {
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let first_weekday = first.weekday().num_days_from_sunday();
+                let mut day = 1 + (weekday + 7 - first_weekday) % 7 + (week - 1) * 7;
+                while NaiveDate::from_ymd_opt(year, month, day).is_none() {
+                    day -= 7;
+                }
+                NaiveDate::from_ymd_opt(year, month, day)?
+            }
+        };
+        Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp() + self.time)
+    }
+}
+
+/// Takes a zone abbreviation: letters, or anything between `<` and `>`.
+fn take_name(rest: &mut &str) -> Option<String> {
+    let (name, after) = match rest.strip_prefix('<') {
+        Some(quoted) => {
+            let (name, after) = quoted.split_once('>')?;
+            (name, after)
+        }
+        None => {
+            let end = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+            rest.split_at(end)
+        }
+    };
+    if name.len() < 3 {
+        return None;
+    }
+    *rest = after;
+    Some(name.to_string())
+}
+
+/// Takes a signed `hh[:mm[:ss]]`, returning it in seconds.
+fn take_time(rest: &mut &str) -> Option<i64> {
+    let (sign, unsigned) = match rest.as_bytes().first()? {
+        b'-' => (-1, &rest[1..]),
+        b'+' => (1, &rest[1..]),
+        _ => (1, *rest),
+    };
+    let end = unsigned
+        .find(|c: char| !c.is_ascii_digit() && c != ':')
+        .unwrap_or(unsigned.len());
+    let (time, after) = unsigned.split_at(end);
+    let mut seconds = 0;
+    for (i, part) in time.split(':').enumerate() {
+        if i > 2 {
+            return None;
+        }
+        let value: i64 = part.parse().ok()?;
+        seconds += value * [3600, 60, 1][i];
+    }
+    *rest = after;
+    Some(sign * seconds)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// A version 2 TZif file with one transition, to `types[0]` at the epoch,
+    /// and the given types and footer TZ string.
+    fn tzif(types: &[(i32, &str)], footer: &str) -> Vec<u8> {
+        let header = |timecnt: u32, typecnt: u32, charcnt: u32| {
+            let mut header = b"TZif2".to_vec();
+            header.extend([0; 15]);
+            for count in [0, 0, 0, timecnt, typecnt, charcnt] {
+                header.extend(count.to_be_bytes());
+            }
+            header
+        };
+        let mut chars = Vec::new();
+        let mut raw_types = Vec::new();
+        for (offset, abbreviation) in types {
+            raw_types.extend(offset.to_be_bytes());
+            raw_types.extend([0, chars.len() as u8]);
+            chars.extend(abbreviation.bytes().chain([0]));
+        }
+        // The version 1 block is read past, so it has no data of its own
+        let mut data = header(0, 0, 0);
+        data.extend(header(1, types.len() as u32, chars.len() as u32));
+        data.extend(0i64.to_be_bytes());
+        data.push(0);
+        data.extend(raw_types);
+        data.extend(chars);
+        data.extend(format!("\n{}\n", footer).bytes());
+        data
+    }
+
+    /// `Europe/Berlin`, built in so tests don't need the system tz database.
+    pub(crate) fn berlin() -> DisplayZone {
+        let data = tzif(&[(3600, "CET"), (7200, "CEST")], "CET-1CEST,M3.5.0,M10.5.0/3");
+        DisplayZone::Named(parse_tzif("Europe/Berlin", &data).unwrap())
+    }
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn tz_strings_switch_at_the_rule_times() {
+        let berlin = PosixTz::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        let abbreviation = |moment: DateTime<Utc>| berlin.local_type_at(moment.timestamp()).abbreviation;
+        assert_eq!(abbreviation(at(2031, 1, 15, 12, 0)), "CET");
+        // The clocks go forward at 02:00 CET on the last Sunday of March
+        assert_eq!(abbreviation(at(2031, 3, 30, 0, 59)), "CET");
+        assert_eq!(abbreviation(at(2031, 3, 30, 1, 0)), "CEST");
+        // and back at 03:00 CEST on the last Sunday of October
+        assert_eq!(abbreviation(at(2031, 10, 26, 0, 59)), "CEST");
+        assert_eq!(abbreviation(at(2031, 10, 26, 1, 0)), "CET");
+        assert_eq!(berlin.local_type_at(at(2031, 7, 1, 0, 0).timestamp()).offset, 7200);
+
+        let sydney = PosixTz::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        assert_eq!(sydney.local_type_at(at(2031, 1, 15, 0, 0).timestamp()).abbreviation, "AEDT");
+        assert_eq!(sydney.local_type_at(at(2031, 7, 15, 0, 0).timestamp()).abbreviation, "AEST");
+
+        let tehran = PosixTz::parse("<+0330>-3:30").unwrap();
+        assert_eq!(tehran.local_type_at(0).offset, 3 * 3600 + 30 * 60);
+        assert_eq!(tehran.local_type_at(0).abbreviation, "+0330");
+        assert!(PosixTz::parse("X1").is_none());
+    }
+
+    #[test]
+    fn display_zones_format_wall_clock_time() {
+        let moment = at(2031, 7, 1, 12, 30);
+        assert_eq!(DisplayZone::parse("DISPLAY_TIMEZONE", "UTC").unwrap().format(moment), "2031-07-01 12:30:00 UTC");
+        assert!(DisplayZone::parse("DISPLAY_TIMEZONE", " ").is_err());
+        assert_eq!(DisplayZone::parse("DISPLAY_TIMEZONE", "+05:30").unwrap().format(moment), "2031-07-01 18:00:00 +05:30");
+        assert!(DisplayZone::parse("DISPLAY_TIMEZONE", "Not/A_Zone").is_err());
+        assert!(DisplayZone::parse("DISPLAY_TIMEZONE", "../etc/passwd").is_err());
+        assert_eq!(berlin().format(moment), "2031-07-01 14:30:00 CEST");
+        assert_eq!(berlin().format(at(2031, 1, 1, 12, 30)), "2031-01-01 13:30:00 CET");
+        assert_eq!(berlin().to_string(), "Europe/Berlin");
+    }
+
+    #[test]
+    fn system_zones_follow_daylight_saving() {
+        let zone = DisplayZone::parse("DISPLAY_TIMEZONE", "America/New_York")
+            .expect("America/New_York should be in the system's tz database");
+        assert_eq!(zone.format(at(2031, 7, 1, 12, 30)), "2031-07-01 08:30:00 EDT");
+        assert_eq!(zone.format(at(2031, 1, 1, 12, 30)), "2031-01-01 07:30:00 EST");
+        assert_eq!(zone.format(at(2001, 1, 1, 12, 30)), "2001-01-01 07:30:00 EST");
+    }
+
+    #[test]
+    fn corrupt_tzif_files_are_refused() {
+        let data = tzif(&[(3600, "CET")], "CET-1");
+        assert!(parse_tzif("Europe/Berlin", &data).is_some());
+        assert!(parse_tzif("Europe/Berlin", &data[..data.len() / 2]).is_none());
+        // Counts of u32::MAX, far past the end of the file
+        let mut huge = data[..44].to_vec();
+        huge[20..44].fill(0xff);
+        assert!(parse_tzif("Europe/Berlin", &huge).is_none());
+        let header = Header {
+            version: b'2',
+            isutcnt: usize::MAX,
+            isstdcnt: 0,
+            leapcnt: 0,
+            timecnt: usize::MAX / 2,
+            typecnt: 1,
+            charcnt: 0,
+        };
+        assert_eq!(header.data_len(8), None);
+    }
+}
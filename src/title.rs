@@ -0,0 +1,125 @@
+use regex::Regex;
+use reqwest::Client;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Most of a page read while looking for its title.
+const MAX_TITLE_BYTES: usize = 64 * 1024;
+
+static TITLE_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title").unwrap());
+
+/// Fetches pages to label opened URLs with their `<title>`, with FETCH_TITLE.
+#[derive(Debug)]
+pub struct TitleFetcher {
+    client: Client,
+    timeout: Duration,
+}
+
+impl TitleFetcher {
+    /// Gives up on a page after `timeout`, so a slow site delays its open by
+    /// at most that long.
+    pub fn new(client: Client, timeout: Duration) -> Self {
+        TitleFetcher { client, timeout }
+    }
+
+    /// Returns the title of the page at `url`, or `None` if the first
+    /// `MAX_TITLE_BYTES` of it have no non-empty one.
+    pub async fn fetch(&self, url: &str) -> Result<Option<String>, reqwest::Error> {
+        let mut response = self.client.get(url).timeout(self.timeout).send().await?.error_for_status()?;
+        let mut body = Vec::new();
+        while body.len() < MAX_TITLE_BYTES
+            && let Some(chunk) = response.chunk().await?
+        {
+            body.extend_from_slice(&chunk);
+        }
+        body.truncate(MAX_TITLE_BYTES);
+        Ok(extract_title(&String::from_utf8_lossy(&body)))
+    }
+}
+
+/// Text of the first `<title>` tag, with entities decoded and whitespace
+/// collapsed.
+fn extract_title(html: &str) -> Option<String> {
+    let raw = TITLE_TAG.captures(html)?.get(1)?.as_str();
+    let title = decode_entities(&raw.split_whitespace().collect::<Vec<_>>().join(" "));
+    (!title.is_empty()).then_some(title)
+}
+
+/// Decodes the character references common in titles; others are kept as
+/// written.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';').filter(|end| *end <= 10) else {
+            decoded.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..end];
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match character {
+            Some(character) => {
+                decoded.push(character);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+
+    #[test]
+    fn extracts_and_decodes_titles() {
+        let html = "<html><head><TITLE lang=\"en\">\n  Rust &amp; Cargo &#8211; &#x41;&copy;\n</TITLE></head>";
+        assert_eq!(extract_title(html).as_deref(), Some("Rust & Cargo \u{2013} A&copy;"));
+        assert_eq!(extract_title("<title>  </title>"), None);
+        assert_eq!(extract_title("<p>no title</p>"), None);
+        assert_eq!(decode_entities("fish & chips &lt;3"), "fish & chips <3");
+    }
+
+    #[tokio::test]
+    async fn fetches_the_title_with_the_user_agent() {
+        let app = axum::Router::new().route(
+            "/page",
+            get(|headers: axum::http::HeaderMap| async move {
+                let agent = headers["user-agent"].to_str().unwrap().to_string();
+                format!("<html><title>Hello from {}</title></html>", agent)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = Client::builder().user_agent("opener-test").build().unwrap();
+        let fetcher = TitleFetcher::new(client, Duration::from_secs(5));
+        let title = fetcher.fetch(&format!("http://{}/page", addr)).await.unwrap();
+        assert_eq!(title.as_deref(), Some("Hello from opener-test"));
+        assert!(fetcher.fetch(&format!("http://{}/missing", addr)).await.is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use crate::backoff::Backoff;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use firestore::errors::FirestoreError;
+use firestore::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Longest pause between retries of a write, however many there are.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Fields written to an opened document to mark it expired. `timestamp` is
+/// written back as-is, so a document shared with a string timestamp ends up
+/// with a Firestore one.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ExpiryUpdate {
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(with = "firestore::serialize_as_timestamp")]
+    pub expired_at: DateTime<Utc>,
+}
+
+/// Fields written to a document handled with USE_STATUS_FIELD; `opened_at`
+/// is cleared for one that failed to open.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StatusUpdate {
+    pub status: String,
+    #[serde(with = "firestore::serialize_as_optional_timestamp")]
+    pub opened_at: Option<DateTime<Utc>>,
+}
+
+/// Writes back to the documents URLs were opened from. The handlers only ever
+/// talk to this trait, as they do to `UrlOpener`, so tests can swap in an
+/// updater that records the writes instead.
+#[async_trait]
+pub trait DocUpdater: Send + Sync {
+    async fn expire(&self, collection_name: &str, doc_id: &str, update: &ExpiryUpdate) -> FirestoreResult<()>;
+
+    async fn set_status(&self, collection_name: &str, doc_id: &str, update: &StatusUpdate) -> FirestoreResult<()>;
+
+    async fn delete(&self, collection_name: &str, doc_id: &str) -> FirestoreResult<()>;
+}
+
+#[async_trait]
+impl DocUpdater for FirestoreDb {
+    async fn expire(&self, collection_name: &str, doc_id: &str, update: &ExpiryUpdate) -> FirestoreResult<()> {
+        self.fluent()
+            .update()
+            .fields(["timestamp", "expired_at"])
+            .in_col(collection_name)
+            .document_id(doc_id)
+            .object(update)
+            .execute::<ExpiryUpdate>()
+            .await
+            .map(|_| ())
+    }
+
+    async fn set_status(&self, collection_name: &str, doc_id: &str, update: &StatusUpdate) -> FirestoreResult<()> {
+        self.fluent()
+            .update()
+            .fields(["status", "opened_at"])
+            .in_col(collection_name)
+            .document_id(doc_id)
+            .object(update)
+            .execute::<StatusUpdate>()
+            .await
+            .map(|_| ())
+    }
+
+    async fn delete(&self, collection_name: &str, doc_id: &str) -> FirestoreResult<()> {
+        self.fluent()
+            .delete()
+            .from(collection_name)
+            .document_id(doc_id)
+            .execute()
+            .await
+    }
+}
+
+/// Retries the writes of another updater that fail transiently, up to
+/// UPDATE_RETRIES times with backoff from UPDATE_RETRY_DELAY_MS, so a blip
+/// doesn't leave an opened document looking unopened.
+pub struct RetryingUpdater {
+    inner: Arc<dyn DocUpdater>,
+    retries: u32,
+    delay: Duration,
+}
+
+impl RetryingUpdater {
+    pub fn new(inner: Arc<dyn DocUpdater>, retries: u32, delay: Duration) -> Self {
+        RetryingUpdater { inner, retries, delay }
+    }
+
+    async fn retry<F, Fut>(&self, doc_id: &str, write: F) -> FirestoreResult<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = FirestoreResult<()>>,
+    {
+        let mut backoff = Backoff::new(self.delay, MAX_RETRY_DELAY);
+        let mut attempt = 0;
+        loop {
+            match write().await {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    attempt += 1;
+                    let delay = backoff.next_delay();
+                    warn!(doc_id = doc_id; "Failed to write to document: {}; retry {}/{} in {:?}", e, attempt, self.retries, delay);
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl DocUpdater for RetryingUpdater {
+    async fn expire(&self, collection_name: &str, doc_id: &str, update: &ExpiryUpdate) -> FirestoreResult<()> {
+        self.retry(doc_id, || self.inner.expire(collection_name, doc_id, update)).await
+    }
+
+    async fn set_status(&self, collection_name: &str, doc_id: &str, update: &StatusUpdate) -> FirestoreResult<()> {
+        self.retry(doc_id, || self.inner.set_status(collection_name, doc_id, update)).await
+    }
+
+    async fn delete(&self, collection_name: &str, doc_id: &str) -> FirestoreResult<()> {
+        self.retry(doc_id, || self.inner.delete(collection_name, doc_id)).await
+    }
+}
+
+/// Whether a write may work if tried again, going by the client's own
+/// judgement of the gRPC status, such as `UNAVAILABLE`, or a network error.
+/// A missing document or a rejected write fails the same way every time.
+fn is_transient(error: &FirestoreError) -> bool {
+    match error {
+        FirestoreError::DatabaseError(error) => error.retry_possible,
+        FirestoreError::NetworkError(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firestore::errors::{FirestoreDatabaseError, FirestoreErrorPublicGenericDetails};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails its first `failures` writes, with errors `retry_possible` or not.
+    struct FlakyUpdater {
+        failures: u32,
+        retry_possible: bool,
+        attempts: AtomicU32,
+    }
+
+    impl FlakyUpdater {
+        fn write(&self) -> FirestoreResult<()> {
+            if self.attempts.fetch_add(1, Ordering::Relaxed) < self.failures {
+                return Err(FirestoreError::DatabaseError(FirestoreDatabaseError {
+                    public: FirestoreErrorPublicGenericDetails {
+                        code: "Unavailable".to_string(),
+                    },
+                    details: "backend down".to_string(),
+                    retry_possible: self.retry_possible,
+                }));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl DocUpdater for FlakyUpdater {
+        async fn expire(&self, _: &str, _: &str, _: &ExpiryUpdate) -> FirestoreResult<()> {
+            self.write()
+        }
+
+        async fn set_status(&self, _: &str, _: &str, _: &StatusUpdate) -> FirestoreResult<()> {
+            self.write()
+        }
+
+        async fn delete(&self, _: &str, _: &str) -> FirestoreResult<()> {
+            self.write()
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Write {
+        Expire,
+        SetStatus,
+        Delete,
+    }
+
+    async fn attempts(write: Write, failures: u32, retry_possible: bool) -> (bool, u32) {
+        let flaky = Arc::new(FlakyUpdater {
+            failures,
+            retry_possible,
+            attempts: AtomicU32::new(0),
+        });
+        let updater = RetryingUpdater::new(flaky.clone(), 2, Duration::from_millis(1));
+        let now = Utc::now();
+        let result = match write {
+            Write::Expire => {
+                let update = ExpiryUpdate {
+                    timestamp: now,
+                    expired_at: now,
+                };
+                updater.expire("shared_urls", "abc", &update).await
+            }
+            Write::SetStatus => {
+                let update = StatusUpdate {
+                    status: "opened".to_string(),
+                    opened_at: Some(now),
+                };
+                updater.set_status("shared_urls", "abc", &update).await
+            }
+            Write::Delete => updater.delete("shared_urls", "abc").await,
+        };
+        (result.is_ok(), flaky.attempts.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        for write in [Write::Expire, Write::SetStatus, Write::Delete] {
+            assert_eq!(attempts(write, 2, true).await, (true, 3));
+            assert_eq!(attempts(write, 3, true).await, (false, 3));
+            assert_eq!(attempts(write, 1, false).await, (false, 1));
+        }
+    }
+}